@@ -0,0 +1,24 @@
+CREATE TABLE Game (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    Player1ID INT NOT NULL,
+    Player2ID INT NOT NULL,
+    WinnerID INT NULL,
+    Rules VARCHAR(1024) NOT NULL DEFAULT "{}",
+    StartedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    EndedAt DATETIME NULL,
+    FOREIGN KEY (Player1ID) REFERENCES User(ID),
+    FOREIGN KEY (Player2ID) REFERENCES User(ID),
+    FOREIGN KEY (WinnerID) REFERENCES User(ID)
+);
+
+CREATE TABLE Penalty (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    GameID INT NOT NULL,
+    UserID INT NOT NULL,
+    Sips INT NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (GameID) REFERENCES Game(ID),
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
+
+ALTER TABLE User ADD COLUMN TotalSips INT NOT NULL DEFAULT 0;
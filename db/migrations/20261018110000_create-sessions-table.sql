@@ -0,0 +1,7 @@
+CREATE TABLE Session (
+    Token CHAR(64) NOT NULL PRIMARY KEY,
+    UserID INT NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ExpiresAt DATETIME NOT NULL,
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
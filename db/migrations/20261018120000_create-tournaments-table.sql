@@ -0,0 +1,45 @@
+CREATE TABLE Tournament (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    Name VARCHAR(64) NOT NULL,
+    Format VARCHAR(20) NOT NULL,
+    Status VARCHAR(20) NOT NULL DEFAULT "registration",
+    Rules VARCHAR(1024) NOT NULL DEFAULT "{}",
+    Rounds INT NOT NULL DEFAULT 0,
+    CurrentRound INT NOT NULL DEFAULT 0,
+    MaxPlayers INT NOT NULL,
+    RegistrationEndsAt DATETIME NOT NULL,
+    WinnerID INT NULL,
+    CreatedBy INT NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (WinnerID) REFERENCES User(ID),
+    FOREIGN KEY (CreatedBy) REFERENCES User(ID)
+);
+
+CREATE TABLE TournamentPlayer (
+    TournamentID INT NOT NULL,
+    UserID INT NOT NULL,
+    Seed INT NOT NULL DEFAULT 0,
+    Points INT NOT NULL DEFAULT 0,
+    Eliminated BOOLEAN NOT NULL DEFAULT FALSE,
+    RegisteredAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    PRIMARY KEY (TournamentID, UserID),
+    FOREIGN KEY (TournamentID) REFERENCES Tournament(ID),
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
+
+CREATE TABLE TournamentMatch (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    TournamentID INT NOT NULL,
+    Round INT NOT NULL,
+    Slot INT NOT NULL,
+    Player1ID INT NULL,
+    Player2ID INT NULL,
+    WinnerID INT NULL,
+    GameID INT NULL,
+    UNIQUE (TournamentID, Round, Slot),
+    FOREIGN KEY (TournamentID) REFERENCES Tournament(ID),
+    FOREIGN KEY (Player1ID) REFERENCES User(ID),
+    FOREIGN KEY (Player2ID) REFERENCES User(ID),
+    FOREIGN KEY (WinnerID) REFERENCES User(ID),
+    FOREIGN KEY (GameID) REFERENCES Game(ID)
+);
@@ -0,0 +1,20 @@
+CREATE TABLE Season (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    Name VARCHAR(64) NOT NULL,
+    StartedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    EndedAt DATETIME NULL
+);
+
+CREATE TABLE SeasonStanding (
+    SeasonID INT NOT NULL,
+    UserID INT NOT NULL,
+    EloPoints INT NOT NULL,
+    Placement INT NOT NULL,
+    Reward VARCHAR(20) NULL,
+    PRIMARY KEY (SeasonID, UserID),
+    INDEX (SeasonID, Placement),
+    FOREIGN KEY (SeasonID) REFERENCES Season(ID),
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
+
+INSERT INTO Season(Name) VALUES("Season 1");
@@ -0,0 +1,15 @@
+CREATE TABLE RatingChange (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    UserID INT NOT NULL,
+    GameID INT NULL,
+    EloBefore INT NOT NULL,
+    EloAfter INT NOT NULL,
+    Delta INT NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX (CreatedAt),
+    INDEX (UserID, CreatedAt),
+    FOREIGN KEY (UserID) REFERENCES User(ID),
+    FOREIGN KEY (GameID) REFERENCES Game(ID)
+);
+
+CREATE INDEX GameWinnerEndedAt ON Game(WinnerID, EndedAt);
@@ -0,0 +1,21 @@
+CREATE TABLE PlayerStats (
+    UserID INT NOT NULL PRIMARY KEY,
+    GamesPlayed INT NOT NULL DEFAULT 0,
+    Wins INT NOT NULL DEFAULT 0,
+    Losses INT NOT NULL DEFAULT 0,
+    CurrentStreak INT NOT NULL DEFAULT 0,
+    BestStreak INT NOT NULL DEFAULT 0,
+    PeakElo INT NOT NULL,
+    TotalGameSeconds BIGINT NOT NULL DEFAULT 0,
+    INDEX (GamesPlayed),
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
+
+INSERT INTO PlayerStats(UserID, GamesPlayed, Wins, Losses, PeakElo, TotalGameSeconds)
+SELECT User.ID, COUNT(Game.ID), COALESCE(SUM(Game.WinnerID = User.ID), 0),
+    COALESCE(SUM(Game.WinnerID != User.ID), 0), User.EloPoints,
+    COALESCE(SUM(TIMESTAMPDIFF(SECOND, Game.StartedAt, Game.EndedAt)), 0)
+FROM User
+INNER JOIN Game ON (Game.Player1ID = User.ID OR Game.Player2ID = User.ID)
+    AND Game.EndedAt IS NOT NULL
+GROUP BY User.ID;
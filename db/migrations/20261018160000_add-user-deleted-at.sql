@@ -0,0 +1 @@
+ALTER TABLE User ADD COLUMN DeletedAt DATETIME NULL;
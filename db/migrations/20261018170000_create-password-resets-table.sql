@@ -0,0 +1,10 @@
+ALTER TABLE User ADD COLUMN Email VARCHAR(254) NULL UNIQUE;
+
+CREATE TABLE PasswordReset (
+    TokenHash CHAR(64) NOT NULL PRIMARY KEY,
+    UserID INT NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ExpiresAt DATETIME NOT NULL,
+    UsedAt DATETIME NULL,
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
@@ -0,0 +1 @@
+ALTER TABLE User ADD COLUMN IsGuest BOOLEAN NOT NULL DEFAULT FALSE;
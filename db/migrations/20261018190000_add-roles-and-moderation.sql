@@ -0,0 +1,38 @@
+ALTER TABLE User ADD COLUMN Role VARCHAR(16) NOT NULL DEFAULT "player";
+
+CREATE TABLE Ban (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    UserID INT NOT NULL,
+    ModeratorID INT NOT NULL,
+    Reason VARCHAR(255) NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    ExpiresAt DATETIME NULL,
+    LiftedAt DATETIME NULL,
+    INDEX (UserID, LiftedAt),
+    FOREIGN KEY (UserID) REFERENCES User(ID),
+    FOREIGN KEY (ModeratorID) REFERENCES User(ID)
+);
+
+CREATE TABLE RatingAdjustment (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    UserID INT NOT NULL,
+    ModeratorID INT NOT NULL,
+    RatingChangeID INT NOT NULL,
+    Reason VARCHAR(255) NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX (UserID, CreatedAt),
+    FOREIGN KEY (UserID) REFERENCES User(ID),
+    FOREIGN KEY (ModeratorID) REFERENCES User(ID),
+    FOREIGN KEY (RatingChangeID) REFERENCES RatingChange(ID)
+);
+
+CREATE TABLE UsernameChange (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    UserID INT NOT NULL,
+    ModeratorID INT NOT NULL,
+    OldUsername VARCHAR(20) NOT NULL,
+    NewUsername VARCHAR(20) NOT NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    FOREIGN KEY (UserID) REFERENCES User(ID),
+    FOREIGN KEY (ModeratorID) REFERENCES User(ID)
+);
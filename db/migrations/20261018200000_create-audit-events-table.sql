@@ -0,0 +1,16 @@
+CREATE TABLE AuditEvent (
+    ID BIGINT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    Action VARCHAR(32) NOT NULL,
+    ActorID INT NULL,
+    TargetID INT NULL,
+    Details VARCHAR(512) NULL,
+    IP VARCHAR(45) NULL,
+    UserAgent VARCHAR(255) NULL,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX (CreatedAt),
+    INDEX (Action, CreatedAt),
+    INDEX (ActorID, CreatedAt),
+    INDEX (TargetID, CreatedAt),
+    FOREIGN KEY (ActorID) REFERENCES User(ID),
+    FOREIGN KEY (TargetID) REFERENCES User(ID)
+);
@@ -0,0 +1,20 @@
+ALTER TABLE User
+    ADD COLUMN TotpSecret VARCHAR(64) NULL,
+    ADD COLUMN TotpEnabled BOOLEAN NOT NULL DEFAULT FALSE,
+    ADD COLUMN TotpLastStep BIGINT NULL;
+
+CREATE TABLE RecoveryCode (
+    UserID INT NOT NULL,
+    CodeHash CHAR(64) NOT NULL,
+    UsedAt DATETIME NULL,
+    PRIMARY KEY (UserID, CodeHash),
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
+
+CREATE TABLE LoginChallenge (
+    TokenHash CHAR(64) NOT NULL PRIMARY KEY,
+    UserID INT NOT NULL,
+    Attempts INT NOT NULL DEFAULT 0,
+    ExpiresAt DATETIME NOT NULL,
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
@@ -0,0 +1,6 @@
+-- Confusable folding isn't available in SQL, the server fills this in for
+-- existing users on startup.
+ALTER TABLE User
+    ADD COLUMN NormalizedUsername VARCHAR(64) NULL;
+
+CREATE UNIQUE INDEX UserNormalizedUsername ON User(NormalizedUsername);
@@ -0,0 +1,13 @@
+-- One row per pair of users, whoever asked first. Declined requests are
+-- deleted.
+CREATE TABLE Friendship (
+    RequesterID INT NOT NULL,
+    AddresseeID INT NOT NULL,
+    Status VARCHAR(16) NOT NULL DEFAULT "pending",
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    AcceptedAt DATETIME NULL,
+    PRIMARY KEY (RequesterID, AddresseeID),
+    INDEX (AddresseeID, Status),
+    FOREIGN KEY (RequesterID) REFERENCES User(ID),
+    FOREIGN KEY (AddresseeID) REFERENCES User(ID)
+);
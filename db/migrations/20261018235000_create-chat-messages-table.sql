@@ -0,0 +1,13 @@
+-- Body keeps what was typed, players were shown it masked when Filtered.
+CREATE TABLE ChatMessage (
+    ID INT NOT NULL PRIMARY KEY AUTO_INCREMENT,
+    GameID INT NOT NULL,
+    UserID INT NOT NULL,
+    Body VARCHAR(255) NULL,
+    Emote VARCHAR(32) NULL,
+    Filtered BOOLEAN NOT NULL DEFAULT FALSE,
+    CreatedAt DATETIME NOT NULL DEFAULT CURRENT_TIMESTAMP,
+    INDEX (GameID, CreatedAt),
+    FOREIGN KEY (GameID) REFERENCES Game(ID),
+    FOREIGN KEY (UserID) REFERENCES User(ID)
+);
@@ -0,0 +1,2 @@
+-- Set for changes that did not come from a game, e.g. season resets.
+ALTER TABLE RatingChange ADD COLUMN Reason VARCHAR(32) NULL;
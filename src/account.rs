@@ -0,0 +1,331 @@
+use crate::{
+    audit::{self, AuditAction, RequestInfo},
+    authentication::{self, AuthError, LoginResponse, Role},
+    avatar::{self, BlobStore},
+    db::{
+        AuditEvent, Ban, ChatMessage, Db, Friend, FriendRequest, GameRecord, PenaltyRecord,
+        PlayerStats, RatingAdjustment, RatingChange, SeasonStanding, SessionRecord,
+        TournamentEntry, User, UsernameChange,
+    },
+    profile, username,
+};
+use chrono::NaiveDateTime;
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::{sync::Arc, time::Duration};
+use warp::reply::Json;
+
+/// Guest names are the prefix and random characters, within the 15
+/// characters allowed for usernames.
+pub const GUEST_PREFIX: &str = "guest_";
+pub const GUEST_SUFFIX_LENGTH: usize = 8;
+pub const DEFAULT_GUEST_RETENTION_DAYS: u32 = 30;
+pub const GUEST_RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PasswordChangeData {
+    pub current_password: String,
+    pub new_password: String,
+}
+
+/// Credentials a guest account is converted to.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ClaimData {
+    pub username: String,
+    pub password: String,
+    pub email: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DeleteAccountData {
+    pub password: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AccountData {
+    pub id: i32,
+    pub username: String,
+    pub elo_points: i32,
+    pub country_id: String,
+    pub profile_picture_url: String,
+    pub total_sips: i32,
+    pub email: Option<String>,
+    pub is_guest: bool,
+    pub role: Role,
+    pub two_factor_enabled: bool,
+}
+
+/// Everything stored about a user.
+#[derive(Serialize)]
+pub struct DataExport {
+    pub exported_at: NaiveDateTime,
+    pub account: AccountData,
+    pub stats: PlayerStats,
+    pub sessions: Vec<SessionRecord>,
+    pub games: Vec<GameRecord>,
+    pub penalties: Vec<PenaltyRecord>,
+    pub rating_history: Vec<RatingChange>,
+    pub seasons: Vec<SeasonStanding>,
+    pub tournaments: Vec<TournamentEntry>,
+    pub friends: Vec<Friend>,
+    pub friend_requests: Vec<FriendRequest>,
+    pub chat_messages: Vec<ChatMessage>,
+    pub bans: Vec<Ban>,
+    pub rating_adjustments: Vec<RatingAdjustment>,
+    pub username_changes: Vec<UsernameChange>,
+    pub audit_events: Vec<AuditEvent>,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&AuthError {
+        err: error.to_string(),
+        code: username::error_code(&error),
+    })
+}
+
+/// Checks the password again before sensitive account changes.
+async fn confirm_password(db: &Db, user: &User, password: &str) -> anyhow::Result<()> {
+    db.get_user_by_name_password(&user.username, password)
+        .await
+        .map(|_| ())
+        .map_err(|_| anyhow::Error::msg("Invalid password"))
+}
+
+/// Changes the password and signs out every session, returning a fresh one.
+pub async fn change_password(
+    user: User,
+    request: RequestInfo,
+    data: PasswordChangeData,
+    db: Arc<Db>,
+) -> Json {
+    let result = async {
+        authentication::validate_password(&data.new_password)?;
+
+        confirm_password(&db, &user, &data.current_password).await?;
+
+        db.update_password(user.id, &data.new_password).await?;
+        db.delete_sessions(user.id).await?;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::PasswordChanged,
+            Some(user.id),
+            Some(user.id),
+            None,
+        )
+        .await;
+
+        let token = authentication::generate_token();
+        db.insert_session(&token, user.id).await?;
+
+        let user = db.get_user_by_name(&user.username).await?;
+        Ok(LoginResponse { user, token })
+    }
+    .await;
+
+    match result {
+        Ok(login) => warp::reply::json(&login),
+        Err(err) => error(err),
+    }
+}
+
+/// Creates a guest account to play casual games without registering.
+pub async fn guest(request: RequestInfo, db: Arc<Db>) -> Json {
+    let result = async {
+        // retry on the rare collision of generated names
+        let mut attempts = 0;
+        let user = loop {
+            let suffix: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(GUEST_SUFFIX_LENGTH)
+                .map(char::from)
+                .collect();
+
+            attempts += 1;
+            match db
+                .insert_guest(
+                    &format!("{}{}", GUEST_PREFIX, suffix),
+                    &authentication::generate_token(),
+                )
+                .await
+            {
+                Ok(user) => break user,
+                Err(_) if attempts < 3 => continue,
+                Err(err) => return Err(err),
+            }
+        };
+
+        let token = authentication::generate_token();
+        db.insert_session(&token, user.id).await?;
+
+        log::info!("Created guest {} for {:?}", user.username, request.ip);
+        Ok(LoginResponse { user, token })
+    }
+    .await;
+
+    match result {
+        Ok(login) => warp::reply::json(&login),
+        Err(err) => error(err),
+    }
+}
+
+/// Deletes guests that were not claimed and saw no activity within the
+/// retention period, configured in days through `GUEST_RETENTION_DAYS`. Like
+/// deleted accounts they stay anonymized in their opponents' history.
+pub async fn run_guest_retention(db: Arc<Db>) {
+    let days = dotenv::var("GUEST_RETENTION_DAYS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_GUEST_RETENTION_DAYS);
+
+    let mut interval = tokio::time::interval(GUEST_RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        let guests = match db.get_inactive_guests(days).await {
+            Ok(guests) => guests,
+            Err(error) => {
+                log::error!("Guest retention failed: {}", error);
+                continue;
+            }
+        };
+
+        let mut deleted = 0;
+        for guest in guests {
+            match db
+                .anonymize_user(guest, &authentication::generate_token())
+                .await
+            {
+                Ok(()) => deleted += 1,
+                Err(error) => log::error!("Failed to delete guest {}: {}", guest, error),
+            }
+        }
+
+        if deleted > 0 {
+            log::info!("Deleted {} guests inactive for {} days", deleted, days);
+        }
+    }
+}
+
+/// Gives a guest a username and password, keeping everything it played.
+pub async fn claim(user: User, request: RequestInfo, data: ClaimData, db: Arc<Db>) -> Json {
+    let result = async {
+        if !user.is_guest {
+            return Err(anyhow::Error::msg("Account is not a guest"));
+        }
+
+        authentication::validate_username(&data.username)?;
+        authentication::validate_password(&data.password)?;
+        let email = data
+            .email
+            .as_deref()
+            .map(profile::validate_email)
+            .transpose()?
+            .flatten();
+
+        db.claim_guest(user.id, &data.username, &data.password, email.as_deref())
+            .await?;
+        let details = format!("{} -> {}", user.username, data.username);
+        audit::record(
+            &db,
+            &request,
+            AuditAction::GuestClaimed,
+            Some(user.id),
+            Some(user.id),
+            Some(&details),
+        )
+        .await;
+
+        let token = authentication::generate_token();
+        db.insert_session(&token, user.id).await?;
+
+        let user = db.get_user_by_name(&data.username).await?;
+        Ok(LoginResponse { user, token })
+    }
+    .await;
+
+    match result {
+        Ok(login) => warp::reply::json(&login),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn delete(
+    user: User,
+    request: RequestInfo,
+    data: DeleteAccountData,
+    db: Arc<Db>,
+    blob_store: Arc<dyn BlobStore>,
+) -> Json {
+    let result = async {
+        confirm_password(&db, &user, &data.password).await?;
+
+        // nobody knows this password, the account can't be signed into again
+        db.anonymize_user(user.id, &authentication::generate_token())
+            .await?;
+
+        avatar::delete_thumbnails(blob_store.as_ref(), &user).await;
+
+        audit::record(
+            &db,
+            &request,
+            AuditAction::AccountDeleted,
+            Some(user.id),
+            Some(user.id),
+            None,
+        )
+        .await;
+
+        log::info!("Deleted account {}", user.id);
+        Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => warp::reply::json(&AuthError {
+            err: String::new(),
+            code: None,
+        }),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn export(user: User, db: Arc<Db>) -> Json {
+    let result = async {
+        Ok::<_, anyhow::Error>(DataExport {
+            exported_at: chrono::Utc::now().naive_utc(),
+            stats: db.get_player_stats(&user.username).await?,
+            sessions: db.get_sessions(user.id).await?,
+            games: db.get_games(user.id).await?,
+            penalties: db.get_penalties(user.id).await?,
+            rating_history: db.get_rating_history(user.id, None, None).await?,
+            seasons: db.get_season_rewards(&user.username).await?,
+            tournaments: db.get_tournament_entries(user.id).await?,
+            friends: db.get_friends(user.id).await?,
+            friend_requests: db.get_friend_requests(user.id).await?,
+            chat_messages: db.get_user_chat_messages(user.id).await?,
+            bans: db.get_bans(user.id).await?,
+            rating_adjustments: db.get_rating_adjustments(user.id).await?,
+            username_changes: db.get_username_changes(user.id).await?,
+            audit_events: db.get_user_audit_events(user.id).await?,
+            account: AccountData {
+                id: user.id,
+                username: user.username,
+                elo_points: user.elo_points,
+                country_id: user.country_id,
+                profile_picture_url: user.profile_picture_url,
+                total_sips: user.total_sips,
+                email: user.email,
+                is_guest: user.is_guest,
+                role: user.role,
+                two_factor_enabled: user.totp_enabled,
+            },
+        })
+    }
+    .await;
+
+    match result {
+        Ok(export) => warp::reply::json(&export),
+        Err(err) => error(err),
+    }
+}
@@ -0,0 +1,472 @@
+use crate::{
+    audit::{self, AuditAction, RequestInfo},
+    authentication::{self, Role},
+    db::{Ban, Db, RatingAdjustment, User, UsernameChange},
+    game::{self, State},
+    username,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::Mutex;
+use warp::reply::Json;
+
+pub const MAX_REASON_LENGTH: usize = 255;
+pub const MAX_RATING_ADJUSTMENT: i32 = 1000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AdminError {
+    pub err: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RoleData {
+    pub role: Role,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct BanData {
+    pub reason: String,
+    /// Suspends for this many hours, bans for good when left out.
+    pub hours: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatingAdjustmentData {
+    pub delta: i32,
+    pub reason: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RenameData {
+    pub username: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct EndGameData {
+    /// Awards the game to this player, aborts it without a result otherwise.
+    pub winner: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct ModerationHistory {
+    pub role: Role,
+    pub bans: Vec<Ban>,
+    pub rating_adjustments: Vec<RatingAdjustment>,
+    pub username_changes: Vec<UsernameChange>,
+}
+
+#[derive(Serialize)]
+pub struct ActiveGame {
+    pub id: u64,
+    pub player1: String,
+    pub player2: String,
+    pub seconds: u64,
+    pub rated: bool,
+    pub tournament_match: Option<i32>,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&AdminError {
+        err: error.to_string(),
+        code: username::error_code(&error),
+    })
+}
+
+fn validate_reason(reason: &str) -> anyhow::Result<&str> {
+    let reason = reason.trim();
+    if reason.is_empty() || reason.len() > MAX_REASON_LENGTH {
+        return Err(anyhow::Error::msg(format!(
+            "Reason must be between 1 to {} characters",
+            MAX_REASON_LENGTH
+        )));
+    }
+
+    Ok(reason)
+}
+
+/// Staff can only act on users below their own role.
+async fn get_target(db: &Db, moderator: &User, username: &str) -> anyhow::Result<User> {
+    let target = db.get_user_by_name(username).await?;
+    if target.role >= moderator.role {
+        return Err(anyhow::Error::msg("Insufficient permissions"));
+    }
+
+    Ok(target)
+}
+
+/// Gives the users listed in `ADMIN_USERNAMES` the admin role, so a fresh
+/// deployment has someone to hand out roles.
+pub async fn bootstrap_admins(db: &Db) {
+    let usernames = dotenv::var("ADMIN_USERNAMES").unwrap_or_default();
+    for username in usernames
+        .split(',')
+        .map(str::trim)
+        .filter(|x| !x.is_empty())
+    {
+        let result = async {
+            let user = db.get_user_by_name(username).await?;
+            if user.role != Role::Admin {
+                db.set_role(user.id, Role::Admin).await?;
+                log::info!("Made {} an admin", username);
+            }
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(err) = result {
+            log::error!("Failed to make {} an admin: {}", username, err);
+        }
+    }
+}
+
+pub async fn set_role(
+    username: String,
+    admin: User,
+    request: RequestInfo,
+    data: RoleData,
+    db: Arc<Db>,
+) -> Json {
+    let result = async {
+        let target = db.get_user_by_name(&username).await?;
+        if target.id == admin.id {
+            return Err(anyhow::Error::msg("You can't change your own role"));
+        }
+        if target.is_guest {
+            return Err(anyhow::Error::msg("Guests can't be given a role"));
+        }
+
+        db.set_role(target.id, data.role).await?;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::RoleChanged,
+            Some(admin.id),
+            Some(target.id),
+            Some(data.role.as_str()),
+        )
+        .await;
+        log::info!(
+            "{} changed the role of {} to {}",
+            admin.username,
+            target.username,
+            data.role.as_str()
+        );
+
+        history_of(&db, &target.username).await
+    }
+    .await;
+
+    match result {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn ban(
+    username: String,
+    moderator: User,
+    request: RequestInfo,
+    data: BanData,
+    db: Arc<Db>,
+) -> Json {
+    let result = async {
+        let reason = validate_reason(&data.reason)?;
+        if data.hours == Some(0) {
+            return Err(anyhow::Error::msg("Suspensions must last at least an hour"));
+        }
+
+        let target = get_target(&db, &moderator, &username).await?;
+        db.insert_ban(target.id, moderator.id, reason, data.hours)
+            .await?;
+        let details = match data.hours {
+            Some(hours) => format!("{} hours: {}", hours, reason),
+            None => format!("permanent: {}", reason),
+        };
+        audit::record(
+            &db,
+            &request,
+            AuditAction::Banned,
+            Some(moderator.id),
+            Some(target.id),
+            Some(&details),
+        )
+        .await;
+        log::info!(
+            "{} banned {} for {:?} hours: {}",
+            moderator.username,
+            target.username,
+            data.hours,
+            reason
+        );
+
+        history_of(&db, &target.username).await
+    }
+    .await;
+
+    match result {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn unban(username: String, moderator: User, request: RequestInfo, db: Arc<Db>) -> Json {
+    let result = async {
+        let target = get_target(&db, &moderator, &username).await?;
+        db.lift_bans(target.id).await?;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::Unbanned,
+            Some(moderator.id),
+            Some(target.id),
+            None,
+        )
+        .await;
+        log::info!("{} unbanned {}", moderator.username, target.username);
+
+        history_of(&db, &target.username).await
+    }
+    .await;
+
+    match result {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn adjust_rating(
+    username: String,
+    admin: User,
+    request: RequestInfo,
+    data: RatingAdjustmentData,
+    db: Arc<Db>,
+) -> Json {
+    let result = async {
+        let reason = validate_reason(&data.reason)?;
+        if data.delta == 0 || data.delta.abs() > MAX_RATING_ADJUSTMENT {
+            return Err(anyhow::Error::msg(format!(
+                "Rating adjustments must be between 1 to {} points",
+                MAX_RATING_ADJUSTMENT
+            )));
+        }
+
+        let target = db.get_user_by_name(&username).await?;
+        db.adjust_elo(target.id, admin.id, data.delta, reason)
+            .await?;
+        let details = format!("{:+}: {}", data.delta, reason);
+        audit::record(
+            &db,
+            &request,
+            AuditAction::RatingAdjusted,
+            Some(admin.id),
+            Some(target.id),
+            Some(&details),
+        )
+        .await;
+        log::info!(
+            "{} adjusted the rating of {} by {}: {}",
+            admin.username,
+            target.username,
+            data.delta,
+            reason
+        );
+
+        if let Err(err) = db.leaderboard_stream.publish(&db).await {
+            log::error!("Failed to publish leaderboard changes: {}", err);
+        }
+
+        history_of(&db, &target.username).await
+    }
+    .await;
+
+    match result {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+/// Renames offensive usernames. Players are identified by name in running
+/// games, queues and challenges, so they must be offline and in none of them.
+pub async fn rename(
+    username: String,
+    moderator: User,
+    request: RequestInfo,
+    data: RenameData,
+    db: Arc<Db>,
+    state: Arc<Mutex<State>>,
+) -> Json {
+    let result = async {
+        authentication::validate_username(&data.username)?;
+
+        let target = get_target(&db, &moderator, &username).await?;
+        let state = state.lock().await;
+        if state.is_present(&target.username) {
+            return Err(anyhow::Error::msg(
+                "Player is online or in a game, try again later",
+            ));
+        }
+
+        db.rename_user(target.id, moderator.id, &data.username)
+            .await?;
+        drop(state);
+        let details = format!("{} -> {}", target.username, data.username);
+        audit::record(
+            &db,
+            &request,
+            AuditAction::Renamed,
+            Some(moderator.id),
+            Some(target.id),
+            Some(&details),
+        )
+        .await;
+        log::info!(
+            "{} renamed {} to {}",
+            moderator.username,
+            target.username,
+            data.username
+        );
+
+        history_of(&db, &data.username).await
+    }
+    .await;
+
+    match result {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+async fn history_of(db: &Db, username: &str) -> anyhow::Result<ModerationHistory> {
+    let user = db.get_user_by_name(username).await?;
+
+    Ok(ModerationHistory {
+        role: user.role,
+        bans: db.get_bans(user.id).await?,
+        rating_adjustments: db.get_rating_adjustments(user.id).await?,
+        username_changes: db.get_username_changes(user.id).await?,
+    })
+}
+
+pub async fn history(username: String, _moderator: User, db: Arc<Db>) -> Json {
+    match history_of(&db, &username).await {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn games(_moderator: User, state: Arc<Mutex<State>>) -> Json {
+    let games: Vec<ActiveGame> = state
+        .lock()
+        .await
+        .games
+        .iter()
+        .map(|game| ActiveGame {
+            id: game.id,
+            player1: game.p1.0.clone(),
+            player2: game.p2.0.clone(),
+            seconds: game.timer.elapsed().as_secs(),
+            rated: game.rated,
+            tournament_match: game.series.tournament_match,
+        })
+        .collect();
+
+    warp::reply::json(&games)
+}
+
+pub async fn end_game(
+    id: u64,
+    moderator: User,
+    request: RequestInfo,
+    data: EndGameData,
+    db: Arc<Db>,
+    state: Arc<Mutex<State>>,
+) -> Json {
+    let result = async {
+        let mut state = state.lock().await;
+
+        match &data.winner {
+            Some(winner) => {
+                let game = state
+                    .games
+                    .iter()
+                    .find(|x| x.id == id)
+                    .ok_or_else(|| anyhow::Error::msg("Game does not exist"))?;
+                if *winner != game.p1.0 && *winner != game.p2.0 {
+                    return Err(anyhow::Error::msg("Winner is not playing this game"));
+                }
+
+                game::finish_game(&db, &mut state, id, winner).await?;
+            }
+            None => game::abort_game(&db, &mut state, id, "Ended by a moderator").await?,
+        }
+        drop(state);
+
+        let details = match &data.winner {
+            Some(winner) => format!("game {} won by {}", id, winner),
+            None => format!("game {} aborted", id),
+        };
+        audit::record(
+            &db,
+            &request,
+            AuditAction::GameEnded,
+            Some(moderator.id),
+            None,
+            Some(&details),
+        )
+        .await;
+
+        log::info!(
+            "{} ended game {} with winner {:?}",
+            moderator.username,
+            id,
+            data.winner
+        );
+        anyhow::Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => warp::reply::json(&AdminError {
+            err: String::new(),
+            code: None,
+        }),
+        Err(err) => error(err),
+    }
+}
+
+/// Chat of a game as typed, unmasked, for handling reports.
+pub async fn chat(id: u64, _moderator: User, db: Arc<Db>) -> Json {
+    match db.get_chat_messages(id).await {
+        Ok(messages) => warp::reply::json(&messages),
+        Err(err) => error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn reason_is_trimmed() {
+        assert_eq!(validate_reason("  cheating ").unwrap(), "cheating");
+    }
+
+    #[test]
+    fn reason_length_is_limited() {
+        assert!(validate_reason("").is_err());
+        assert!(validate_reason("   ").is_err());
+        assert!(validate_reason(&"x".repeat(MAX_REASON_LENGTH)).is_ok());
+        assert!(validate_reason(&"x".repeat(MAX_REASON_LENGTH + 1)).is_err());
+    }
+
+    #[test]
+    fn roles_are_ordered_and_parsed() {
+        assert!(Role::Player < Role::Moderator && Role::Moderator < Role::Admin);
+        for role in [Role::Player, Role::Moderator, Role::Admin] {
+            assert_eq!(Role::try_from(String::from(role.as_str())).unwrap(), role);
+        }
+        assert!(Role::try_from(String::from("owner")).is_err());
+    }
+}
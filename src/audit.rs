@@ -0,0 +1,299 @@
+use crate::db::{AuditEvent, AuditFilter, Db, User};
+use chrono::{Duration as ChronoDuration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::{
+    net::{IpAddr, SocketAddr},
+    sync::Arc,
+    time::Duration,
+};
+use warp::{reply::Json, Filter, Rejection};
+
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 200;
+pub const MAX_DETAILS_LENGTH: usize = 512;
+pub const MAX_USER_AGENT_LENGTH: usize = 255;
+/// Events are kept this many days unless `AUDIT_RETENTION_DAYS` says otherwise.
+pub const DEFAULT_RETENTION_DAYS: u32 = 180;
+pub const RETENTION_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum AuditAction {
+    Login,
+    LoginFailed,
+    PasswordChanged,
+    PasswordResetRequested,
+    PasswordReset,
+    AccountDeleted,
+    GuestClaimed,
+    RoleChanged,
+    Banned,
+    Unbanned,
+    RatingAdjusted,
+    Renamed,
+    GameEnded,
+    TwoFactorEnabled,
+    TwoFactorDisabled,
+    RecoveryCodeUsed,
+}
+
+impl AuditAction {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            AuditAction::Login => "login",
+            AuditAction::LoginFailed => "login_failed",
+            AuditAction::PasswordChanged => "password_changed",
+            AuditAction::PasswordResetRequested => "password_reset_requested",
+            AuditAction::PasswordReset => "password_reset",
+            AuditAction::AccountDeleted => "account_deleted",
+            AuditAction::GuestClaimed => "guest_claimed",
+            AuditAction::RoleChanged => "role_changed",
+            AuditAction::Banned => "banned",
+            AuditAction::Unbanned => "unbanned",
+            AuditAction::RatingAdjusted => "rating_adjusted",
+            AuditAction::Renamed => "renamed",
+            AuditAction::GameEnded => "game_ended",
+            AuditAction::TwoFactorEnabled => "two_factor_enabled",
+            AuditAction::TwoFactorDisabled => "two_factor_disabled",
+            AuditAction::RecoveryCodeUsed => "recovery_code_used",
+        }
+    }
+}
+
+impl TryFrom<String> for AuditAction {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "login" => Ok(AuditAction::Login),
+            "login_failed" => Ok(AuditAction::LoginFailed),
+            "password_changed" => Ok(AuditAction::PasswordChanged),
+            "password_reset_requested" => Ok(AuditAction::PasswordResetRequested),
+            "password_reset" => Ok(AuditAction::PasswordReset),
+            "account_deleted" => Ok(AuditAction::AccountDeleted),
+            "guest_claimed" => Ok(AuditAction::GuestClaimed),
+            "role_changed" => Ok(AuditAction::RoleChanged),
+            "banned" => Ok(AuditAction::Banned),
+            "unbanned" => Ok(AuditAction::Unbanned),
+            "rating_adjusted" => Ok(AuditAction::RatingAdjusted),
+            "renamed" => Ok(AuditAction::Renamed),
+            "game_ended" => Ok(AuditAction::GameEnded),
+            "two_factor_enabled" => Ok(AuditAction::TwoFactorEnabled),
+            "two_factor_disabled" => Ok(AuditAction::TwoFactorDisabled),
+            "recovery_code_used" => Ok(AuditAction::RecoveryCodeUsed),
+            _ => Err(anyhow::Error::msg("Invalid audit action")),
+        }
+    }
+}
+
+/// Where a request came from.
+#[derive(Debug, Clone, Default)]
+pub struct RequestInfo {
+    pub ip: Option<IpAddr>,
+    pub user_agent: Option<String>,
+}
+
+pub fn request_info() -> impl Filter<Extract = (RequestInfo,), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and(warp::header::optional::<String>("user-agent"))
+        .map(|addr: Option<SocketAddr>, user_agent| RequestInfo {
+            ip: addr.map(|addr| addr.ip()),
+            user_agent,
+        })
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditError {
+    pub err: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AuditQuery {
+    pub action: Option<AuditAction>,
+    /// Username of the user that acted.
+    pub actor: Option<String>,
+    /// Username of the user acted on.
+    pub target: Option<String>,
+    pub from: Option<NaiveDate>,
+    pub to: Option<NaiveDate>,
+    pub offset: Option<u32>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize)]
+pub struct AuditPage {
+    pub total: i64,
+    pub offset: u32,
+    pub limit: u32,
+    pub events: Vec<AuditEvent>,
+}
+
+fn truncate(value: &str, max: usize) -> &str {
+    match value.char_indices().nth(max) {
+        Some((index, _)) => &value[..index],
+        None => value,
+    }
+}
+
+/// Stores an event. Failures are only logged, auditing never fails the
+/// action being audited.
+pub async fn record(
+    db: &Db,
+    request: &RequestInfo,
+    action: AuditAction,
+    actor_id: Option<i32>,
+    target_id: Option<i32>,
+    details: Option<&str>,
+) {
+    let result = db
+        .insert_audit_event(
+            action,
+            actor_id,
+            target_id,
+            details.map(|x| truncate(x, MAX_DETAILS_LENGTH)),
+            request.ip.map(|ip| ip.to_string()).as_deref(),
+            request
+                .user_agent
+                .as_deref()
+                .map(|x| truncate(x, MAX_USER_AGENT_LENGTH)),
+        )
+        .await;
+
+    if let Err(error) = result {
+        log::error!("Failed to record {} event: {}", action.as_str(), error);
+    }
+}
+
+/// Failed logins are recorded against the account that was tried, if any.
+pub async fn record_failed_login(
+    db: &Db,
+    request: &RequestInfo,
+    username: &str,
+    error: &anyhow::Error,
+) {
+    let target_id = db.get_user_by_name(username).await.ok().map(|user| user.id);
+    let details = format!("{}: {}", username, error);
+    record(
+        db,
+        request,
+        AuditAction::LoginFailed,
+        None,
+        target_id,
+        Some(&details),
+    )
+    .await;
+}
+
+pub async fn events(_admin: User, query: AuditQuery, db: Arc<Db>) -> Json {
+    let result = async {
+        let limit = query.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            return Err(anyhow::Error::msg(format!(
+                "Page size must be between 1 to {} entries",
+                MAX_PAGE_SIZE
+            )));
+        }
+
+        let actor_id = match &query.actor {
+            Some(actor) => Some(db.get_user_by_name(actor).await?.id),
+            None => None,
+        };
+        let target_id = match &query.target {
+            Some(target) => Some(db.get_user_by_name(target).await?.id),
+            None => None,
+        };
+
+        let filter = AuditFilter {
+            action: query.action,
+            actor_id,
+            target_id,
+            from: query.from.map(|x| x.and_hms_opt(0, 0, 0).unwrap()),
+            to: query
+                .to
+                .map(|x| x.and_hms_opt(0, 0, 0).unwrap() + ChronoDuration::days(1)),
+            offset: query.offset.unwrap_or(0),
+            limit,
+        };
+
+        let (events, total) = db.get_audit_events(&filter).await?;
+        Ok(AuditPage {
+            total,
+            offset: filter.offset,
+            limit,
+            events,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(page) => warp::reply::json(&page),
+        Err(err) => warp::reply::json(&AuditError {
+            err: err.to_string(),
+        }),
+    }
+}
+
+/// Deletes events older than the retention period, configured in days
+/// through `AUDIT_RETENTION_DAYS`.
+pub async fn run_retention(db: Arc<Db>) {
+    let days = dotenv::var("AUDIT_RETENTION_DAYS")
+        .ok()
+        .and_then(|x| x.parse().ok())
+        .unwrap_or(DEFAULT_RETENTION_DAYS);
+
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match db.delete_audit_events_before(days).await {
+            Ok(0) => {}
+            Ok(deleted) => log::info!("Deleted {} audit events older than {} days", deleted, days),
+            Err(error) => log::error!("Audit retention failed: {}", error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn truncates_on_char_boundaries() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("exactly", 7), "exactly");
+        assert_eq!(truncate("truncated", 5), "trunc");
+        assert_eq!(truncate("ääää", 2), "ää");
+    }
+
+    #[test]
+    fn actions_round_trip() {
+        let actions = [
+            AuditAction::Login,
+            AuditAction::LoginFailed,
+            AuditAction::PasswordChanged,
+            AuditAction::PasswordResetRequested,
+            AuditAction::PasswordReset,
+            AuditAction::AccountDeleted,
+            AuditAction::GuestClaimed,
+            AuditAction::RoleChanged,
+            AuditAction::Banned,
+            AuditAction::Unbanned,
+            AuditAction::RatingAdjusted,
+            AuditAction::Renamed,
+            AuditAction::GameEnded,
+            AuditAction::TwoFactorEnabled,
+            AuditAction::TwoFactorDisabled,
+            AuditAction::RecoveryCodeUsed,
+        ];
+
+        for action in actions {
+            let stored = String::from(action.as_str());
+            assert_eq!(AuditAction::try_from(stored.clone()).unwrap(), action);
+            // query strings use the same names as the database
+            assert_eq!(
+                serde_json::to_string(&action).unwrap(),
+                format!("\"{}\"", stored)
+            );
+        }
+    }
+}
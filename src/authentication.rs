@@ -1,59 +1,326 @@
-use std::sync::Arc;
+use std::{convert::Infallible, sync::Arc};
 
-use crate::db::Db;
+use crate::{
+    audit::{self, AuditAction, RequestInfo},
+    db::{Db, User},
+    profile,
+    rate_limit::{self, LoginLimiter, TooManyRequests},
+    two_factor, username,
+};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
-use warp::reply::Json;
+use sha2::{Digest, Sha256};
+use warp::{
+    http::StatusCode,
+    reject::{MissingHeader, Reject},
+    reply::Response,
+    Filter, Rejection, Reply,
+};
+
+pub const SESSION_TOKEN_LENGTH: usize = 64;
+pub const SESSION_DURATION_DAYS: i64 = 30;
+
+/// Permission levels, each including the ones before it.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "lowercase")]
+pub enum Role {
+    Player,
+    Moderator,
+    Admin,
+}
+
+impl Role {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Role::Player => "player",
+            Role::Moderator => "moderator",
+            Role::Admin => "admin",
+        }
+    }
+}
+
+impl TryFrom<String> for Role {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "player" => Ok(Role::Player),
+            "moderator" => Ok(Role::Moderator),
+            "admin" => Ok(Role::Admin),
+            _ => Err(anyhow::Error::msg("Invalid role")),
+        }
+    }
+}
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AuthError {
     pub err: String,
+    /// Set for failures clients tell apart, such as refused usernames.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub code: Option<String>,
 }
 
 #[derive(Serialize, Deserialize, Debug)]
 pub struct AuthenticationData {
     pub username: String,
     pub password: String,
+    /// Only used when registering, needed to reset a forgotten password.
+    #[serde(default)]
+    pub email: Option<String>,
+    /// Authenticator or recovery code, skips the challenge step when given.
+    #[serde(default)]
+    pub code: Option<String>,
 }
 
-pub async fn register(authentication: AuthenticationData, db: Arc<Db>) -> String {
-    if authentication.username.len() < 3 || authentication.username.len() > 15 {
-        let error = AuthError {
-            err: String::from("Username must be between 3 to 15 characters"),
-        };
-        return serde_json::to_string(&error).unwrap();
+#[derive(Serialize)]
+pub struct LoginResponse {
+    #[serde(flatten)]
+    pub user: User,
+    pub token: String,
+}
+
+#[derive(Debug)]
+pub struct Unauthorized;
+
+impl Reject for Unauthorized {}
+
+#[derive(Debug)]
+pub struct Forbidden;
+
+impl Reject for Forbidden {}
+
+pub fn validate_username(username: &str) -> anyhow::Result<()> {
+    Ok(username::validate(username)?)
+}
+
+pub fn validate_password(password: &str) -> anyhow::Result<()> {
+    if password.len() < 3 || password.len() > 250 {
+        return Err(anyhow::Error::msg(
+            "Password must be between 3 to 250 characters",
+        ));
     }
 
-    if authentication.password.len() < 3 || authentication.password.len() > 250 {
-        let error = AuthError {
-            err: String::from("Username must be between 3 to 250 characters"),
-        };
-        return serde_json::to_string(&error).unwrap();
+    Ok(())
+}
+
+/// Tokens other than sessions are only stored hashed, so a leaked table
+/// can't be used to take over accounts.
+pub fn hash_token(token: &str) -> String {
+    hex::encode(Sha256::digest(token.as_bytes()))
+}
+
+pub fn generate_token() -> String {
+    rand::thread_rng()
+        .sample_iter(&Alphanumeric)
+        .take(SESSION_TOKEN_LENGTH)
+        .map(char::from)
+        .collect()
+}
+
+/// Resolves the `Authorization: Bearer <token>` header to the session's user.
+pub fn authenticated(db: Arc<Db>) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    warp::header::<String>("authorization")
+        .and(warp::any().map(move || db.clone()))
+        .and_then(|header: String, db: Arc<Db>| async move {
+            let token = match header.strip_prefix("Bearer ") {
+                Some(token) => token,
+                None => return Err(warp::reject::custom(Unauthorized)),
+            };
+
+            db.get_user_by_session(token)
+                .await
+                .map_err(|_| warp::reject::custom(Unauthorized))
+        })
+}
+
+/// Like [`authenticated`], but only lets users with at least `role` through.
+pub fn with_role(
+    db: Arc<Db>,
+    role: Role,
+) -> impl Filter<Extract = (User,), Error = Rejection> + Clone {
+    authenticated(db).and_then(move |user: User| async move {
+        if user.role >= role {
+            Ok(user)
+        } else {
+            Err(warp::reject::custom(Forbidden))
+        }
+    })
+}
+
+pub async fn handle_rejection(rejection: Rejection) -> Result<Response, Infallible> {
+    if let Some(TooManyRequests { retry_after }) = rejection.find() {
+        return Ok(rate_limit::too_many_requests(*retry_after));
+    }
+
+    let (status, err) = if rejection.is_not_found() {
+        (StatusCode::NOT_FOUND, "Not found")
+    } else if rejection.find::<Unauthorized>().is_some()
+        || rejection.find::<MissingHeader>().is_some()
+    {
+        (StatusCode::UNAUTHORIZED, "Invalid or expired session")
+    } else if rejection.find::<Forbidden>().is_some() {
+        (StatusCode::FORBIDDEN, "Insufficient permissions")
+    } else if rejection.find::<warp::reject::MethodNotAllowed>().is_some() {
+        (StatusCode::METHOD_NOT_ALLOWED, "Method not allowed")
+    } else {
+        (StatusCode::BAD_REQUEST, "Bad request")
+    };
+
+    let error = AuthError {
+        err: String::from(err),
+        code: None,
+    };
+
+    Ok(warp::reply::with_status(warp::reply::json(&error), status).into_response())
+}
+
+pub async fn register(authentication: AuthenticationData, db: Arc<Db>) -> String {
+    if let Err(error) = validate_username(&authentication.username)
+        .and_then(|()| validate_password(&authentication.password))
+    {
+        return serde_json::to_string(&AuthError {
+            err: error.to_string(),
+            code: username::error_code(&error),
+        })
+        .unwrap();
     }
 
+    let email = match authentication
+        .email
+        .as_deref()
+        .map(profile::validate_email)
+        .transpose()
+    {
+        Ok(email) => email.flatten(),
+        Err(error) => {
+            return serde_json::to_string(&AuthError {
+                err: error.to_string(),
+                code: None,
+            })
+            .unwrap()
+        }
+    };
+
     match db
-        .insert_user(&authentication.username, &authentication.password)
+        .insert_user(
+            &authentication.username,
+            &authentication.password,
+            email.as_deref(),
+        )
         .await
     {
-        Ok(()) => serde_json::to_string(&AuthError { err: String::new() }).unwrap(),
+        Ok(()) => serde_json::to_string(&AuthError {
+            err: String::new(),
+            code: None,
+        })
+        .unwrap(),
         Err(error) => serde_json::to_string(&AuthError {
             err: error.to_string(),
+            code: username::error_code(&error),
         })
         .unwrap(),
     }
 }
 
-pub async fn login(authentication: AuthenticationData, db: Arc<Db>) -> Json {
+pub async fn login(
+    request: RequestInfo,
+    authentication: AuthenticationData,
+    db: Arc<Db>,
+    limiter: Arc<LoginLimiter>,
+) -> Response {
+    if let Err(retry_after) = limiter.check_user(&authentication.username).await {
+        return rate_limit::too_many_requests(retry_after);
+    }
+
     let user = match db
         .get_user_by_name_password(&authentication.username, &authentication.password)
         .await
     {
         Ok(user) => user,
         Err(error) => {
+            limiter
+                .record_failure(request.ip, &authentication.username)
+                .await;
+            audit::record_failed_login(&db, &request, &authentication.username, &error).await;
             return warp::reply::json(&AuthError {
                 err: error.to_string(),
+                code: None,
             })
+            .into_response();
         }
     };
 
-    warp::reply::json(&user)
+    if user.totp_enabled {
+        let result = match &authentication.code {
+            Some(code) => two_factor::verify_login(&db, &request, &limiter, &user, code)
+                .await
+                .map(|()| None),
+            None => two_factor::challenge(&db, &user).await.map(Some),
+        };
+
+        match result {
+            Ok(None) => {}
+            Ok(Some(challenge)) => return warp::reply::json(&challenge).into_response(),
+            Err(error) => {
+                return warp::reply::json(&AuthError {
+                    err: error.to_string(),
+                    code: None,
+                })
+                .into_response()
+            }
+        }
+    }
+
+    limiter.record_success(&user.username).await;
+    audit::record(
+        &db,
+        &request,
+        AuditAction::Login,
+        Some(user.id),
+        Some(user.id),
+        None,
+    )
+    .await;
+
+    let token = generate_token();
+    if let Err(error) = db.insert_session(&token, user.id).await {
+        return warp::reply::json(&AuthError {
+            err: error.to_string(),
+            code: None,
+        })
+        .into_response();
+    }
+
+    warp::reply::json(&LoginResponse { user, token }).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn login_response_omits_password() {
+        let response = LoginResponse {
+            user: User {
+                id: 1,
+                username: String::from("alice"),
+                password: String::from("password hash"),
+                elo_points: 1000,
+                country_id: String::from("NO"),
+                profile_picture_url: String::new(),
+                total_sips: 0,
+                email: None,
+                is_guest: false,
+                role: Role::Player,
+                totp_enabled: false,
+            },
+            token: String::from("token"),
+        };
+        let json: serde_json::Value = serde_json::to_value(&response).unwrap();
+
+        assert_eq!(json["username"], "alice");
+        assert_eq!(json["token"], "token");
+        assert!(json.get("password").is_none());
+        assert!(!json.to_string().contains("password hash"));
+    }
 }
@@ -0,0 +1,251 @@
+use crate::{
+    db::{Db, User},
+    profile,
+};
+use futures_util::{TryFutureExt, TryStreamExt};
+use image::{
+    imageops::FilterType,
+    io::{Limits, Reader},
+    ImageFormat,
+};
+use rand::{distributions::Alphanumeric, Rng};
+use serde::{Deserialize, Serialize};
+use std::{future::Future, io::Cursor, path::PathBuf, pin::Pin, sync::Arc};
+use warp::{multipart::FormData, reply::Json, Buf};
+
+pub const MAX_UPLOAD_BYTES: u64 = 2 * 1024 * 1024;
+pub const MAX_IMAGE_DIMENSION: u32 = 4096;
+
+/// Square thumbnails generated for every upload, the first one becomes the
+/// profile picture.
+pub const THUMBNAIL_SIZES: [u32; 2] = [256, 64];
+
+pub type BlobFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// Storage for uploaded files, addressed by key.
+pub trait BlobStore: Send + Sync {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BlobFuture<'a, ()>;
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a, ()>;
+    /// Public URL the blob is served from.
+    fn url(&self, key: &str) -> String;
+    /// Inverse of [`BlobStore::url`], `None` for URLs this store did not hand out.
+    fn key(&self, url: &str) -> Option<String>;
+}
+
+/// Keeps blobs as files below `root`, served by the `/avatars` route.
+pub struct LocalBlobStore {
+    pub root: PathBuf,
+    pub public_url: String,
+}
+
+impl LocalBlobStore {
+    /// Configured through `AVATAR_DIR` and `PUBLIC_URL`.
+    pub fn from_env() -> Self {
+        dotenv::dotenv().ok();
+
+        Self {
+            root: PathBuf::from(dotenv::var("AVATAR_DIR").unwrap_or(String::from("avatars"))),
+            public_url: format!(
+                "{}/avatars",
+                dotenv::var("PUBLIC_URL")
+                    .unwrap_or(String::from("http://localhost:8000"))
+                    .trim_end_matches('/')
+            ),
+        }
+    }
+
+    fn path(&self, key: &str) -> anyhow::Result<PathBuf> {
+        if key.is_empty() || key.contains(['/', '\\']) || key.starts_with('.') {
+            return Err(anyhow::Error::msg("Invalid blob key"));
+        }
+
+        Ok(self.root.join(key))
+    }
+}
+
+impl BlobStore for LocalBlobStore {
+    fn put<'a>(&'a self, key: &'a str, data: Vec<u8>) -> BlobFuture<'a, ()> {
+        Box::pin(async move {
+            let path = self.path(key)?;
+            tokio::fs::create_dir_all(&self.root).await?;
+            tokio::fs::write(path, data).await?;
+            Ok(())
+        })
+    }
+
+    fn delete<'a>(&'a self, key: &'a str) -> BlobFuture<'a, ()> {
+        Box::pin(async move {
+            tokio::fs::remove_file(self.path(key)?).await?;
+            Ok(())
+        })
+    }
+
+    fn url(&self, key: &str) -> String {
+        format!("{}/{}", self.public_url, key)
+    }
+
+    fn key(&self, url: &str) -> Option<String> {
+        url.strip_prefix(&self.public_url)?
+            .strip_prefix('/')
+            .map(String::from)
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AvatarError {
+    pub err: String,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&AvatarError {
+        err: error.to_string(),
+    })
+}
+
+/// Reads the `avatar` field of the form.
+async fn read_avatar(form: FormData) -> anyhow::Result<Vec<u8>> {
+    let mut form = form.map_err(anyhow::Error::from);
+    while let Some(part) = form.try_next().await? {
+        if part.name() != "avatar" {
+            continue;
+        }
+
+        return part
+            .stream()
+            .try_fold(Vec::new(), |mut data, buf| async move {
+                data.extend_from_slice(buf.chunk());
+                Ok(data)
+            })
+            .map_err(anyhow::Error::from)
+            .await;
+    }
+
+    Err(anyhow::Error::msg("Missing avatar field"))
+}
+
+/// Decodes a PNG or JPEG upload and renders the thumbnails as PNGs.
+fn render_thumbnails(data: &[u8]) -> anyhow::Result<Vec<(u32, Vec<u8>)>> {
+    let mut reader = Reader::new(Cursor::new(data)).with_guessed_format()?;
+    if !matches!(reader.format(), Some(ImageFormat::Png | ImageFormat::Jpeg)) {
+        return Err(anyhow::Error::msg("Avatar must be a PNG or JPEG image"));
+    }
+
+    let mut limits = Limits::default();
+    limits.max_image_width = Some(MAX_IMAGE_DIMENSION);
+    limits.max_image_height = Some(MAX_IMAGE_DIMENSION);
+    reader.limits(limits);
+
+    let image = reader.decode()?;
+
+    THUMBNAIL_SIZES
+        .iter()
+        .map(|&size| {
+            let mut thumbnail = Vec::new();
+            image
+                .resize_to_fill(size, size, FilterType::Lanczos3)
+                .write_to(&mut Cursor::new(&mut thumbnail), ImageFormat::Png)?;
+            Ok((size, thumbnail))
+        })
+        .collect()
+}
+
+/// Key of an upload `user_id` made, `None` for other users' uploads and
+/// foreign URLs.
+fn owned_key(blob_store: &dyn BlobStore, user_id: i32, url: &str) -> Option<String> {
+    blob_store
+        .key(url)
+        .filter(|x| x.starts_with(&format!("{}-", user_id)))
+}
+
+/// Removes every thumbnail of the user's current upload. Pictures pointing
+/// at someone else's upload are left alone.
+pub async fn delete_thumbnails(blob_store: &dyn BlobStore, user: &User) {
+    let Some(key) = owned_key(blob_store, user.id, &user.profile_picture_url) else {
+        return;
+    };
+
+    for size in THUMBNAIL_SIZES {
+        let key = key.replacen(
+            &format!("-{}.png", THUMBNAIL_SIZES[0]),
+            &format!("-{}.png", size),
+            1,
+        );
+        if let Err(error) = blob_store.delete(&key).await {
+            log::warn!("Failed to delete avatar {}: {}", key, error);
+        }
+    }
+}
+
+pub async fn upload(
+    user: User,
+    form: FormData,
+    db: Arc<Db>,
+    blob_store: Arc<dyn BlobStore>,
+) -> Json {
+    let result = async {
+        let data = read_avatar(form).await?;
+        let thumbnails = tokio::task::spawn_blocking(move || render_thumbnails(&data)).await??;
+
+        // a fresh name per upload keeps caches from serving the old picture
+        let nonce: String = rand::thread_rng()
+            .sample_iter(&Alphanumeric)
+            .take(8)
+            .map(char::from)
+            .collect();
+
+        let mut urls = Vec::with_capacity(thumbnails.len());
+        for (size, thumbnail) in thumbnails {
+            let key = format!("{}-{}-{}.png", user.id, nonce, size);
+            blob_store.put(&key, thumbnail).await?;
+            urls.push(blob_store.url(&key));
+        }
+
+        db.update_profile(user.id, None, Some(&urls[0])).await?;
+
+        // the previous upload is not referenced anymore
+        delete_thumbnails(blob_store.as_ref(), &user).await;
+
+        profile::get_profile(&db, &user.username).await
+    }
+    .await;
+
+    match result {
+        Ok(profile) => warp::reply::json(&profile),
+        Err(err) => error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> LocalBlobStore {
+        LocalBlobStore {
+            root: PathBuf::from("avatars"),
+            public_url: String::from("https://example.com/avatars"),
+        }
+    }
+
+    #[test]
+    fn owns_own_uploads() {
+        let url = "https://example.com/avatars/12-abcdefgh-256.png";
+        assert_eq!(
+            owned_key(&store(), 12, url).as_deref(),
+            Some("12-abcdefgh-256.png")
+        );
+    }
+
+    #[test]
+    fn does_not_own_other_uploads() {
+        let store = store();
+        let urls = [
+            "https://example.com/avatars/12-abcdefgh-256.png",
+            "https://example.com/avatars/123-abcdefgh-256.png",
+            "https://other.com/avatars/1-abcdefgh-256.png",
+        ];
+
+        for url in urls {
+            assert!(owned_key(&store, 1, url).is_none(), "{}", url);
+        }
+    }
+}
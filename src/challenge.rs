@@ -0,0 +1,302 @@
+use crate::{
+    db::{Db, User},
+    game::{self, State},
+    rules::RuleSet,
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{sync::Arc, time::Duration};
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use warp::filters::ws::Message;
+
+/// Challenges not answered within this time are dropped.
+pub const CHALLENGE_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// A friend asked to play a series, waiting for the target to answer.
+#[derive(Debug)]
+pub struct Challenge {
+    pub id: u64,
+    pub challenger: (String, UnboundedSender<Message>),
+    pub target: String,
+    pub rules: RuleSet,
+    pub ranked: bool,
+}
+
+impl Challenge {
+    pub fn involves(&self, username: &str) -> bool {
+        self.challenger.0 == username || self.target == username
+    }
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeNotification {
+    id: String,
+    username: String,
+    rules: RuleSet,
+    ranked: bool,
+    expires_in: u64,
+}
+
+/// Sent, declined, cancelled or expired challenges, `username` being the
+/// other player.
+#[derive(Serialize, Deserialize)]
+pub struct ChallengeUpdateNotification {
+    id: String,
+    username: String,
+}
+
+fn send_update(tx: &UnboundedSender<Message>, id: &str, username: &str) {
+    let notif = ChallengeUpdateNotification {
+        id: String::from(id),
+        username: String::from(username),
+    };
+
+    // the player may have left already
+    let _ = tx.send(Message::text(serde_json::to_string(&notif).unwrap()));
+}
+
+fn notify(state: &State, username: &str, id: &str, other: &str) {
+    if let Some(tx) = state.connections.get(username) {
+        send_update(tx, id, other);
+    }
+}
+
+fn get_username(msg: &Value) -> anyhow::Result<&str> {
+    msg["username"]
+        .as_str()
+        .ok_or_else(|| anyhow::Error::msg("Missing username"))
+}
+
+/// Challenges an online friend, ranked unless `ranked` is false.
+pub async fn challenge(
+    db: &Db,
+    state: &Arc<Mutex<State>>,
+    me: &User,
+    tx: &UnboundedSender<Message>,
+    msg: &Value,
+) -> anyhow::Result<()> {
+    let target = get_username(msg)?;
+    let rules = game::parse_rules(msg)?;
+    let ranked = msg["ranked"].as_bool().unwrap_or(true);
+
+    if target == me.username {
+        return Err(anyhow::Error::msg("You can't challenge yourself"));
+    }
+
+    let friends = db.get_friend_usernames(&me.username).await?;
+    let target = friends
+        .into_iter()
+        .find(|x| x == target)
+        .ok_or_else(|| anyhow::Error::msg("You can only challenge friends"))?;
+
+    let mut locked_state = state.lock().await;
+    if !locked_state.connections.contains_key(&target) {
+        return Err(anyhow::Error::msg("Player is offline"));
+    }
+    if locked_state.is_playing(&me.username) || locked_state.is_playing(&target) {
+        return Err(anyhow::Error::msg("Player is already in a game"));
+    }
+    if locked_state
+        .challenges
+        .iter()
+        .any(|x| x.involves(&me.username) && x.involves(&target))
+    {
+        return Err(anyhow::Error::msg(
+            "There already is a challenge between you",
+        ));
+    }
+
+    let notif = ChallengeNotification {
+        id: String::from("challenge"),
+        username: me.username.clone(),
+        rules,
+        ranked,
+        expires_in: CHALLENGE_TIMEOUT.as_secs(),
+    };
+    // only keep challenges the target actually received
+    locked_state.connections[&target]
+        .send(Message::text(serde_json::to_string(&notif).unwrap()))?;
+
+    let id = rand::random();
+    locked_state.challenges.push(Challenge {
+        id,
+        challenger: (me.username.clone(), tx.clone()),
+        target: target.clone(),
+        rules,
+        ranked,
+    });
+    send_update(tx, "challengesent", &target);
+    drop(locked_state);
+
+    let state = state.clone();
+    tokio::task::spawn(async move {
+        tokio::time::sleep(CHALLENGE_TIMEOUT).await;
+
+        let mut state = state.lock().await;
+        if let Some(index) = state.challenges.iter().position(|x| x.id == id) {
+            let challenge = state.challenges.remove(index);
+            send_update(
+                &challenge.challenger.1,
+                "challengeexpired",
+                &challenge.target,
+            );
+            notify(
+                &state,
+                &challenge.target,
+                "challengeexpired",
+                &challenge.challenger.0,
+            );
+        }
+    });
+
+    Ok(())
+}
+
+fn take_challenge(state: &mut State, challenger: &str, target: &str) -> anyhow::Result<Challenge> {
+    let index = state
+        .challenges
+        .iter()
+        .position(|x| x.challenger.0 == challenger && x.target == target)
+        .ok_or_else(|| anyhow::Error::msg("Challenge does not exist"))?;
+
+    Ok(state.challenges.remove(index))
+}
+
+/// Accepts a challenge, starting the series right away.
+pub async fn accept(
+    db: &Db,
+    state: &Mutex<State>,
+    me: &User,
+    tx: &UnboundedSender<Message>,
+    msg: &Value,
+) -> anyhow::Result<()> {
+    let challenger = get_username(msg)?;
+
+    let mut state = state.lock().await;
+    let challenge = take_challenge(&mut state, challenger, &me.username)?;
+
+    if state.is_playing(&me.username) || state.is_playing(challenger) {
+        send_update(&challenge.challenger.1, "challengecancelled", &me.username);
+        return Err(anyhow::Error::msg("Player is already in a game"));
+    }
+
+    // neither player waits for another opponent anymore
+    state
+        .pending_matches
+        .retain(|_, x| x.0 != me.username && x.0 != challenger);
+    state
+        .rooms
+        .retain(|_, x| x.host.0 != me.username && x.host.0 != challenger);
+
+    game::start_challenge_game(
+        db,
+        &mut state,
+        challenge.challenger,
+        (me.username.clone(), tx.clone()),
+        challenge.rules,
+        challenge.ranked,
+    )
+    .await
+}
+
+pub async fn decline(state: &Mutex<State>, me: &User, msg: &Value) -> anyhow::Result<()> {
+    let challenger = get_username(msg)?;
+
+    let mut state = state.lock().await;
+    let challenge = take_challenge(&mut state, challenger, &me.username)?;
+    send_update(&challenge.challenger.1, "challengedeclined", &me.username);
+
+    Ok(())
+}
+
+/// Takes back a challenge the player sent.
+pub async fn cancel(state: &Mutex<State>, me: &User, msg: &Value) -> anyhow::Result<()> {
+    let target = get_username(msg)?;
+
+    let mut state = state.lock().await;
+    take_challenge(&mut state, &me.username, target)?;
+    notify(&state, target, "challengecancelled", &me.username);
+
+    Ok(())
+}
+
+/// Drops the challenges of a player who disconnected.
+pub fn cancel_all(state: &mut State, username: &str) {
+    let (cancelled, challenges) = std::mem::take(&mut state.challenges)
+        .into_iter()
+        .partition::<Vec<_>, _>(|x| x.involves(username));
+    state.challenges = challenges;
+
+    for challenge in cancelled {
+        if challenge.challenger.0 == username {
+            notify(state, &challenge.target, "challengecancelled", username);
+        } else {
+            send_update(&challenge.challenger.1, "challengecancelled", username);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn add_challenge(
+        state: &mut State,
+        challenger: &str,
+        target: &str,
+    ) -> UnboundedReceiver<Message> {
+        let (tx, rx) = unbounded_channel();
+        state.challenges.push(Challenge {
+            id: rand::random(),
+            challenger: (String::from(challenger), tx),
+            target: String::from(target),
+            rules: RuleSet::default(),
+            ranked: true,
+        });
+
+        rx
+    }
+
+    fn received(rx: &mut UnboundedReceiver<Message>) -> Vec<ChallengeUpdateNotification> {
+        std::iter::from_fn(|| rx.try_recv().ok())
+            .map(|x| serde_json::from_str(x.to_str().unwrap()).unwrap())
+            .collect()
+    }
+
+    #[test]
+    fn take_challenge_matches_direction() {
+        let mut state = State::new();
+        let _rx = add_challenge(&mut state, "alice", "bob");
+
+        assert!(take_challenge(&mut state, "bob", "alice").is_err());
+        assert!(take_challenge(&mut state, "alice", "bob").is_ok());
+        assert!(state.challenges.is_empty());
+    }
+
+    #[test]
+    fn cancel_all_notifies_the_other_player() {
+        let mut state = State::new();
+        let (carol_tx, mut carol_rx) = unbounded_channel();
+        state.connections.insert(String::from("carol"), carol_tx);
+
+        let mut alice_rx = add_challenge(&mut state, "alice", "bob");
+        let _bob_rx = add_challenge(&mut state, "bob", "carol");
+        let _dave_rx = add_challenge(&mut state, "dave", "erin");
+
+        cancel_all(&mut state, "bob");
+
+        assert_eq!(state.challenges.len(), 1);
+        assert_eq!(state.challenges[0].challenger.0, "dave");
+
+        let alice = received(&mut alice_rx);
+        assert_eq!(alice.len(), 1);
+        assert_eq!(alice[0].id, "challengecancelled");
+        assert_eq!(alice[0].username, "bob");
+
+        let carol = received(&mut carol_rx);
+        assert_eq!(carol.len(), 1);
+        assert_eq!(carol[0].id, "challengecancelled");
+        assert_eq!(carol[0].username, "bob");
+    }
+}
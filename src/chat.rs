@@ -0,0 +1,279 @@
+use crate::{
+    db::{Db, User},
+    game::{GameState, State},
+    profanity,
+    rate_limit::{self, ChatLimiter},
+};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use warp::filters::ws::Message;
+
+pub const MAX_CHAT_LENGTH: usize = 200;
+/// Quick reactions, clients map them to images.
+pub const EMOTES: &[&str] = &[
+    "gg", "wp", "cheers", "laugh", "wow", "oops", "thinking", "sorry", "thanks",
+];
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatNotification {
+    id: String,
+    username: String,
+    text: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct EmoteNotification {
+    id: String,
+    username: String,
+    emote: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct ChatMutedNotification {
+    id: String,
+    muted: bool,
+}
+
+async fn check_rate(limiter: &ChatLimiter, username: &str) -> anyhow::Result<()> {
+    limiter.check(username).await.map_err(|retry_after| {
+        anyhow::Error::msg(format!(
+            "You are sending messages too fast, try again in {} seconds",
+            rate_limit::retry_after_secs(retry_after)
+        ))
+    })
+}
+
+fn find_game<'a>(state: &'a State, username: &str) -> anyhow::Result<&'a GameState> {
+    state
+        .games
+        .iter()
+        .find(|x| x.p1.0 == username || x.p2.0 == username)
+        .ok_or_else(|| anyhow::Error::msg("You are not in a game"))
+}
+
+/// Sends to the sender, and to the opponent unless they muted the chat.
+fn relay(state: &State, game: &GameState, sender: &str, message: Message) {
+    let (me, opponent) = if game.p1.0 == sender {
+        (&game.p1, &game.p2)
+    } else {
+        (&game.p2, &game.p1)
+    };
+
+    if !state
+        .chat_muted
+        .contains(&(opponent.0.clone(), me.0.clone()))
+    {
+        let _ = opponent.1.send(message.clone());
+    }
+    let _ = me.1.send(message);
+}
+
+pub async fn chat(
+    db: &Db,
+    state: &Mutex<State>,
+    limiter: &ChatLimiter,
+    me: &User,
+    msg: &Value,
+) -> anyhow::Result<()> {
+    let text = msg["text"].as_str().unwrap_or_default().trim();
+    if text.is_empty() || text.chars().count() > MAX_CHAT_LENGTH {
+        return Err(anyhow::Error::msg(format!(
+            "Messages must be between 1 to {} characters",
+            MAX_CHAT_LENGTH
+        )));
+    }
+
+    check_rate(limiter, &me.username).await?;
+
+    let state = state.lock().await;
+    let game = find_game(&state, &me.username)?;
+
+    let masked = profanity::mask(text);
+    let filtered = masked != text;
+    db.insert_chat_message(game.id, me.id, Some(text), None, filtered)
+        .await?;
+
+    let notif = ChatNotification {
+        id: String::from("chat"),
+        username: me.username.clone(),
+        text: masked,
+    };
+    relay(
+        &state,
+        game,
+        &me.username,
+        Message::text(serde_json::to_string(&notif).unwrap()),
+    );
+
+    Ok(())
+}
+
+pub async fn emote(
+    db: &Db,
+    state: &Mutex<State>,
+    limiter: &ChatLimiter,
+    me: &User,
+    msg: &Value,
+) -> anyhow::Result<()> {
+    let emote = msg["emote"].as_str().unwrap_or_default();
+    if !EMOTES.contains(&emote) {
+        return Err(anyhow::Error::msg("Unknown emote"));
+    }
+
+    check_rate(limiter, &me.username).await?;
+
+    let state = state.lock().await;
+    let game = find_game(&state, &me.username)?;
+
+    db.insert_chat_message(game.id, me.id, None, Some(emote), false)
+        .await?;
+
+    let notif = EmoteNotification {
+        id: String::from("emote"),
+        username: me.username.clone(),
+        emote: String::from(emote),
+    };
+    relay(
+        &state,
+        game,
+        &me.username,
+        Message::text(serde_json::to_string(&notif).unwrap()),
+    );
+
+    Ok(())
+}
+
+/// Stops or resumes the opponent's chat and emotes for the rest of the
+/// series.
+pub async fn mute(
+    state: &Mutex<State>,
+    me: &User,
+    tx: &UnboundedSender<Message>,
+    msg: &Value,
+) -> anyhow::Result<()> {
+    let muted = msg["muted"].as_bool().unwrap_or(true);
+
+    let mut state = state.lock().await;
+    let game = find_game(&state, &me.username)?;
+    let opponent = if game.p1.0 == me.username {
+        game.p2.0.clone()
+    } else {
+        game.p1.0.clone()
+    };
+
+    let key = (me.username.clone(), opponent);
+    if muted {
+        state.chat_muted.insert(key);
+    } else {
+        state.chat_muted.remove(&key);
+    }
+
+    let notif = ChatMutedNotification {
+        id: String::from("chatmuted"),
+        muted,
+    };
+    tx.send(Message::text(serde_json::to_string(&notif).unwrap()))?;
+
+    Ok(())
+}
+
+/// Forgets the mutes between two players once their series is over.
+pub fn clear_mutes(state: &mut State, p1: &str, p2: &str) {
+    state.chat_muted.retain(|(player, opponent)| {
+        !(player == p1 && opponent == p2 || player == p2 && opponent == p1)
+    });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::Series, rules::RuleSet};
+    use std::time::Instant;
+    use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver};
+
+    fn game() -> (
+        GameState,
+        UnboundedReceiver<Message>,
+        UnboundedReceiver<Message>,
+    ) {
+        let (p1_tx, p1_rx) = unbounded_channel();
+        let (p2_tx, p2_rx) = unbounded_channel();
+
+        let game = GameState {
+            id: 1,
+            p1: (String::from("alice"), p1_tx),
+            p2: (String::from("bob"), p2_tx),
+            turn: String::from("alice"),
+            timer: Instant::now(),
+            p1_cards: Vec::new(),
+            p2_cards: Vec::new(),
+            turned_card: None,
+            rules: RuleSet::default(),
+            p1_sips: 0,
+            p2_sips: 0,
+            series: Series {
+                best_of: 1,
+                p1_wins: 0,
+                p2_wins: 0,
+                first_turn: String::from("alice"),
+                tournament_match: None,
+                casual: false,
+            },
+            rated: true,
+        };
+
+        (game, p1_rx, p2_rx)
+    }
+
+    #[test]
+    fn relays_to_both_players() {
+        let (game, mut alice, mut bob) = game();
+        relay(&State::new(), &game, "alice", Message::text("hi"));
+
+        assert_eq!(alice.try_recv().unwrap().to_str().unwrap(), "hi");
+        assert_eq!(bob.try_recv().unwrap().to_str().unwrap(), "hi");
+    }
+
+    #[test]
+    fn muted_chat_only_reaches_the_sender() {
+        let (game, mut alice, mut bob) = game();
+        let mut state = State::new();
+        state
+            .chat_muted
+            .insert((String::from("bob"), String::from("alice")));
+
+        relay(&state, &game, "alice", Message::text("hi"));
+        assert!(alice.try_recv().is_ok());
+        assert!(bob.try_recv().is_err());
+
+        relay(&state, &game, "bob", Message::text("hello"));
+        assert!(alice.try_recv().is_ok());
+        assert!(bob.try_recv().is_ok());
+    }
+
+    #[test]
+    fn clears_mutes_between_the_players() {
+        let mut state = State::new();
+        for (player, opponent) in [("bob", "alice"), ("alice", "bob"), ("carol", "dave")] {
+            state
+                .chat_muted
+                .insert((String::from(player), String::from(opponent)));
+        }
+
+        clear_mutes(&mut state, "alice", "bob");
+        assert_eq!(state.chat_muted.len(), 1);
+        assert!(state
+            .chat_muted
+            .contains(&(String::from("carol"), String::from("dave"))));
+    }
+
+    #[test]
+    fn finds_the_players_game() {
+        let mut state = State::new();
+        state.games.push(game().0);
+
+        assert!(find_game(&state, "bob").is_ok());
+        assert!(find_game(&state, "carol").is_err());
+    }
+}
@@ -1,3 +1,13 @@
+use crate::{
+    audit::AuditAction,
+    authentication::Role,
+    friends::{self, Presence},
+    leaderboard::{LeaderboardCache, LeaderboardStream, LEADERBOARD_CACHE_TTL},
+    rules::RuleSet,
+    tournament::{TournamentFormat, TournamentStatus},
+    username::{self, UsernameError},
+};
+use chrono::NaiveDateTime;
 use serde::{Deserialize, Serialize};
 use sqlx::MySqlPool;
 
@@ -10,6 +20,7 @@ pub struct User {
     pub username: String,
 
     #[sqlx(rename = "Password")]
+    #[serde(skip_serializing)]
     pub password: String,
 
     #[sqlx(rename = "EloPoints")]
@@ -20,48 +31,2396 @@ pub struct User {
 
     #[sqlx(rename = "ProfilePictureURL")]
     pub profile_picture_url: String,
+
+    #[sqlx(rename = "TotalSips")]
+    pub total_sips: i32,
+
+    #[sqlx(rename = "Email")]
+    pub email: Option<String>,
+
+    #[sqlx(rename = "IsGuest")]
+    pub is_guest: bool,
+
+    #[sqlx(rename = "Role", try_from = "String")]
+    pub role: Role,
+
+    #[sqlx(rename = "TotpEnabled")]
+    pub totp_enabled: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, sqlx::FromRow)]
+pub struct LeaderboardEntry {
+    #[sqlx(rename = "Placement")]
+    pub rank: i64,
+
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "EloPoints")]
+    pub elo_points: i32,
+
+    #[sqlx(rename = "CountryID")]
+    pub country_id: String,
+
+    #[sqlx(rename = "ProfilePictureURL")]
+    pub profile_picture_url: String,
+
+    #[sqlx(rename = "GamesPlayed")]
+    pub games_played: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, sqlx::FromRow)]
+pub struct WindowedLeaderboardEntry {
+    #[sqlx(rename = "Placement")]
+    pub rank: i64,
+
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "EloPoints")]
+    pub elo_points: i32,
+
+    #[sqlx(rename = "CountryID")]
+    pub country_id: String,
+
+    #[sqlx(rename = "ProfilePictureURL")]
+    pub profile_picture_url: String,
+
+    #[sqlx(rename = "RatingGained")]
+    pub rating_gained: i64,
+
+    #[sqlx(rename = "GamesWon")]
+    pub games_won: i64,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct PlayerStats {
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "GamesPlayed")]
+    pub games_played: i32,
+
+    #[sqlx(rename = "Wins")]
+    pub wins: i32,
+
+    #[sqlx(rename = "Losses")]
+    pub losses: i32,
+
+    #[sqlx(skip)]
+    pub win_rate: f64,
+
+    /// Positive for a winning streak, negative for a losing one.
+    #[sqlx(rename = "CurrentStreak")]
+    pub current_streak: i32,
+
+    #[sqlx(rename = "BestStreak")]
+    pub best_streak: i32,
+
+    #[sqlx(rename = "PeakElo")]
+    pub peak_elo: i32,
+
+    #[sqlx(rename = "TotalGameSeconds")]
+    #[serde(skip)]
+    pub total_game_seconds: i64,
+
+    #[sqlx(skip)]
+    pub average_game_seconds: f64,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct RatingChange {
+    #[sqlx(rename = "GameID")]
+    pub game_id: Option<i32>,
+
+    #[sqlx(rename = "EloBefore")]
+    pub elo_before: i32,
+
+    #[sqlx(rename = "EloAfter")]
+    pub elo_after: i32,
+
+    #[sqlx(rename = "Delta")]
+    pub delta: i32,
+
+    /// Why the rating changed when it wasn't a game.
+    #[sqlx(rename = "Reason")]
+    pub reason: Option<String>,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct Ban {
+    #[sqlx(rename = "ID")]
+    pub id: i32,
+
+    #[sqlx(rename = "Moderator")]
+    pub moderator: String,
+
+    #[sqlx(rename = "Reason")]
+    pub reason: String,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+
+    /// `None` for permanent bans.
+    #[sqlx(rename = "ExpiresAt")]
+    pub expires_at: Option<NaiveDateTime>,
+
+    #[sqlx(rename = "LiftedAt")]
+    pub lifted_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct RatingAdjustment {
+    #[sqlx(rename = "Moderator")]
+    pub moderator: String,
+
+    #[sqlx(rename = "EloBefore")]
+    pub elo_before: i32,
+
+    #[sqlx(rename = "EloAfter")]
+    pub elo_after: i32,
+
+    #[sqlx(rename = "Delta")]
+    pub delta: i32,
+
+    #[sqlx(rename = "Reason")]
+    pub reason: String,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct UsernameChange {
+    #[sqlx(rename = "Moderator")]
+    pub moderator: String,
+
+    #[sqlx(rename = "OldUsername")]
+    pub old_username: String,
+
+    #[sqlx(rename = "NewUsername")]
+    pub new_username: String,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct GameRecord {
+    #[sqlx(rename = "ID")]
+    pub id: i32,
+
+    #[sqlx(rename = "Player1")]
+    pub player1: String,
+
+    #[sqlx(rename = "Player2")]
+    pub player2: String,
+
+    #[sqlx(rename = "Winner")]
+    pub winner: Option<String>,
+
+    #[sqlx(rename = "Rules", json)]
+    pub rules: RuleSet,
+
+    #[sqlx(rename = "StartedAt")]
+    pub started_at: NaiveDateTime,
+
+    #[sqlx(rename = "EndedAt")]
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct PenaltyRecord {
+    #[sqlx(rename = "GameID")]
+    pub game_id: i32,
+
+    #[sqlx(rename = "Sips")]
+    pub sips: i32,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct SessionRecord {
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+
+    #[sqlx(rename = "ExpiresAt")]
+    pub expires_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct TournamentEntry {
+    #[sqlx(rename = "TournamentID")]
+    pub tournament_id: i32,
+
+    #[sqlx(rename = "Name")]
+    pub name: String,
+
+    #[sqlx(rename = "Seed")]
+    pub seed: i32,
+
+    #[sqlx(rename = "Points")]
+    pub points: i32,
+
+    #[sqlx(rename = "Eliminated")]
+    pub eliminated: bool,
+
+    #[sqlx(rename = "RegisteredAt")]
+    pub registered_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct HeadToHead {
+    pub player1: String,
+    pub player2: String,
+    pub games: i64,
+    pub player1_wins: i64,
+    pub player2_wins: i64,
+    pub last_played: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct PlayerRank {
+    pub rank: i64,
+    pub country_rank: i64,
+    /// Zero based index of the player on the unfiltered leaderboard.
+    pub position: i64,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct LeaderboardFilter {
+    pub country: Option<String>,
+    pub min_games: u32,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+#[derive(Debug, Clone)]
+pub struct AuditFilter {
+    pub action: Option<AuditAction>,
+    pub actor_id: Option<i32>,
+    pub target_id: Option<i32>,
+    pub from: Option<NaiveDateTime>,
+    pub to: Option<NaiveDateTime>,
+    pub offset: u32,
+    pub limit: u32,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct AuditEvent {
+    #[sqlx(rename = "ID")]
+    pub id: i64,
+
+    #[sqlx(rename = "Action", try_from = "String")]
+    pub action: AuditAction,
+
+    #[sqlx(rename = "Actor")]
+    pub actor: Option<String>,
+
+    #[sqlx(rename = "Target")]
+    pub target: Option<String>,
+
+    #[sqlx(rename = "Details")]
+    pub details: Option<String>,
+
+    #[sqlx(rename = "IP")]
+    pub ip: Option<String>,
+
+    #[sqlx(rename = "UserAgent")]
+    pub user_agent: Option<String>,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct Friend {
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "EloPoints")]
+    pub elo_points: i32,
+
+    #[sqlx(rename = "CountryID")]
+    pub country_id: String,
+
+    #[sqlx(rename = "ProfilePictureURL")]
+    pub profile_picture_url: String,
+
+    #[sqlx(rename = "AcceptedAt")]
+    pub since: NaiveDateTime,
+
+    #[sqlx(skip)]
+    pub presence: Presence,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct FriendRequest {
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+
+    /// Sent to the user rather than by them.
+    #[sqlx(rename = "Incoming")]
+    pub incoming: bool,
+}
+
+#[derive(Serialize, Deserialize, Debug, sqlx::FromRow)]
+pub struct ChatMessage {
+    #[sqlx(rename = "GameID")]
+    pub game_id: i32,
+
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "Body")]
+    pub body: Option<String>,
+
+    #[sqlx(rename = "Emote")]
+    pub emote: Option<String>,
+
+    /// Shown masked to the players.
+    #[sqlx(rename = "Filtered")]
+    pub filtered: bool,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct Tournament {
+    #[sqlx(rename = "ID")]
+    pub id: i32,
+
+    #[sqlx(rename = "Name")]
+    pub name: String,
+
+    #[sqlx(rename = "Format", try_from = "String")]
+    pub format: TournamentFormat,
+
+    #[sqlx(rename = "Status", try_from = "String")]
+    pub status: TournamentStatus,
+
+    #[sqlx(rename = "Rules", json)]
+    pub rules: RuleSet,
+
+    #[sqlx(rename = "Rounds")]
+    pub rounds: i32,
+
+    #[sqlx(rename = "CurrentRound")]
+    pub current_round: i32,
+
+    #[sqlx(rename = "MaxPlayers")]
+    pub max_players: i32,
+
+    #[sqlx(rename = "RegistrationEndsAt")]
+    pub registration_ends_at: NaiveDateTime,
+
+    #[sqlx(rename = "Winner")]
+    pub winner: Option<String>,
+
+    #[sqlx(rename = "CreatedAt")]
+    pub created_at: NaiveDateTime,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct TournamentPlayer {
+    #[sqlx(rename = "UserID")]
+    pub user_id: i32,
+
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "EloPoints")]
+    pub elo_points: i32,
+
+    #[sqlx(rename = "Seed")]
+    pub seed: i32,
+
+    #[sqlx(rename = "Points")]
+    pub points: i32,
+
+    #[sqlx(rename = "Eliminated")]
+    pub eliminated: bool,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct TournamentMatch {
+    #[sqlx(rename = "ID")]
+    pub id: i32,
+
+    #[sqlx(rename = "TournamentID")]
+    pub tournament_id: i32,
+
+    #[sqlx(rename = "Round")]
+    pub round: i32,
+
+    #[sqlx(rename = "Slot")]
+    pub slot: i32,
+
+    #[sqlx(rename = "Player1ID")]
+    pub player1_id: Option<i32>,
+
+    #[sqlx(rename = "Player1")]
+    pub player1: Option<String>,
+
+    #[sqlx(rename = "Player2ID")]
+    pub player2_id: Option<i32>,
+
+    #[sqlx(rename = "Player2")]
+    pub player2: Option<String>,
+
+    #[sqlx(rename = "WinnerID")]
+    pub winner_id: Option<i32>,
+
+    #[sqlx(rename = "Winner")]
+    pub winner: Option<String>,
+
+    #[sqlx(rename = "GameID")]
+    pub game_id: Option<i32>,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct Season {
+    #[sqlx(rename = "ID")]
+    pub id: i32,
+
+    #[sqlx(rename = "Name")]
+    pub name: String,
+
+    #[sqlx(rename = "StartedAt")]
+    pub started_at: NaiveDateTime,
+
+    #[sqlx(rename = "EndedAt")]
+    pub ended_at: Option<NaiveDateTime>,
+}
+
+#[derive(Serialize, Deserialize, sqlx::FromRow)]
+pub struct SeasonStanding {
+    #[sqlx(rename = "SeasonID")]
+    pub season_id: i32,
+
+    #[sqlx(rename = "SeasonName")]
+    pub season_name: String,
+
+    #[sqlx(rename = "Username")]
+    pub username: String,
+
+    #[sqlx(rename = "CountryID")]
+    pub country_id: String,
+
+    #[sqlx(rename = "EloPoints")]
+    pub elo_points: i32,
+
+    #[sqlx(rename = "Placement")]
+    pub placement: i32,
+
+    #[sqlx(rename = "Reward")]
+    pub reward: Option<String>,
+}
+
+pub struct Db {
+    pub pool: MySqlPool,
+    pub leaderboard_cache: LeaderboardCache,
+    pub leaderboard_stream: LeaderboardStream,
 }
 
-pub struct Db {
-    pub pool: MySqlPool,
-}
+impl Db {
+    pub async fn new() -> Self {
+        dotenv::dotenv().ok();
+
+        let pool = MySqlPool::connect(&dotenv::var("DATABASE_URL").unwrap())
+            .await
+            .unwrap();
+
+        Self::run_migrations(&pool).await;
+
+        Self {
+            pool,
+            leaderboard_cache: LeaderboardCache::new(LEADERBOARD_CACHE_TTL),
+            leaderboard_stream: LeaderboardStream::new(),
+        }
+    }
+
+    pub async fn run_migrations(pool: &MySqlPool) {
+        sqlx::migrate!("db/migrations").run(pool).await.unwrap()
+    }
+
+    /// Whether another user already has a name that reads the same.
+    async fn normalized_username_exists(
+        &self,
+        transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+        normalized: &str,
+        except_user_id: Option<i32>,
+    ) -> anyhow::Result<bool> {
+        const QUERY: &str = "
+            SELECT EXISTS(
+                SELECT 1 FROM User WHERE NormalizedUsername = ? AND ID != COALESCE(?, 0)
+            )
+        ";
+
+        let result: (bool,) = sqlx::query_as(QUERY)
+            .bind(normalized)
+            .bind(except_user_id)
+            .fetch_one(&mut **transaction)
+            .await?;
+
+        Ok(result.0)
+    }
+
+    pub async fn get_unnormalized_usernames(&self) -> anyhow::Result<Vec<(i32, String)>> {
+        let users =
+            sqlx::query_as("SELECT ID, Username FROM User WHERE NormalizedUsername IS NULL")
+                .fetch_all(&self.pool)
+                .await?;
+
+        Ok(users)
+    }
+
+    /// Returns false when the name is already taken by a look-alike.
+    pub async fn set_normalized_username(
+        &self,
+        user_id: i32,
+        normalized: &str,
+    ) -> anyhow::Result<bool> {
+        let result = sqlx::query("UPDATE User SET NormalizedUsername = ? WHERE ID = ?")
+            .bind(normalized)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await;
+
+        match result {
+            Ok(_) => Ok(true),
+            Err(sqlx::Error::Database(error)) if error.is_unique_violation() => Ok(false),
+            Err(error) => Err(error.into()),
+        }
+    }
+
+    pub async fn insert_user(
+        &self,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let normalized = username::normalize(username);
+        let mut transaction = self.pool.begin().await?;
+        if self
+            .normalized_username_exists(&mut transaction, &normalized, None)
+            .await?
+        {
+            return Err(UsernameError::Taken.into());
+        }
+
+        const QUERY: &str = "
+            INSERT INTO User(Username, NormalizedUsername, Password, Email) VALUES(?, ?, ?, ?)
+        ";
+
+        sqlx::query(QUERY)
+            .bind(username)
+            .bind(&normalized)
+            .bind(password)
+            .bind(email)
+            .execute(&mut *transaction)
+            .await
+            .map_err(username_taken)?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Creates a guest account, failing when the generated name is taken.
+    pub async fn insert_guest(&self, username: &str, password: &str) -> anyhow::Result<User> {
+        const QUERY: &str = "
+            INSERT INTO User(Username, NormalizedUsername, Password, IsGuest)
+            VALUES(?, ?, ?, TRUE)
+        ";
+
+        sqlx::query(QUERY)
+            .bind(username)
+            .bind(username::normalize(username))
+            .bind(password)
+            .execute(&self.pool)
+            .await
+            .map_err(username_taken)?;
+
+        self.get_user_by_name(username).await
+    }
+
+    /// Guests that neither signed in nor started a game within `days` days.
+    pub async fn get_inactive_guests(&self, days: u32) -> anyhow::Result<Vec<i32>> {
+        const QUERY: &str = "
+            SELECT ID FROM User
+            WHERE IsGuest AND DeletedAt IS NULL
+                AND NOT EXISTS (
+                    SELECT * FROM Session WHERE Session.UserID = User.ID
+                        AND Session.CreatedAt >= DATE_SUB(CURRENT_TIMESTAMP, INTERVAL ? DAY)
+                )
+                AND NOT EXISTS (
+                    SELECT * FROM Game WHERE (Game.Player1ID = User.ID OR Game.Player2ID = User.ID)
+                        AND Game.StartedAt >= DATE_SUB(CURRENT_TIMESTAMP, INTERVAL ? DAY)
+                )
+        ";
+
+        let guests: Vec<(i32,)> = sqlx::query_as(QUERY)
+            .bind(days)
+            .bind(days)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(guests.into_iter().map(|(id,)| id).collect())
+    }
+
+    /// Turns a guest into a full account, keeping its games and stats.
+    pub async fn claim_guest(
+        &self,
+        user_id: i32,
+        username: &str,
+        password: &str,
+        email: Option<&str>,
+    ) -> anyhow::Result<()> {
+        let normalized = username::normalize(username);
+        let mut transaction = self.pool.begin().await?;
+
+        if self
+            .normalized_username_exists(&mut transaction, &normalized, Some(user_id))
+            .await?
+        {
+            return Err(UsernameError::Taken.into());
+        }
+
+        if let Some(email) = email {
+            let result: (bool,) =
+                sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Email = ?)")
+                    .bind(email)
+                    .fetch_one(&mut *transaction)
+                    .await?;
+
+            if result.0 {
+                return Err(anyhow::Error::msg("Email is already in use"));
+            }
+        }
+
+        const QUERY: &str = "
+            UPDATE User SET Username = ?, NormalizedUsername = ?, Password = ?, Email = ?,
+                IsGuest = FALSE
+            WHERE ID = ? AND IsGuest
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(username)
+            .bind(&normalized)
+            .bind(password)
+            .bind(email)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await
+            .map_err(username_taken)?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg("Account is not a guest"));
+        }
+
+        transaction.commit().await?;
+
+        // the player joins the leaderboard
+        self.leaderboard_cache.clear();
+
+        Ok(())
+    }
+
+    pub async fn get_user_by_name(&self, username: &str) -> anyhow::Result<User> {
+        let mut transaction = self.pool.begin().await?;
+        let result: (bool,) =
+            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Username = ?)")
+                .bind(username)
+                .fetch_one(&mut *transaction)
+                .await
+                .unwrap();
+
+        if !result.0 {
+            return Err(anyhow::Error::msg("Invalid username or password"));
+        }
+
+        const QUERY: &str = "
+            SELECT * FROM User WHERE Username = ?
+        ";
+
+        let user = sqlx::query_as::<_, User>(QUERY)
+            .bind(username)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+        Ok(user)
+    }
+
+    /// Fails with the ban reason while the user is banned, only for the
+    /// user signing in, who may see it.
+    async fn check_ban(&self, user_id: i32) -> anyhow::Result<()> {
+        match self.get_active_ban(user_id).await? {
+            Some(ban) => Err(anyhow::Error::msg(match ban.expires_at {
+                Some(expires_at) => format!(
+                    "Account suspended until {}: {}",
+                    expires_at.format("%Y-%m-%d %H:%M UTC"),
+                    ban.reason
+                ),
+                None => format!("Account banned: {}", ban.reason),
+            })),
+            None => Ok(()),
+        }
+    }
+
+    pub async fn get_user_by_name_password(
+        &self,
+        username: &str,
+        password: &str,
+    ) -> anyhow::Result<User> {
+        let mut transaction = self.pool.begin().await?;
+        let result: (bool,) =
+            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Username = ?)")
+                .bind(username)
+                .fetch_one(&mut *transaction)
+                .await
+                .unwrap();
+
+        if !result.0 {
+            return Err(anyhow::Error::msg("Invalid username or password"));
+        }
+
+        const QUERY: &str = "
+            SELECT * FROM User WHERE Username = ? AND Password = ?
+        ";
+
+        let user = sqlx::query_as::<_, User>(QUERY)
+            .bind(username)
+            .bind(password)
+            .fetch_one(&mut *transaction)
+            .await?;
+
+        self.check_ban(user.id).await?;
+        Ok(user)
+    }
+
+    pub async fn insert_session(&self, token: &str, user_id: i32) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            INSERT INTO Session(Token, UserID, ExpiresAt)
+            VALUES(?, ?, DATE_ADD(CURRENT_TIMESTAMP, INTERVAL ? DAY))
+        ";
+
+        sqlx::query(QUERY)
+            .bind(token)
+            .bind(user_id)
+            .bind(crate::authentication::SESSION_DURATION_DAYS)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_user_by_session(&self, token: &str) -> anyhow::Result<User> {
+        let query = format!(
+            "
+            SELECT User.* FROM Session
+            INNER JOIN User ON User.ID = Session.UserID
+            WHERE Session.Token = ? AND Session.ExpiresAt > CURRENT_TIMESTAMP
+                AND NOT EXISTS(SELECT 1 FROM Ban WHERE Ban.UserID = User.ID AND {})
+            ",
+            Self::ACTIVE_BAN
+        );
+
+        sqlx::query_as::<_, User>(&query)
+            .bind(token)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("Invalid or expired session"))
+    }
+
+    const LEADERBOARD_FROM: &'static str = "
+        FROM User
+        LEFT JOIN PlayerStats ON PlayerStats.UserID = User.ID
+        WHERE User.DeletedAt IS NULL AND NOT User.IsGuest
+            AND (? IS NULL OR User.CountryID = ?)
+            AND COALESCE(PlayerStats.GamesPlayed, 0) >= ?
+    ";
+
+    /// Returns one page of the leaderboard and the number of players on it.
+    pub async fn get_leaderboard(
+        &self,
+        filter: &LeaderboardFilter,
+    ) -> anyhow::Result<(Vec<LeaderboardEntry>, i64)> {
+        let query = format!(
+            "
+            SELECT User.Username, User.EloPoints, User.CountryID, User.ProfilePictureURL,
+                CAST(COALESCE(PlayerStats.GamesPlayed, 0) AS SIGNED) AS GamesPlayed,
+                CAST(RANK() OVER (ORDER BY User.EloPoints DESC) AS SIGNED) AS Placement
+            {}
+            ORDER BY User.EloPoints DESC, User.ID ASC
+            LIMIT ? OFFSET ?
+            ",
+            Self::LEADERBOARD_FROM
+        );
+
+        let entries = sqlx::query_as::<_, LeaderboardEntry>(&query)
+            .bind(&filter.country)
+            .bind(&filter.country)
+            .bind(filter.min_games)
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let (total,): (i64,) =
+            sqlx::query_as(&format!("SELECT COUNT(*) {}", Self::LEADERBOARD_FROM))
+                .bind(&filter.country)
+                .bind(&filter.country)
+                .bind(filter.min_games)
+                .fetch_one(&self.pool)
+                .await?;
+
+        Ok((entries, total))
+    }
+
+    /// Ranks follow the leaderboard: players with equal rating share a rank,
+    /// and ties are ordered by account age.
+    pub async fn get_rank(&self, user: &User) -> anyhow::Result<PlayerRank> {
+        const QUERY: &str = "
+            SELECT
+                CAST(1 + SUM(EloPoints > ?) AS SIGNED),
+                CAST(1 + SUM(EloPoints > ? AND CountryID = ?) AS SIGNED),
+                CAST(SUM(EloPoints > ? OR (EloPoints = ? AND ID < ?)) AS SIGNED)
+            FROM User
+            WHERE DeletedAt IS NULL AND NOT IsGuest
+        ";
+
+        let (rank, country_rank, position): (i64, i64, i64) = sqlx::query_as(QUERY)
+            .bind(user.elo_points)
+            .bind(user.elo_points)
+            .bind(&user.country_id)
+            .bind(user.elo_points)
+            .bind(user.elo_points)
+            .bind(user.id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(PlayerRank {
+            rank,
+            country_rank,
+            position,
+        })
+    }
+
+    pub async fn add_elo(
+        &self,
+        username: &str,
+        elo: i32,
+        game_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        self.change_elo(username, elo, game_id).await
+    }
+
+    pub async fn remove_elo(
+        &self,
+        username: &str,
+        elo: i32,
+        game_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        self.change_elo(username, -elo, game_id).await
+    }
+
+    /// Applies a rating change and records it as a `RatingChange` event.
+    async fn change_elo(
+        &self,
+        username: &str,
+        delta: i32,
+        game_id: Option<u64>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let (user_id, elo_before): (i32, i32) =
+            sqlx::query_as("SELECT ID, EloPoints FROM User WHERE Username = ? FOR UPDATE")
+                .bind(username)
+                .fetch_one(&mut *transaction)
+                .await?;
+
+        sqlx::query("UPDATE User SET EloPoints = EloPoints + ? WHERE ID = ?")
+            .bind(delta)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        const QUERY: &str = "
+            INSERT INTO RatingChange(UserID, GameID, EloBefore, EloAfter, Delta)
+            VALUES(?, ?, ?, ?, ?)
+        ";
+
+        sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(game_id)
+            .bind(elo_before)
+            .bind(elo_before + delta)
+            .bind(delta)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        self.leaderboard_cache
+            .invalidate_rating_change(elo_before, elo_before + delta);
+
+        Ok(())
+    }
+
+    /// Returns one page of the players ranked by rating gained or games won
+    /// over the last `days` days, and the number of players on it.
+    pub async fn get_windowed_leaderboard(
+        &self,
+        days: u32,
+        by_wins: bool,
+        filter: &LeaderboardFilter,
+    ) -> anyhow::Result<(Vec<WindowedLeaderboardEntry>, i64)> {
+        const FROM: &str = "
+            FROM User
+            LEFT JOIN (
+                SELECT UserID, SUM(Delta) AS RatingGained FROM RatingChange
+                WHERE CreatedAt >= DATE_SUB(CURRENT_TIMESTAMP, INTERVAL ? DAY)
+                    AND (Reason IS NULL OR Reason <> 'season reset')
+                GROUP BY UserID
+            ) AS Changes ON Changes.UserID = User.ID
+            LEFT JOIN (
+                SELECT WinnerID, COUNT(*) AS GamesWon FROM Game
+                WHERE EndedAt >= DATE_SUB(CURRENT_TIMESTAMP, INTERVAL ? DAY)
+                GROUP BY WinnerID
+            ) AS Wins ON Wins.WinnerID = User.ID
+            WHERE User.DeletedAt IS NULL AND NOT User.IsGuest
+                AND (Changes.UserID IS NOT NULL OR Wins.WinnerID IS NOT NULL)
+                AND (? IS NULL OR User.CountryID = ?)
+        ";
+
+        let order = if by_wins {
+            "COALESCE(Wins.GamesWon, 0) DESC"
+        } else {
+            "COALESCE(Changes.RatingGained, 0) DESC"
+        };
+
+        let query = format!(
+            "
+            SELECT User.Username, User.EloPoints, User.CountryID, User.ProfilePictureURL,
+                CAST(COALESCE(Changes.RatingGained, 0) AS SIGNED) AS RatingGained,
+                CAST(COALESCE(Wins.GamesWon, 0) AS SIGNED) AS GamesWon,
+                CAST(RANK() OVER (ORDER BY {order}) AS SIGNED) AS Placement
+            {FROM}
+            ORDER BY {order}, User.ID ASC
+            LIMIT ? OFFSET ?
+            "
+        );
+
+        let entries = sqlx::query_as::<_, WindowedLeaderboardEntry>(&query)
+            .bind(days)
+            .bind(days)
+            .bind(&filter.country)
+            .bind(&filter.country)
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let (total,): (i64,) = sqlx::query_as(&format!("SELECT COUNT(*) {FROM}"))
+            .bind(days)
+            .bind(days)
+            .bind(&filter.country)
+            .bind(&filter.country)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok((entries, total))
+    }
+
+    pub async fn insert_game(
+        &self,
+        p1_id: i32,
+        p2_id: i32,
+        rules: &RuleSet,
+    ) -> anyhow::Result<u64> {
+        let result = sqlx::query("INSERT INTO Game(Player1ID, Player2ID, Rules) VALUES(?, ?, ?)")
+            .bind(p1_id)
+            .bind(p2_id)
+            .bind(serde_json::to_string(rules)?)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.last_insert_id())
+    }
+
+    pub async fn end_game(&self, game_id: u64, winner: &str) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            UPDATE Game SET EndedAt = CURRENT_TIMESTAMP,
+                WinnerID = (SELECT ID FROM User WHERE Username = ?)
+            WHERE ID = ?
+        ";
+
+        sqlx::query(QUERY)
+            .bind(winner)
+            .bind(game_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn add_penalty(&self, game_id: u64, username: &str, sips: u32) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        const QUERY: &str = "
+            INSERT INTO Penalty(GameID, UserID, Sips)
+            SELECT ?, ID, ? FROM User WHERE Username = ?
+        ";
+
+        sqlx::query(QUERY)
+            .bind(game_id)
+            .bind(sips)
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("UPDATE User SET TotalSips = TotalSips + ? WHERE Username = ?")
+            .bind(sips)
+            .bind(username)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn set_tournament_match_game(
+        &self,
+        match_id: i32,
+        game_id: u64,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE TournamentMatch SET GameID = ? WHERE ID = ?")
+            .bind(game_id)
+            .bind(match_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    #[allow(clippy::too_many_arguments)]
+    pub async fn insert_tournament(
+        &self,
+        name: &str,
+        format: TournamentFormat,
+        rules: &RuleSet,
+        rounds: u32,
+        max_players: u32,
+        registration_minutes: u32,
+        created_by: i32,
+    ) -> anyhow::Result<i32> {
+        const QUERY: &str = "
+            INSERT INTO Tournament(Name, Format, Rules, Rounds, MaxPlayers, RegistrationEndsAt, CreatedBy)
+            VALUES(?, ?, ?, ?, ?, DATE_ADD(CURRENT_TIMESTAMP, INTERVAL ? MINUTE), ?)
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(name)
+            .bind(format.as_str())
+            .bind(serde_json::to_string(rules)?)
+            .bind(rounds)
+            .bind(max_players)
+            .bind(registration_minutes)
+            .bind(created_by)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(result.last_insert_id() as i32)
+    }
+
+    const TOURNAMENT_QUERY: &'static str = "
+        SELECT Tournament.*, Winner.Username AS Winner FROM Tournament
+        LEFT JOIN User AS Winner ON Winner.ID = Tournament.WinnerID
+    ";
+
+    pub async fn get_tournaments(&self) -> anyhow::Result<Vec<Tournament>> {
+        let query = format!(
+            "{} ORDER BY Tournament.CreatedAt DESC LIMIT 100",
+            Self::TOURNAMENT_QUERY
+        );
+
+        Ok(sqlx::query_as::<_, Tournament>(&query)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_tournament(&self, id: i32) -> anyhow::Result<Tournament> {
+        let query = format!("{} WHERE Tournament.ID = ?", Self::TOURNAMENT_QUERY);
+
+        sqlx::query_as::<_, Tournament>(&query)
+            .bind(id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("Tournament does not exist"))
+    }
+
+    pub async fn get_tournaments_by_status(
+        &self,
+        status: TournamentStatus,
+    ) -> anyhow::Result<Vec<Tournament>> {
+        let query = format!("{} WHERE Tournament.Status = ?", Self::TOURNAMENT_QUERY);
+
+        Ok(sqlx::query_as::<_, Tournament>(&query)
+            .bind(status.as_str())
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_tournaments_to_start(&self) -> anyhow::Result<Vec<Tournament>> {
+        let query = format!(
+            "{} WHERE Tournament.Status = ? AND Tournament.RegistrationEndsAt <= CURRENT_TIMESTAMP",
+            Self::TOURNAMENT_QUERY
+        );
+
+        Ok(sqlx::query_as::<_, Tournament>(&query)
+            .bind(TournamentStatus::Registration.as_str())
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn insert_tournament_player(
+        &self,
+        tournament_id: i32,
+        user_id: i32,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        const QUERY: &str = "
+            SELECT Status, MaxPlayers, RegistrationEndsAt > CURRENT_TIMESTAMP,
+                (SELECT COUNT(*) FROM TournamentPlayer WHERE TournamentID = Tournament.ID),
+                EXISTS(SELECT 1 FROM TournamentPlayer WHERE TournamentID = Tournament.ID AND UserID = ?)
+            FROM Tournament WHERE ID = ? FOR UPDATE
+        ";
+
+        let (status, max_players, open, players, registered): (String, i32, bool, i64, bool) =
+            sqlx::query_as(QUERY)
+                .bind(user_id)
+                .bind(tournament_id)
+                .fetch_optional(&mut *transaction)
+                .await?
+                .ok_or_else(|| anyhow::Error::msg("Tournament does not exist"))?;
+
+        if status != TournamentStatus::Registration.as_str() || !open {
+            return Err(anyhow::Error::msg("Registration is closed"));
+        }
+
+        if registered {
+            return Err(anyhow::Error::msg("Already registered"));
+        }
+
+        if players >= max_players as i64 {
+            return Err(anyhow::Error::msg("Tournament is full"));
+        }
+
+        sqlx::query("INSERT INTO TournamentPlayer(TournamentID, UserID) VALUES(?, ?)")
+            .bind(tournament_id)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_tournament_players(
+        &self,
+        tournament_id: i32,
+    ) -> anyhow::Result<Vec<TournamentPlayer>> {
+        const QUERY: &str = "
+            SELECT TournamentPlayer.*, User.Username, User.EloPoints FROM TournamentPlayer
+            INNER JOIN User ON User.ID = TournamentPlayer.UserID
+            WHERE TournamentPlayer.TournamentID = ?
+            ORDER BY TournamentPlayer.Points DESC, TournamentPlayer.Seed ASC, User.EloPoints DESC
+        ";
+
+        Ok(sqlx::query_as::<_, TournamentPlayer>(QUERY)
+            .bind(tournament_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    /// Stores the seeding, in order, and moves the tournament into its first round.
+    pub async fn start_tournament(
+        &self,
+        tournament_id: i32,
+        rounds: u32,
+        seeding: &[i32],
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        for (i, user_id) in seeding.iter().enumerate() {
+            sqlx::query(
+                "UPDATE TournamentPlayer SET Seed = ? WHERE TournamentID = ? AND UserID = ?",
+            )
+            .bind(i as i32 + 1)
+            .bind(tournament_id)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+        }
+
+        sqlx::query("UPDATE Tournament SET Status = ?, Rounds = ? WHERE ID = ?")
+            .bind(TournamentStatus::Running.as_str())
+            .bind(rounds)
+            .bind(tournament_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Inserts the pairings of a round. Players paired with nobody get a bye,
+    /// which counts as a win.
+    pub async fn insert_tournament_round(
+        &self,
+        tournament_id: i32,
+        round: u32,
+        pairings: &[(Option<i32>, Option<i32>)],
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        for (slot, (player1, player2)) in pairings.iter().enumerate() {
+            let bye = match (player1, player2) {
+                (Some(player), None) | (None, Some(player)) => Some(*player),
+                _ => None,
+            };
+
+            const QUERY: &str = "
+                INSERT INTO TournamentMatch(TournamentID, Round, Slot, Player1ID, Player2ID, WinnerID)
+                VALUES(?, ?, ?, ?, ?, ?)
+            ";
+
+            sqlx::query(QUERY)
+                .bind(tournament_id)
+                .bind(round)
+                .bind(slot as i32)
+                .bind(player1)
+                .bind(player2)
+                .bind(bye)
+                .execute(&mut *transaction)
+                .await?;
+
+            if let Some(bye) = bye {
+                sqlx::query(
+                    "UPDATE TournamentPlayer SET Points = Points + 1 WHERE TournamentID = ? AND UserID = ?",
+                )
+                .bind(tournament_id)
+                .bind(bye)
+                .execute(&mut *transaction)
+                .await?;
+            }
+        }
+
+        sqlx::query("UPDATE Tournament SET CurrentRound = ? WHERE ID = ?")
+            .bind(round)
+            .bind(tournament_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    const TOURNAMENT_MATCH_QUERY: &'static str = "
+        SELECT TournamentMatch.*, Player1.Username AS Player1, Player2.Username AS Player2,
+            Winner.Username AS Winner
+        FROM TournamentMatch
+        LEFT JOIN User AS Player1 ON Player1.ID = TournamentMatch.Player1ID
+        LEFT JOIN User AS Player2 ON Player2.ID = TournamentMatch.Player2ID
+        LEFT JOIN User AS Winner ON Winner.ID = TournamentMatch.WinnerID
+    ";
+
+    pub async fn get_tournament_matches(
+        &self,
+        tournament_id: i32,
+    ) -> anyhow::Result<Vec<TournamentMatch>> {
+        let query = format!(
+            "{} WHERE TournamentMatch.TournamentID = ? ORDER BY TournamentMatch.Round, TournamentMatch.Slot",
+            Self::TOURNAMENT_MATCH_QUERY
+        );
+
+        Ok(sqlx::query_as::<_, TournamentMatch>(&query)
+            .bind(tournament_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_tournament_match(&self, match_id: i32) -> anyhow::Result<TournamentMatch> {
+        let query = format!(
+            "{} WHERE TournamentMatch.ID = ?",
+            Self::TOURNAMENT_MATCH_QUERY
+        );
+
+        sqlx::query_as::<_, TournamentMatch>(&query)
+            .bind(match_id)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("Tournament match does not exist"))
+    }
+
+    /// Stores the winner of a match, awarding a point and eliminating the
+    /// loser when `eliminate` is set.
+    pub async fn set_tournament_match_winner(
+        &self,
+        tournament_match: &TournamentMatch,
+        winner_id: i32,
+        eliminate: bool,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query("UPDATE TournamentMatch SET WinnerID = ? WHERE ID = ?")
+            .bind(winner_id)
+            .bind(tournament_match.id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query(
+            "UPDATE TournamentPlayer SET Points = Points + 1 WHERE TournamentID = ? AND UserID = ?",
+        )
+        .bind(tournament_match.tournament_id)
+        .bind(winner_id)
+        .execute(&mut *transaction)
+        .await?;
+
+        if eliminate {
+            const QUERY: &str = "
+                UPDATE TournamentPlayer SET Eliminated = TRUE
+                WHERE TournamentID = ? AND UserID IN (?, ?) AND UserID != ?
+            ";
+
+            sqlx::query(QUERY)
+                .bind(tournament_match.tournament_id)
+                .bind(tournament_match.player1_id)
+                .bind(tournament_match.player2_id)
+                .bind(winner_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn finish_tournament(
+        &self,
+        tournament_id: i32,
+        status: TournamentStatus,
+        winner_id: Option<i32>,
+    ) -> anyhow::Result<()> {
+        sqlx::query("UPDATE Tournament SET Status = ?, WinnerID = ? WHERE ID = ?")
+            .bind(status.as_str())
+            .bind(winner_id)
+            .bind(tournament_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_seasons(&self) -> anyhow::Result<Vec<Season>> {
+        Ok(
+            sqlx::query_as::<_, Season>("SELECT * FROM Season ORDER BY ID DESC")
+                .fetch_all(&self.pool)
+                .await?,
+        )
+    }
+
+    const SEASON_STANDING_QUERY: &'static str = "
+        SELECT SeasonStanding.*, Season.Name AS SeasonName, User.Username, User.CountryID
+        FROM SeasonStanding
+        INNER JOIN Season ON Season.ID = SeasonStanding.SeasonID
+        INNER JOIN User ON User.ID = SeasonStanding.UserID
+    ";
+
+    /// Returns one page of a season's final standings and the number of
+    /// players on it. Placements stay the global ones when filtering by country.
+    pub async fn get_season_leaderboard(
+        &self,
+        season_id: i32,
+        filter: &LeaderboardFilter,
+    ) -> anyhow::Result<(Vec<SeasonStanding>, i64)> {
+        const FILTER: &str =
+            "WHERE SeasonStanding.SeasonID = ? AND (? IS NULL OR User.CountryID = ?)";
+
+        let query = format!(
+            "{} {} ORDER BY SeasonStanding.Placement, User.ID LIMIT ? OFFSET ?",
+            Self::SEASON_STANDING_QUERY,
+            FILTER
+        );
+
+        let standings = sqlx::query_as::<_, SeasonStanding>(&query)
+            .bind(season_id)
+            .bind(&filter.country)
+            .bind(&filter.country)
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let query = format!(
+            "SELECT COUNT(*) FROM SeasonStanding INNER JOIN User ON User.ID = SeasonStanding.UserID {}",
+            FILTER
+        );
+
+        let (total,): (i64,) = sqlx::query_as(&query)
+            .bind(season_id)
+            .bind(&filter.country)
+            .bind(&filter.country)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok((standings, total))
+    }
+
+    pub async fn get_season_rewards(&self, username: &str) -> anyhow::Result<Vec<SeasonStanding>> {
+        let query = format!(
+            "{} WHERE User.Username = ? AND SeasonStanding.Reward IS NOT NULL ORDER BY SeasonStanding.SeasonID DESC",
+            Self::SEASON_STANDING_QUERY
+        );
+
+        Ok(sqlx::query_as::<_, SeasonStanding>(&query)
+            .bind(username)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    /// Archives the final standings of the running season, hands out the
+    /// rewards, soft-resets every rating and opens the next season.
+    pub async fn rollover_season(
+        &self,
+        name: &str,
+        base_elo: i32,
+        reset_factor: f64,
+    ) -> anyhow::Result<i32> {
+        let mut transaction = self.pool.begin().await?;
+
+        let season: Option<(i32,)> =
+            sqlx::query_as("SELECT ID FROM Season WHERE EndedAt IS NULL FOR UPDATE")
+                .fetch_optional(&mut *transaction)
+                .await?;
+
+        if let Some((season_id,)) = season {
+            const STANDINGS_QUERY: &str = "
+                INSERT INTO SeasonStanding(SeasonID, UserID, EloPoints, Placement)
+                SELECT ?, ID, EloPoints, RANK() OVER (ORDER BY EloPoints DESC) FROM User
+                WHERE DeletedAt IS NULL AND NOT IsGuest
+            ";
+
+            sqlx::query(STANDINGS_QUERY)
+                .bind(season_id)
+                .execute(&mut *transaction)
+                .await?;
+
+            const REWARDS_QUERY: &str = "
+                UPDATE SeasonStanding SET Reward = CASE
+                    WHEN Placement = 1 THEN 'champion'
+                    WHEN Placement <= 10 THEN 'top10'
+                    WHEN Placement <= 100 THEN 'top100'
+                END
+                WHERE SeasonID = ?
+            ";
+
+            sqlx::query(REWARDS_QUERY)
+                .bind(season_id)
+                .execute(&mut *transaction)
+                .await?;
+
+            sqlx::query("UPDATE Season SET EndedAt = CURRENT_TIMESTAMP WHERE ID = ?")
+                .bind(season_id)
+                .execute(&mut *transaction)
+                .await?;
+        }
+
+        // the history shows the reset instead of an unexplained jump
+        const RESET_HISTORY_QUERY: &str = "
+            INSERT INTO RatingChange(UserID, EloBefore, EloAfter, Delta, Reason)
+            SELECT ID, EloPoints, ResetElo, ResetElo - EloPoints, 'season reset' FROM (
+                SELECT ID, EloPoints, ? + ROUND((EloPoints - ?) * ?) AS ResetElo FROM User
+                WHERE DeletedAt IS NULL AND NOT IsGuest
+            ) AS Reset
+            WHERE ResetElo <> EloPoints
+        ";
+
+        sqlx::query(RESET_HISTORY_QUERY)
+            .bind(base_elo)
+            .bind(base_elo)
+            .bind(reset_factor)
+            .execute(&mut *transaction)
+            .await?;
+
+        const RESET_QUERY: &str = "
+            UPDATE User SET EloPoints = ? + ROUND((EloPoints - ?) * ?)
+            WHERE DeletedAt IS NULL AND NOT IsGuest
+        ";
+
+        sqlx::query(RESET_QUERY)
+            .bind(base_elo)
+            .bind(base_elo)
+            .bind(reset_factor)
+            .execute(&mut *transaction)
+            .await?;
+
+        let result = sqlx::query("INSERT INTO Season(Name) VALUES(?)")
+            .bind(name)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        self.leaderboard_cache.clear();
+
+        Ok(result.last_insert_id() as i32)
+    }
+
+    /// Counts a finished game into the stats of both players. Must run after
+    /// the rating change so the peak rating is up to date.
+    pub async fn record_game_result(
+        &self,
+        winner: &str,
+        loser: &str,
+        game_seconds: u64,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        // assignments run left to right, BestStreak sees the new CurrentStreak
+        const WINNER_QUERY: &str = "
+            INSERT INTO PlayerStats(UserID, GamesPlayed, Wins, CurrentStreak, BestStreak, PeakElo, TotalGameSeconds)
+            SELECT ID, 1, 1, 1, 1, EloPoints, ? FROM User WHERE Username = ?
+            ON DUPLICATE KEY UPDATE
+                GamesPlayed = GamesPlayed + 1,
+                Wins = Wins + 1,
+                CurrentStreak = IF(CurrentStreak > 0, CurrentStreak + 1, 1),
+                BestStreak = GREATEST(BestStreak, CurrentStreak),
+                PeakElo = GREATEST(PeakElo, VALUES(PeakElo)),
+                TotalGameSeconds = TotalGameSeconds + VALUES(TotalGameSeconds)
+        ";
+
+        sqlx::query(WINNER_QUERY)
+            .bind(game_seconds)
+            .bind(winner)
+            .execute(&mut *transaction)
+            .await?;
+
+        const LOSER_QUERY: &str = "
+            INSERT INTO PlayerStats(UserID, GamesPlayed, Losses, CurrentStreak, PeakElo, TotalGameSeconds)
+            SELECT ID, 1, 1, -1, EloPoints, ? FROM User WHERE Username = ?
+            ON DUPLICATE KEY UPDATE
+                GamesPlayed = GamesPlayed + 1,
+                Losses = Losses + 1,
+                CurrentStreak = IF(CurrentStreak < 0, CurrentStreak - 1, -1),
+                PeakElo = GREATEST(PeakElo, VALUES(PeakElo)),
+                TotalGameSeconds = TotalGameSeconds + VALUES(TotalGameSeconds)
+        ";
+
+        sqlx::query(LOSER_QUERY)
+            .bind(game_seconds)
+            .bind(loser)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn get_player_stats(&self, username: &str) -> anyhow::Result<PlayerStats> {
+        const QUERY: &str = "
+            SELECT User.Username,
+                COALESCE(PlayerStats.GamesPlayed, 0) AS GamesPlayed,
+                COALESCE(PlayerStats.Wins, 0) AS Wins,
+                COALESCE(PlayerStats.Losses, 0) AS Losses,
+                COALESCE(PlayerStats.CurrentStreak, 0) AS CurrentStreak,
+                COALESCE(PlayerStats.BestStreak, 0) AS BestStreak,
+                GREATEST(COALESCE(PlayerStats.PeakElo, 0), User.EloPoints) AS PeakElo,
+                COALESCE(PlayerStats.TotalGameSeconds, 0) AS TotalGameSeconds
+            FROM User
+            LEFT JOIN PlayerStats ON PlayerStats.UserID = User.ID
+            WHERE User.Username = ?
+        ";
+
+        let mut stats = sqlx::query_as::<_, PlayerStats>(QUERY)
+            .bind(username)
+            .fetch_optional(&self.pool)
+            .await?
+            .ok_or_else(|| anyhow::Error::msg("User does not exist"))?;
+
+        if stats.games_played > 0 {
+            stats.win_rate = stats.wins as f64 / stats.games_played as f64;
+            stats.average_game_seconds =
+                stats.total_game_seconds as f64 / stats.games_played as f64;
+        }
+
+        Ok(stats)
+    }
+
+    pub async fn get_head_to_head(
+        &self,
+        player1: &User,
+        player2: &User,
+    ) -> anyhow::Result<HeadToHead> {
+        const QUERY: &str = "
+            SELECT CAST(COUNT(*) AS SIGNED),
+                CAST(COALESCE(SUM(WinnerID = ?), 0) AS SIGNED),
+                CAST(COALESCE(SUM(WinnerID = ?), 0) AS SIGNED),
+                MAX(EndedAt)
+            FROM Game
+            WHERE EndedAt IS NOT NULL
+                AND ((Player1ID = ? AND Player2ID = ?) OR (Player1ID = ? AND Player2ID = ?))
+        ";
+
+        let (games, player1_wins, player2_wins, last_played): (
+            i64,
+            i64,
+            i64,
+            Option<NaiveDateTime>,
+        ) = sqlx::query_as(QUERY)
+            .bind(player1.id)
+            .bind(player2.id)
+            .bind(player1.id)
+            .bind(player2.id)
+            .bind(player2.id)
+            .bind(player1.id)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok(HeadToHead {
+            player1: player1.username.clone(),
+            player2: player2.username.clone(),
+            games,
+            player1_wins,
+            player2_wins,
+            last_played,
+        })
+    }
+
+    /// Rating changes of a player within `[from, to)`, oldest first. Open
+    /// ended when a bound is `None`.
+    pub async fn get_rating_history(
+        &self,
+        user_id: i32,
+        from: Option<NaiveDateTime>,
+        to: Option<NaiveDateTime>,
+    ) -> anyhow::Result<Vec<RatingChange>> {
+        const QUERY: &str = "
+            SELECT GameID, EloBefore, EloAfter, Delta, Reason, CreatedAt FROM RatingChange
+            WHERE UserID = ? AND (? IS NULL OR CreatedAt >= ?) AND (? IS NULL OR CreatedAt < ?)
+            ORDER BY CreatedAt, ID
+        ";
+
+        Ok(sqlx::query_as::<_, RatingChange>(QUERY)
+            .bind(user_id)
+            .bind(from)
+            .bind(from)
+            .bind(to)
+            .bind(to)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    /// Updates the given profile fields, leaving `None` ones untouched.
+    pub async fn update_profile(
+        &self,
+        user_id: i32,
+        country_id: Option<&str>,
+        profile_picture_url: Option<&str>,
+    ) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            UPDATE User SET CountryID = COALESCE(?, CountryID),
+                ProfilePictureURL = COALESCE(?, ProfilePictureURL)
+            WHERE ID = ?
+        ";
+
+        sqlx::query(QUERY)
+            .bind(country_id)
+            .bind(profile_picture_url)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Sets the address password resets are sent to, `None` removes it.
+    pub async fn update_email(&self, user_id: i32, email: Option<&str>) -> anyhow::Result<()> {
+        let result: (bool,) =
+            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Email = ? AND ID != ?)")
+                .bind(email)
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?;
+
+        if result.0 {
+            return Err(anyhow::Error::msg("Email is already in use"));
+        }
+
+        sqlx::query("UPDATE User SET Email = ? WHERE ID = ?")
+            .bind(email)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_user_by_email(&self, email: &str) -> anyhow::Result<Option<User>> {
+        Ok(
+            sqlx::query_as::<_, User>("SELECT * FROM User WHERE Email = ? AND DeletedAt IS NULL")
+                .bind(email)
+                .fetch_optional(&self.pool)
+                .await?,
+        )
+    }
+
+    /// Stores a reset token by its hash, replacing any the user still had open.
+    pub async fn insert_password_reset(
+        &self,
+        token_hash: &str,
+        user_id: i32,
+        valid_minutes: i64,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM PasswordReset WHERE UserID = ? AND UsedAt IS NULL")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        const QUERY: &str = "
+            INSERT INTO PasswordReset(TokenHash, UserID, ExpiresAt)
+            VALUES(?, ?, DATE_ADD(CURRENT_TIMESTAMP, INTERVAL ? MINUTE))
+        ";
+
+        sqlx::query(QUERY)
+            .bind(token_hash)
+            .bind(user_id)
+            .bind(valid_minutes)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Uses up a reset token and sets the new password, signing out every
+    /// session of the user. Returns the user's ID.
+    pub async fn redeem_password_reset(
+        &self,
+        token_hash: &str,
+        password: &str,
+    ) -> anyhow::Result<i32> {
+        let mut transaction = self.pool.begin().await?;
+
+        let user_id: Option<(i32,)> = sqlx::query_as(
+            "
+            SELECT UserID FROM PasswordReset
+            WHERE TokenHash = ? AND UsedAt IS NULL AND ExpiresAt > CURRENT_TIMESTAMP
+            FOR UPDATE
+            ",
+        )
+        .bind(token_hash)
+        .fetch_optional(&mut *transaction)
+        .await?;
+
+        let Some((user_id,)) = user_id else {
+            return Err(anyhow::Error::msg("Invalid or expired reset token"));
+        };
+
+        sqlx::query("UPDATE PasswordReset SET UsedAt = CURRENT_TIMESTAMP WHERE TokenHash = ?")
+            .bind(token_hash)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("UPDATE User SET Password = ? WHERE ID = ?")
+            .bind(password)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM Session WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(user_id)
+    }
+
+    pub async fn update_password(&self, user_id: i32, password: &str) -> anyhow::Result<()> {
+        sqlx::query("UPDATE User SET Password = ? WHERE ID = ?")
+            .bind(password)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn delete_sessions(&self, user_id: i32) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM Session WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Strips an account of everything identifying it. The row stays so the
+    /// match history of its opponents keeps pointing at a player, which is
+    /// now shown as `deleted-<id>`.
+    pub async fn anonymize_user(&self, user_id: i32, password: &str) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query("DELETE FROM Session WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM PasswordReset WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM LoginChallenge WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM RecoveryCode WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM Friendship WHERE RequesterID = ? OR AddresseeID = ?")
+            .bind(user_id)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM ChatMessage WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        const QUERY: &str = "
+            UPDATE User SET Username = CONCAT('deleted-', ID),
+                NormalizedUsername = CONCAT('deleted', ID), Password = ?,
+                CountryID = DEFAULT(CountryID), ProfilePictureURL = '', Email = NULL,
+                TotpSecret = NULL, TotpEnabled = FALSE,
+                DeletedAt = CURRENT_TIMESTAMP
+            WHERE ID = ?
+        ";
+
+        sqlx::query(QUERY)
+            .bind(password)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        self.leaderboard_cache.clear();
+
+        Ok(())
+    }
+
+    pub async fn get_games(&self, user_id: i32) -> anyhow::Result<Vec<GameRecord>> {
+        const QUERY: &str = "
+            SELECT Game.*, Player1.Username AS Player1, Player2.Username AS Player2,
+                Winner.Username AS Winner
+            FROM Game
+            INNER JOIN User AS Player1 ON Player1.ID = Game.Player1ID
+            INNER JOIN User AS Player2 ON Player2.ID = Game.Player2ID
+            LEFT JOIN User AS Winner ON Winner.ID = Game.WinnerID
+            WHERE Game.Player1ID = ? OR Game.Player2ID = ?
+            ORDER BY Game.StartedAt
+        ";
+
+        Ok(sqlx::query_as::<_, GameRecord>(QUERY)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_penalties(&self, user_id: i32) -> anyhow::Result<Vec<PenaltyRecord>> {
+        Ok(sqlx::query_as::<_, PenaltyRecord>(
+            "SELECT GameID, Sips, CreatedAt FROM Penalty WHERE UserID = ? ORDER BY CreatedAt",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    pub async fn get_sessions(&self, user_id: i32) -> anyhow::Result<Vec<SessionRecord>> {
+        Ok(sqlx::query_as::<_, SessionRecord>(
+            "SELECT CreatedAt, ExpiresAt FROM Session WHERE UserID = ? ORDER BY CreatedAt",
+        )
+        .bind(user_id)
+        .fetch_all(&self.pool)
+        .await?)
+    }
+
+    pub async fn get_tournament_entries(
+        &self,
+        user_id: i32,
+    ) -> anyhow::Result<Vec<TournamentEntry>> {
+        const QUERY: &str = "
+            SELECT TournamentPlayer.*, Tournament.Name FROM TournamentPlayer
+            INNER JOIN Tournament ON Tournament.ID = TournamentPlayer.TournamentID
+            WHERE TournamentPlayer.UserID = ?
+            ORDER BY TournamentPlayer.RegisteredAt
+        ";
+
+        Ok(sqlx::query_as::<_, TournamentEntry>(QUERY)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    const ACTIVE_BAN: &'static str =
+        "Ban.LiftedAt IS NULL AND (Ban.ExpiresAt IS NULL OR Ban.ExpiresAt > CURRENT_TIMESTAMP)";
+
+    const BAN_QUERY: &'static str = "
+        SELECT Ban.*, Moderator.Username AS Moderator FROM Ban
+        INNER JOIN User AS Moderator ON Moderator.ID = Ban.ModeratorID
+    ";
+
+    pub async fn set_role(&self, user_id: i32, role: Role) -> anyhow::Result<()> {
+        sqlx::query("UPDATE User SET Role = ? WHERE ID = ?")
+            .bind(role.as_str())
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    pub async fn get_active_ban(&self, user_id: i32) -> anyhow::Result<Option<Ban>> {
+        let query = format!(
+            "{} WHERE Ban.UserID = ? AND {} ORDER BY Ban.ExpiresAt IS NULL DESC, Ban.ExpiresAt DESC LIMIT 1",
+            Self::BAN_QUERY,
+            Self::ACTIVE_BAN
+        );
+
+        Ok(sqlx::query_as::<_, Ban>(&query)
+            .bind(user_id)
+            .fetch_optional(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_bans(&self, user_id: i32) -> anyhow::Result<Vec<Ban>> {
+        let query = format!(
+            "{} WHERE Ban.UserID = ? ORDER BY Ban.CreatedAt DESC",
+            Self::BAN_QUERY
+        );
+
+        Ok(sqlx::query_as::<_, Ban>(&query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    /// Bans a user for `hours`, or for good when `None`, and signs them out.
+    pub async fn insert_ban(
+        &self,
+        user_id: i32,
+        moderator_id: i32,
+        reason: &str,
+        hours: Option<u32>,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        const QUERY: &str = "
+            INSERT INTO Ban(UserID, ModeratorID, Reason, ExpiresAt)
+            VALUES(?, ?, ?, IF(? IS NULL, NULL, DATE_ADD(CURRENT_TIMESTAMP, INTERVAL ? HOUR)))
+        ";
+
+        sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(moderator_id)
+            .bind(reason)
+            .bind(hours)
+            .bind(hours)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM Session WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    /// Lifts every active ban of the user.
+    pub async fn lift_bans(&self, user_id: i32) -> anyhow::Result<()> {
+        let query = format!(
+            "UPDATE Ban SET LiftedAt = CURRENT_TIMESTAMP WHERE UserID = ? AND {}",
+            Self::ACTIVE_BAN
+        );
+
+        let result = sqlx::query(&query)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg("Player is not banned"));
+        }
+
+        Ok(())
+    }
+
+    /// Applies a manual rating change, recorded both as a `RatingChange` and
+    /// as the moderator's `RatingAdjustment`.
+    pub async fn adjust_elo(
+        &self,
+        user_id: i32,
+        moderator_id: i32,
+        delta: i32,
+        reason: &str,
+    ) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        let (elo_before,): (i32,) =
+            sqlx::query_as("SELECT EloPoints FROM User WHERE ID = ? FOR UPDATE")
+                .bind(user_id)
+                .fetch_one(&mut *transaction)
+                .await?;
+
+        sqlx::query("UPDATE User SET EloPoints = EloPoints + ? WHERE ID = ?")
+            .bind(delta)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        const QUERY: &str = "
+            INSERT INTO RatingChange(UserID, EloBefore, EloAfter, Delta)
+            VALUES(?, ?, ?, ?)
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(elo_before)
+            .bind(elo_before + delta)
+            .bind(delta)
+            .execute(&mut *transaction)
+            .await?;
+
+        const ADJUSTMENT_QUERY: &str = "
+            INSERT INTO RatingAdjustment(UserID, ModeratorID, RatingChangeID, Reason)
+            VALUES(?, ?, ?, ?)
+        ";
+
+        sqlx::query(ADJUSTMENT_QUERY)
+            .bind(user_id)
+            .bind(moderator_id)
+            .bind(result.last_insert_id())
+            .bind(reason)
+            .execute(&mut *transaction)
+            .await?;
+
+        transaction.commit().await?;
+
+        self.leaderboard_cache
+            .invalidate_rating_change(elo_before, elo_before + delta);
+
+        Ok(())
+    }
+
+    pub async fn get_rating_adjustments(
+        &self,
+        user_id: i32,
+    ) -> anyhow::Result<Vec<RatingAdjustment>> {
+        const QUERY: &str = "
+            SELECT RatingAdjustment.Reason, RatingAdjustment.CreatedAt,
+                RatingChange.EloBefore, RatingChange.EloAfter, RatingChange.Delta,
+                Moderator.Username AS Moderator
+            FROM RatingAdjustment
+            INNER JOIN RatingChange ON RatingChange.ID = RatingAdjustment.RatingChangeID
+            INNER JOIN User AS Moderator ON Moderator.ID = RatingAdjustment.ModeratorID
+            WHERE RatingAdjustment.UserID = ?
+            ORDER BY RatingAdjustment.CreatedAt DESC
+        ";
+
+        Ok(sqlx::query_as::<_, RatingAdjustment>(QUERY)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
 
-impl Db {
-    pub async fn new() -> Self {
-        dotenv::dotenv().ok();
+    /// Renames a user on behalf of a moderator, keeping the old name on record.
+    pub async fn rename_user(
+        &self,
+        user_id: i32,
+        moderator_id: i32,
+        username: &str,
+    ) -> anyhow::Result<()> {
+        let normalized = username::normalize(username);
+        let mut transaction = self.pool.begin().await?;
 
-        let pool = MySqlPool::connect(&dotenv::var("DATABASE_URL").unwrap())
+        if self
+            .normalized_username_exists(&mut transaction, &normalized, Some(user_id))
+            .await?
+        {
+            return Err(UsernameError::Taken.into());
+        }
+
+        const QUERY: &str = "
+            INSERT INTO UsernameChange(UserID, ModeratorID, OldUsername, NewUsername)
+            SELECT ID, ?, Username, ? FROM User WHERE ID = ?
+        ";
+
+        sqlx::query(QUERY)
+            .bind(moderator_id)
+            .bind(username)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("UPDATE User SET Username = ?, NormalizedUsername = ? WHERE ID = ?")
+            .bind(username)
+            .bind(&normalized)
+            .bind(user_id)
+            .execute(&mut *transaction)
             .await
-            .unwrap();
+            .map_err(username_taken)?;
 
-        Self::run_migrations(&pool).await;
+        transaction.commit().await?;
+
+        self.leaderboard_cache.clear();
+
+        Ok(())
+    }
+
+    pub async fn get_username_changes(&self, user_id: i32) -> anyhow::Result<Vec<UsernameChange>> {
+        const QUERY: &str = "
+            SELECT UsernameChange.*, Moderator.Username AS Moderator FROM UsernameChange
+            INNER JOIN User AS Moderator ON Moderator.ID = UsernameChange.ModeratorID
+            WHERE UsernameChange.UserID = ?
+            ORDER BY UsernameChange.CreatedAt DESC
+        ";
 
-        Self { pool }
+        Ok(sqlx::query_as::<_, UsernameChange>(QUERY)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
     }
 
-    pub async fn run_migrations(pool: &MySqlPool) {
-        sqlx::migrate!("db/migrations").run(pool).await.unwrap()
+    /// Ends a game without a winner.
+    pub async fn abort_game(&self, game_id: u64) -> anyhow::Result<()> {
+        sqlx::query("UPDATE Game SET EndedAt = CURRENT_TIMESTAMP WHERE ID = ?")
+            .bind(game_id)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
     }
 
-    pub async fn insert_user(&self, username: &str, password: &str) -> anyhow::Result<()> {
-        let mut transaction = self.pool.begin().await?;
-        let result: (bool,) =
-            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Username = ?)")
-                .bind(username)
-                .fetch_one(&mut *transaction)
-                .await?;
+    pub async fn insert_audit_event(
+        &self,
+        action: AuditAction,
+        actor_id: Option<i32>,
+        target_id: Option<i32>,
+        details: Option<&str>,
+        ip: Option<&str>,
+        user_agent: Option<&str>,
+    ) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            INSERT INTO AuditEvent(Action, ActorID, TargetID, Details, IP, UserAgent)
+            VALUES(?, ?, ?, ?, ?, ?)
+        ";
 
-        if result.0 {
-            return Err(anyhow::Error::msg("Username already exists!"));
+        sqlx::query(QUERY)
+            .bind(action.as_str())
+            .bind(actor_id)
+            .bind(target_id)
+            .bind(details)
+            .bind(ip)
+            .bind(user_agent)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    const AUDIT_FROM: &'static str = "
+        FROM AuditEvent
+        LEFT JOIN User AS Actor ON Actor.ID = AuditEvent.ActorID
+        LEFT JOIN User AS Target ON Target.ID = AuditEvent.TargetID
+        WHERE (? IS NULL OR AuditEvent.Action = ?)
+            AND (? IS NULL OR AuditEvent.ActorID = ?)
+            AND (? IS NULL OR AuditEvent.TargetID = ?)
+            AND (? IS NULL OR AuditEvent.CreatedAt >= ?)
+            AND (? IS NULL OR AuditEvent.CreatedAt < ?)
+    ";
+
+    /// Returns one page of matching events, newest first, and their number.
+    pub async fn get_audit_events(
+        &self,
+        filter: &AuditFilter,
+    ) -> anyhow::Result<(Vec<AuditEvent>, i64)> {
+        let action = filter.action.map(|x| x.as_str());
+
+        let query = format!(
+            "SELECT AuditEvent.*, Actor.Username AS Actor, Target.Username AS Target {}
+            ORDER BY AuditEvent.ID DESC LIMIT ? OFFSET ?",
+            Self::AUDIT_FROM
+        );
+
+        let events = sqlx::query_as::<_, AuditEvent>(&query)
+            .bind(action)
+            .bind(action)
+            .bind(filter.actor_id)
+            .bind(filter.actor_id)
+            .bind(filter.target_id)
+            .bind(filter.target_id)
+            .bind(filter.from)
+            .bind(filter.from)
+            .bind(filter.to)
+            .bind(filter.to)
+            .bind(filter.limit)
+            .bind(filter.offset)
+            .fetch_all(&self.pool)
+            .await?;
+
+        let query = format!("SELECT COUNT(*) {}", Self::AUDIT_FROM);
+        let (total,): (i64,) = sqlx::query_as(&query)
+            .bind(action)
+            .bind(action)
+            .bind(filter.actor_id)
+            .bind(filter.actor_id)
+            .bind(filter.target_id)
+            .bind(filter.target_id)
+            .bind(filter.from)
+            .bind(filter.from)
+            .bind(filter.to)
+            .bind(filter.to)
+            .fetch_one(&self.pool)
+            .await?;
+
+        Ok((events, total))
+    }
+
+    /// Events the user took part in. Addresses and user agents are only
+    /// included where the user was the actor, not those of moderators.
+    pub async fn get_user_audit_events(&self, user_id: i32) -> anyhow::Result<Vec<AuditEvent>> {
+        const QUERY: &str = "
+            SELECT AuditEvent.ID, AuditEvent.Action, AuditEvent.Details, AuditEvent.CreatedAt,
+                Actor.Username AS Actor, Target.Username AS Target,
+                IF(AuditEvent.ActorID = ?, AuditEvent.IP, NULL) AS IP,
+                IF(AuditEvent.ActorID = ?, AuditEvent.UserAgent, NULL) AS UserAgent
+            FROM AuditEvent
+            LEFT JOIN User AS Actor ON Actor.ID = AuditEvent.ActorID
+            LEFT JOIN User AS Target ON Target.ID = AuditEvent.TargetID
+            WHERE AuditEvent.ActorID = ? OR AuditEvent.TargetID = ?
+            ORDER BY AuditEvent.ID
+        ";
+
+        Ok(sqlx::query_as::<_, AuditEvent>(QUERY)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn delete_audit_events_before(&self, days: u32) -> anyhow::Result<u64> {
+        let result = sqlx::query(
+            "DELETE FROM AuditEvent WHERE CreatedAt < DATE_SUB(CURRENT_TIMESTAMP, INTERVAL ? DAY)",
+        )
+        .bind(days)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected())
+    }
+
+    /// Returns the TOTP secret, enrolled or not, and the last time step used.
+    pub async fn get_totp(&self, user_id: i32) -> anyhow::Result<(Option<String>, Option<i64>)> {
+        Ok(
+            sqlx::query_as("SELECT TotpSecret, TotpLastStep FROM User WHERE ID = ?")
+                .bind(user_id)
+                .fetch_one(&self.pool)
+                .await?,
+        )
+    }
+
+    /// Stores a secret awaiting its first code, replacing an earlier one.
+    pub async fn set_pending_totp(&self, user_id: i32, secret: &str) -> anyhow::Result<()> {
+        let result = sqlx::query(
+            "UPDATE User SET TotpSecret = ?, TotpLastStep = NULL WHERE ID = ? AND NOT TotpEnabled",
+        )
+        .bind(secret)
+        .bind(user_id)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg(
+                "Two-factor authentication is already enabled",
+            ));
         }
 
+        Ok(())
+    }
+
+    pub async fn enable_totp(&self, user_id: i32, code_hashes: &[String]) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
+        sqlx::query("UPDATE User SET TotpEnabled = TRUE WHERE ID = ?")
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        Self::insert_recovery_codes(&mut transaction, user_id, code_hashes).await?;
+
+        transaction.commit().await?;
+
+        Ok(())
+    }
+
+    pub async fn disable_totp(&self, user_id: i32) -> anyhow::Result<()> {
+        let mut transaction = self.pool.begin().await?;
+
         const QUERY: &str = "
-            INSERT INTO User(Username, Password) VALUES(?, ?)
+            UPDATE User SET TotpSecret = NULL, TotpEnabled = FALSE, TotpLastStep = NULL
+            WHERE ID = ?
         ";
 
         sqlx::query(QUERY)
-            .bind(username)
-            .bind(password)
+            .bind(user_id)
+            .execute(&mut *transaction)
+            .await?;
+
+        sqlx::query("DELETE FROM RecoveryCode WHERE UserID = ?")
+            .bind(user_id)
             .execute(&mut *transaction)
             .await?;
 
@@ -70,81 +2429,396 @@ impl Db {
         Ok(())
     }
 
-    pub async fn get_user_by_name(&self, username: &str) -> anyhow::Result<User> {
+    pub async fn replace_recovery_codes(
+        &self,
+        user_id: i32,
+        code_hashes: &[String],
+    ) -> anyhow::Result<()> {
         let mut transaction = self.pool.begin().await?;
-        let result: (bool,) =
-            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Username = ?)")
-                .bind(username)
-                .fetch_one(&mut *transaction)
-                .await
-                .unwrap();
+        Self::insert_recovery_codes(&mut transaction, user_id, code_hashes).await?;
+        transaction.commit().await?;
 
-        if !result.0 {
-            return Err(anyhow::Error::msg("Invalid username or password"));
+        Ok(())
+    }
+
+    async fn insert_recovery_codes(
+        transaction: &mut sqlx::Transaction<'_, sqlx::MySql>,
+        user_id: i32,
+        code_hashes: &[String],
+    ) -> anyhow::Result<()> {
+        sqlx::query("DELETE FROM RecoveryCode WHERE UserID = ?")
+            .bind(user_id)
+            .execute(&mut **transaction)
+            .await?;
+
+        for code_hash in code_hashes {
+            sqlx::query("INSERT INTO RecoveryCode(UserID, CodeHash) VALUES(?, ?)")
+                .bind(user_id)
+                .bind(code_hash)
+                .execute(&mut **transaction)
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    /// Marks a time step as used, failing for steps at or before the last
+    /// one so a code can't be replayed.
+    pub async fn use_totp_step(&self, user_id: i32, step: i64) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE User SET TotpLastStep = ? WHERE ID = ? AND (TotpLastStep IS NULL OR TotpLastStep < ?)",
+        )
+        .bind(step)
+        .bind(user_id)
+        .bind(step)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn use_recovery_code(&self, user_id: i32, code_hash: &str) -> anyhow::Result<bool> {
+        let result = sqlx::query(
+            "UPDATE RecoveryCode SET UsedAt = CURRENT_TIMESTAMP WHERE UserID = ? AND CodeHash = ? AND UsedAt IS NULL",
+        )
+        .bind(user_id)
+        .bind(code_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(result.rows_affected() == 1)
+    }
+
+    pub async fn insert_login_challenge(
+        &self,
+        token_hash: &str,
+        user_id: i32,
+        valid_minutes: i64,
+    ) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            INSERT INTO LoginChallenge(TokenHash, UserID, ExpiresAt)
+            VALUES(?, ?, DATE_ADD(CURRENT_TIMESTAMP, INTERVAL ? MINUTE))
+        ";
+
+        sqlx::query(QUERY)
+            .bind(token_hash)
+            .bind(user_id)
+            .bind(valid_minutes)
+            .execute(&self.pool)
+            .await?;
+
+        Ok(())
+    }
+
+    /// Returns the user of an open challenge and counts the attempt against it.
+    pub async fn attempt_login_challenge(
+        &self,
+        token_hash: &str,
+        max_attempts: i32,
+    ) -> anyhow::Result<User> {
+        let result = sqlx::query(
+            "UPDATE LoginChallenge SET Attempts = Attempts + 1 WHERE TokenHash = ? AND Attempts < ? AND ExpiresAt > CURRENT_TIMESTAMP",
+        )
+        .bind(token_hash)
+        .bind(max_attempts)
+        .execute(&self.pool)
+        .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg("Invalid or expired login challenge"));
         }
 
         const QUERY: &str = "
-            SELECT * FROM User WHERE Username = ?
+            SELECT User.* FROM LoginChallenge
+            INNER JOIN User ON User.ID = LoginChallenge.UserID
+            WHERE LoginChallenge.TokenHash = ?
         ";
 
         let user = sqlx::query_as::<_, User>(QUERY)
-            .bind(username)
-            .fetch_one(&mut *transaction)
+            .bind(token_hash)
+            .fetch_one(&self.pool)
             .await?;
+
+        self.check_ban(user.id).await?;
         Ok(user)
     }
 
-    pub async fn get_user_by_name_password(
+    pub async fn delete_login_challenge(&self, token_hash: &str) -> anyhow::Result<()> {
+        sqlx::query(
+            "DELETE FROM LoginChallenge WHERE TokenHash = ? OR ExpiresAt < CURRENT_TIMESTAMP",
+        )
+        .bind(token_hash)
+        .execute(&self.pool)
+        .await?;
+
+        Ok(())
+    }
+
+    /// Sends a friend request, or accepts the one the other user already
+    /// sent. Returns whether the two are friends now.
+    pub async fn insert_friend_request(
         &self,
-        username: &str,
-        password: &str,
-    ) -> anyhow::Result<User> {
+        requester_id: i32,
+        addressee_id: i32,
+    ) -> anyhow::Result<bool> {
         let mut transaction = self.pool.begin().await?;
-        let result: (bool,) =
-            sqlx::query_as("SELECT EXISTS(SELECT 1 FROM User WHERE Username = ?)")
-                .bind(username)
+
+        const EXISTING: &str = "
+            SELECT RequesterID, Status FROM Friendship
+            WHERE (RequesterID = ? AND AddresseeID = ?) OR (RequesterID = ? AND AddresseeID = ?)
+            FOR UPDATE
+        ";
+
+        let existing: Option<(i32, String)> = sqlx::query_as(EXISTING)
+            .bind(requester_id)
+            .bind(addressee_id)
+            .bind(addressee_id)
+            .bind(requester_id)
+            .fetch_optional(&mut *transaction)
+            .await?;
+
+        match &existing {
+            Some((_, status)) if status == "accepted" => {
+                return Err(anyhow::Error::msg("You are already friends"));
+            }
+            Some((id, _)) if *id == requester_id => {
+                return Err(anyhow::Error::msg("Friend request already sent"));
+            }
+            Some(_) => {
+                const QUERY: &str = "
+                    UPDATE Friendship SET Status = 'accepted', AcceptedAt = CURRENT_TIMESTAMP
+                    WHERE RequesterID = ? AND AddresseeID = ?
+                ";
+
+                sqlx::query(QUERY)
+                    .bind(addressee_id)
+                    .bind(requester_id)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
+            None => {
+                let result: (i64,) = sqlx::query_as(
+                    "SELECT COUNT(*) FROM Friendship WHERE RequesterID = ? OR AddresseeID = ?",
+                )
+                .bind(requester_id)
+                .bind(requester_id)
                 .fetch_one(&mut *transaction)
-                .await
-                .unwrap();
+                .await?;
 
-        if !result.0 {
-            return Err(anyhow::Error::msg("Invalid username or password"));
+                if result.0 >= friends::MAX_FRIENDS {
+                    return Err(anyhow::Error::msg(format!(
+                        "You can have at most {} friends and requests",
+                        friends::MAX_FRIENDS
+                    )));
+                }
+
+                sqlx::query("INSERT INTO Friendship(RequesterID, AddresseeID) VALUES(?, ?)")
+                    .bind(requester_id)
+                    .bind(addressee_id)
+                    .execute(&mut *transaction)
+                    .await?;
+            }
         }
 
+        transaction.commit().await?;
+
+        Ok(existing.is_some())
+    }
+
+    pub async fn accept_friend_request(
+        &self,
+        user_id: i32,
+        requester_id: i32,
+    ) -> anyhow::Result<()> {
         const QUERY: &str = "
-            SELECT * FROM User WHERE Username = ? AND Password = ?
+            UPDATE Friendship SET Status = 'accepted', AcceptedAt = CURRENT_TIMESTAMP
+            WHERE RequesterID = ? AND AddresseeID = ? AND Status = 'pending'
         ";
 
-        let user = sqlx::query_as::<_, User>(QUERY)
-            .bind(username)
-            .bind(password)
-            .fetch_one(&mut *transaction)
+        let result = sqlx::query(QUERY)
+            .bind(requester_id)
+            .bind(user_id)
+            .execute(&self.pool)
             .await?;
-        Ok(user)
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg("Friend request does not exist"));
+        }
+
+        Ok(())
     }
 
-    pub async fn get_leaderboard(&self) -> Vec<User> {
-        sqlx::query_as::<_, User>("SELECT * FROM User ORDER BY EloPoints DESC LIMIT 100;")
-            .fetch_all(&self.pool)
-            .await
-            .unwrap()
+    pub async fn decline_friend_request(
+        &self,
+        user_id: i32,
+        requester_id: i32,
+    ) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            DELETE FROM Friendship
+            WHERE RequesterID = ? AND AddresseeID = ? AND Status = 'pending'
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(requester_id)
+            .bind(user_id)
+            .execute(&self.pool)
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg("Friend request does not exist"));
+        }
+
+        Ok(())
     }
 
-    pub async fn add_elo(&self, username: &str, elo: i32) {
-        sqlx::query("UPDATE User SET EloPoints = EloPoints + ? WHERE Username = ?")
-            .bind(elo)
-            .bind(username)
+    /// Removes a friend, or takes back a request the user sent.
+    pub async fn remove_friend(&self, user_id: i32, other_id: i32) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            DELETE FROM Friendship
+            WHERE (RequesterID = ? AND AddresseeID = ?)
+                OR (RequesterID = ? AND AddresseeID = ? AND Status = 'accepted')
+        ";
+
+        let result = sqlx::query(QUERY)
+            .bind(user_id)
+            .bind(other_id)
+            .bind(other_id)
+            .bind(user_id)
             .execute(&self.pool)
-            .await
-            .unwrap();
+            .await?;
+
+        if result.rows_affected() == 0 {
+            return Err(anyhow::Error::msg("You are not friends"));
+        }
+
+        Ok(())
+    }
+
+    pub async fn get_friends(&self, user_id: i32) -> anyhow::Result<Vec<Friend>> {
+        const QUERY: &str = "
+            SELECT User.Username, User.EloPoints, User.CountryID, User.ProfilePictureURL,
+                Friendship.AcceptedAt
+            FROM Friendship
+            INNER JOIN User ON User.ID = IF(Friendship.RequesterID = ?,
+                Friendship.AddresseeID, Friendship.RequesterID)
+            WHERE (Friendship.RequesterID = ? OR Friendship.AddresseeID = ?)
+                AND Friendship.Status = 'accepted' AND User.DeletedAt IS NULL
+            ORDER BY User.Username
+        ";
+
+        Ok(sqlx::query_as::<_, Friend>(QUERY)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_friend_requests(&self, user_id: i32) -> anyhow::Result<Vec<FriendRequest>> {
+        const QUERY: &str = "
+            SELECT User.Username, Friendship.CreatedAt, Friendship.AddresseeID = ? AS Incoming
+            FROM Friendship
+            INNER JOIN User ON User.ID = IF(Friendship.RequesterID = ?,
+                Friendship.AddresseeID, Friendship.RequesterID)
+            WHERE (Friendship.RequesterID = ? OR Friendship.AddresseeID = ?)
+                AND Friendship.Status = 'pending' AND User.DeletedAt IS NULL
+            ORDER BY Friendship.CreatedAt DESC
+        ";
+
+        Ok(sqlx::query_as::<_, FriendRequest>(QUERY)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
     }
 
-    pub async fn remove_elo(&self, username: &str, elo: i32) {
-        sqlx::query("UPDATE User SET EloPoints = EloPoints - ? WHERE Username = ?")
-            .bind(elo)
+    /// Names of the user's friends, who are told about their presence.
+    pub async fn get_friend_usernames(&self, username: &str) -> anyhow::Result<Vec<String>> {
+        const QUERY: &str = "
+            SELECT Friend.Username FROM User
+            INNER JOIN Friendship ON Friendship.Status = 'accepted'
+                AND (Friendship.RequesterID = User.ID OR Friendship.AddresseeID = User.ID)
+            INNER JOIN User AS Friend ON Friend.ID = IF(Friendship.RequesterID = User.ID,
+                Friendship.AddresseeID, Friendship.RequesterID)
+            WHERE User.Username = ?
+        ";
+
+        let usernames: Vec<(String,)> = sqlx::query_as(QUERY)
             .bind(username)
+            .fetch_all(&self.pool)
+            .await?;
+
+        Ok(usernames.into_iter().map(|x| x.0).collect())
+    }
+
+    pub async fn insert_chat_message(
+        &self,
+        game_id: u64,
+        user_id: i32,
+        body: Option<&str>,
+        emote: Option<&str>,
+        filtered: bool,
+    ) -> anyhow::Result<()> {
+        const QUERY: &str = "
+            INSERT INTO ChatMessage(GameID, UserID, Body, Emote, Filtered) VALUES(?, ?, ?, ?, ?)
+        ";
+
+        sqlx::query(QUERY)
+            .bind(game_id)
+            .bind(user_id)
+            .bind(body)
+            .bind(emote)
+            .bind(filtered)
             .execute(&self.pool)
-            .await
-            .unwrap();
+            .await?;
+
+        Ok(())
+    }
+
+    const CHAT_FROM: &'static str = "
+        SELECT ChatMessage.GameID, User.Username, ChatMessage.Body, ChatMessage.Emote,
+            ChatMessage.Filtered, ChatMessage.CreatedAt
+        FROM ChatMessage
+        INNER JOIN User ON User.ID = ChatMessage.UserID
+    ";
+
+    /// Chat of a game as it was typed, for handling reports.
+    pub async fn get_chat_messages(&self, game_id: u64) -> anyhow::Result<Vec<ChatMessage>> {
+        let query = format!(
+            "{} WHERE ChatMessage.GameID = ? ORDER BY ChatMessage.ID",
+            Self::CHAT_FROM
+        );
+
+        Ok(sqlx::query_as::<_, ChatMessage>(&query)
+            .bind(game_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+
+    pub async fn get_user_chat_messages(&self, user_id: i32) -> anyhow::Result<Vec<ChatMessage>> {
+        let query = format!(
+            "{} WHERE ChatMessage.UserID = ? ORDER BY ChatMessage.ID",
+            Self::CHAT_FROM
+        );
+
+        Ok(sqlx::query_as::<_, ChatMessage>(&query)
+            .bind(user_id)
+            .fetch_all(&self.pool)
+            .await?)
+    }
+}
+
+/// Turns a race on the unique normalized name into the usual error.
+fn username_taken(error: sqlx::Error) -> anyhow::Error {
+    match &error {
+        sqlx::Error::Database(database_error)
+            if database_error.is_unique_violation()
+                && database_error.message().contains("UserNormalizedUsername") =>
+        {
+            UsernameError::Taken.into()
+        }
+        _ => error.into(),
     }
 }
@@ -0,0 +1,298 @@
+use crate::{
+    db::{Db, Friend, FriendRequest, User},
+    game::State,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use tokio::sync::{mpsc::UnboundedSender, Mutex};
+use warp::{filters::ws::Message, reply::Json};
+
+/// Friends and pending requests, in either direction, a user can have.
+pub const MAX_FRIENDS: i64 = 200;
+
+/// What a player is doing, as far as `/game` connections tell.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Presence {
+    #[default]
+    Offline,
+    Online,
+    InQueue,
+    InGame,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct FriendsError {
+    pub err: String,
+}
+
+#[derive(Serialize)]
+pub struct FriendsList {
+    pub friends: Vec<Friend>,
+    pub requests: Vec<FriendRequest>,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct PresenceNotification {
+    id: String,
+    username: String,
+    presence: Presence,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FriendRequestNotification {
+    id: String,
+    username: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct FriendRemovedNotification {
+    id: String,
+    username: String,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&FriendsError {
+        err: error.to_string(),
+    })
+}
+
+pub fn presence(state: &State, username: &str) -> Presence {
+    if state.is_playing(username) {
+        return Presence::InGame;
+    }
+
+    if !state.connections.contains_key(username) {
+        return Presence::Offline;
+    }
+
+    let queued = state.pending_matches.values().any(|x| x.0 == username)
+        || state.rooms.values().any(|x| x.host.0 == username);
+    if queued {
+        Presence::InQueue
+    } else {
+        Presence::Online
+    }
+}
+
+fn presence_message(state: &State, username: &str) -> Message {
+    let notif = PresenceNotification {
+        id: String::from("presence"),
+        username: String::from(username),
+        presence: presence(state, username),
+    };
+
+    Message::text(serde_json::to_string(&notif).unwrap())
+}
+
+/// Tells the user's connected friends what the user is doing now.
+pub async fn broadcast_presence(db: &Db, state: &State, username: &str) {
+    let friends = match db.get_friend_usernames(username).await {
+        Ok(friends) => friends,
+        Err(error) => {
+            log::error!("Failed to get friends of {}: {}", username, error);
+            return;
+        }
+    };
+
+    let message = presence_message(state, username);
+    for friend in friends {
+        if let Some(tx) = state.connections.get(&friend) {
+            let _ = tx.send(message.clone());
+        }
+    }
+}
+
+/// Tells a user who just connected which friends aren't offline.
+pub async fn send_presences(db: &Db, state: &State, username: &str, tx: &UnboundedSender<Message>) {
+    let friends = match db.get_friend_usernames(username).await {
+        Ok(friends) => friends,
+        Err(error) => {
+            log::error!("Failed to get friends of {}: {}", username, error);
+            return;
+        }
+    };
+
+    for friend in friends {
+        if presence(state, &friend) != Presence::Offline {
+            let _ = tx.send(presence_message(state, &friend));
+        }
+    }
+}
+
+fn notify(state: &State, username: &str, message: Message) {
+    if let Some(tx) = state.connections.get(username) {
+        let _ = tx.send(message);
+    }
+}
+
+/// Both users learn about each other once they became friends.
+fn notify_friends(state: &State, user: &str, friend: &str) {
+    notify(state, user, presence_message(state, friend));
+    notify(state, friend, presence_message(state, user));
+}
+
+async fn get_other(db: &Db, user: &User, username: &str) -> anyhow::Result<User> {
+    if user.is_guest {
+        return Err(anyhow::Error::msg("Guests can't have friends"));
+    }
+
+    let other = db.get_user_by_name(username).await?;
+    if other.id == user.id {
+        return Err(anyhow::Error::msg("You can't be friends with yourself"));
+    }
+    if other.is_guest {
+        return Err(anyhow::Error::msg("Guests can't have friends"));
+    }
+
+    Ok(other)
+}
+
+async fn list_of(db: &Db, state: &Mutex<State>, user: &User) -> anyhow::Result<FriendsList> {
+    let mut friends = db.get_friends(user.id).await?;
+    let requests = db.get_friend_requests(user.id).await?;
+
+    let state = state.lock().await;
+    for friend in &mut friends {
+        friend.presence = presence(&state, &friend.username);
+    }
+
+    Ok(FriendsList { friends, requests })
+}
+
+pub async fn list(user: User, db: Arc<Db>, state: Arc<Mutex<State>>) -> Json {
+    match list_of(&db, &state, &user).await {
+        Ok(list) => warp::reply::json(&list),
+        Err(err) => error(err),
+    }
+}
+
+/// Sends a friend request, accepting it right away when the other user
+/// already asked.
+pub async fn request(username: String, user: User, db: Arc<Db>, state: Arc<Mutex<State>>) -> Json {
+    let result = async {
+        let other = get_other(&db, &user, &username).await?;
+        let accepted = db.insert_friend_request(user.id, other.id).await?;
+
+        let state_guard = state.lock().await;
+        if accepted {
+            notify_friends(&state_guard, &user.username, &other.username);
+        } else {
+            let notif = FriendRequestNotification {
+                id: String::from("friendrequest"),
+                username: user.username.clone(),
+            };
+            notify(
+                &state_guard,
+                &other.username,
+                Message::text(serde_json::to_string(&notif).unwrap()),
+            );
+        }
+        drop(state_guard);
+
+        list_of(&db, &state, &user).await
+    }
+    .await;
+
+    match result {
+        Ok(list) => warp::reply::json(&list),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn accept(username: String, user: User, db: Arc<Db>, state: Arc<Mutex<State>>) -> Json {
+    let result = async {
+        let other = get_other(&db, &user, &username).await?;
+        db.accept_friend_request(user.id, other.id).await?;
+
+        notify_friends(&*state.lock().await, &user.username, &other.username);
+
+        list_of(&db, &state, &user).await
+    }
+    .await;
+
+    match result {
+        Ok(list) => warp::reply::json(&list),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn decline(username: String, user: User, db: Arc<Db>, state: Arc<Mutex<State>>) -> Json {
+    let result = async {
+        let other = get_other(&db, &user, &username).await?;
+        db.decline_friend_request(user.id, other.id).await?;
+
+        list_of(&db, &state, &user).await
+    }
+    .await;
+
+    match result {
+        Ok(list) => warp::reply::json(&list),
+        Err(err) => error(err),
+    }
+}
+
+/// Removes a friend or takes back a sent request.
+pub async fn remove(username: String, user: User, db: Arc<Db>, state: Arc<Mutex<State>>) -> Json {
+    let result = async {
+        let other = get_other(&db, &user, &username).await?;
+        db.remove_friend(user.id, other.id).await?;
+
+        let notif = FriendRemovedNotification {
+            id: String::from("friendremoved"),
+            username: user.username.clone(),
+        };
+        notify(
+            &*state.lock().await,
+            &other.username,
+            Message::text(serde_json::to_string(&notif).unwrap()),
+        );
+
+        list_of(&db, &state, &user).await
+    }
+    .await;
+
+    match result {
+        Ok(list) => warp::reply::json(&list),
+        Err(err) => error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{game::Room, rules::RuleSet};
+    use tokio::sync::mpsc::unbounded_channel;
+
+    #[test]
+    fn presence_follows_connections_and_queues() {
+        let (tx, _rx) = unbounded_channel();
+        let mut state = State::new();
+        for username in ["online", "queued", "hosting"] {
+            state.connections.insert(String::from(username), tx.clone());
+        }
+        state
+            .pending_matches
+            .insert(RuleSet::default(), (String::from("queued"), tx.clone()));
+        state.rooms.insert(
+            String::from("ABCDEF"),
+            Room {
+                host: (String::from("hosting"), tx.clone()),
+                rules: RuleSet::default(),
+            },
+        );
+
+        assert_eq!(presence(&state, "offline"), Presence::Offline);
+        assert_eq!(presence(&state, "online"), Presence::Online);
+        assert_eq!(presence(&state, "queued"), Presence::InQueue);
+        assert_eq!(presence(&state, "hosting"), Presence::InQueue);
+    }
+
+    #[test]
+    fn presence_serializes_in_snake_case() {
+        assert_eq!(
+            serde_json::to_string(&Presence::InGame).unwrap(),
+            "\"in_game\""
+        );
+    }
+}
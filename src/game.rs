@@ -1,9 +1,24 @@
-use crate::{authentication::AuthenticationData, db::Db};
+use crate::{
+    audit::{self, AuditAction, RequestInfo},
+    authentication::AuthenticationData,
+    challenge::{self, Challenge},
+    chat,
+    db::{Db, User},
+    friends,
+    rate_limit::{self, ChatLimiter, LoginLimiter},
+    rules::{RuleSet, SeriesRating},
+    tournament, two_factor,
+};
 use futures_util::{SinkExt, StreamExt, TryFutureExt};
-use rand::{seq::SliceRandom, Rng};
+use rand::{distributions::Alphanumeric, Rng};
 use serde::{Deserialize, Serialize};
 use serde_json::Value;
-use std::{fmt::Display, sync::Arc, time::Instant};
+use std::{
+    collections::{HashMap, HashSet},
+    fmt::Display,
+    sync::Arc,
+    time::Instant,
+};
 use tokio::sync::{
     mpsc::{self, UnboundedSender},
     Mutex,
@@ -11,25 +26,73 @@ use tokio::sync::{
 use tokio_stream::wrappers::UnboundedReceiverStream;
 use warp::{filters::ws::Message, reply::Reply};
 
+pub const ROOM_CODE_LENGTH: usize = 6;
+
+#[derive(Debug)]
+pub struct Room {
+    pub host: (String, UnboundedSender<Message>),
+    pub rules: RuleSet,
+}
+
 #[derive(Default, Debug)]
 pub struct State {
     pub games: Vec<GameState>,
-    pub pending_match: Option<(String, UnboundedSender<Message>)>,
+    pub pending_matches: HashMap<RuleSet, (String, UnboundedSender<Message>)>,
+    pub rooms: HashMap<String, Room>,
+    pub connections: HashMap<String, UnboundedSender<Message>>,
+    pub challenges: Vec<Challenge>,
+    /// Players who muted their opponent's chat for the rest of the series,
+    /// as `(player, opponent)`.
+    pub chat_muted: HashSet<(String, String)>,
 }
 
 impl State {
     pub fn new() -> Self {
         Self {
             games: Vec::new(),
-            pending_match: None,
+            pending_matches: HashMap::new(),
+            rooms: HashMap::new(),
+            connections: HashMap::new(),
+            challenges: Vec::new(),
+            chat_muted: HashSet::new(),
         }
     }
 
-    pub fn get_game(&mut self, id: &str) -> &mut GameState {
+    pub fn generate_room_code(&self) -> String {
+        loop {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(ROOM_CODE_LENGTH)
+                .map(|x| (x as char).to_ascii_uppercase())
+                .collect();
+
+            if !self.rooms.contains_key(&code) {
+                return code;
+            }
+        }
+    }
+
+    pub fn is_playing(&self, username: &str) -> bool {
+        self.games
+            .iter()
+            .any(|x| x.p1.0 == username || x.p2.0 == username)
+    }
+
+    /// Whether the player is referred to by name anywhere, which includes
+    /// games and queues they left behind when disconnecting.
+    pub fn is_present(&self, username: &str) -> bool {
+        self.connections.contains_key(username)
+            || self.is_playing(username)
+            || self.pending_matches.values().any(|x| x.0 == username)
+            || self.rooms.values().any(|x| x.host.0 == username)
+            || self.challenges.iter().any(|x| x.involves(username))
+    }
+
+    pub fn get_game(&mut self, id: &str) -> anyhow::Result<&mut GameState> {
         self.games
             .iter_mut()
             .find(|x| x.p1.0 == id || x.p2.0 == id)
-            .unwrap()
+            .ok_or_else(|| anyhow::Error::msg("You are not in a game"))
     }
 }
 
@@ -40,6 +103,7 @@ pub enum Symbol {
     Spades,
     Diamonds,
     Hearts,
+    Joker,
 }
 
 impl Display for Symbol {
@@ -49,6 +113,7 @@ impl Display for Symbol {
             Symbol::Spades => f.write_str("spades"),
             Symbol::Diamonds => f.write_str("diamonds"),
             Symbol::Hearts => f.write_str("hearts"),
+            Symbol::Joker => f.write_str("joker"),
         }
     }
 }
@@ -60,16 +125,40 @@ pub struct Card {
 }
 
 impl Card {
-    pub fn normalize(&mut self) {
+    pub fn normalize(&mut self, rules: &RuleSet) {
+        // jokers outrank everything
+        if self.symbol == Symbol::Joker {
+            self.value = 15;
+        }
+
         // ace
-        if self.value == 1 {
+        if rules.ace_high && self.value == 1 {
             self.value = 14;
         }
     }
 }
 
+#[derive(Debug, Clone)]
+pub struct Series {
+    pub best_of: u32,
+    pub p1_wins: u32,
+    pub p2_wins: u32,
+    pub first_turn: String,
+    /// Tournament match decided by this series.
+    pub tournament_match: Option<i32>,
+    /// Casual series never change ratings.
+    pub casual: bool,
+}
+
+impl Series {
+    pub fn is_decided(&self) -> bool {
+        self.p1_wins.max(self.p2_wins) > self.best_of / 2
+    }
+}
+
 #[derive(Debug)]
 pub struct GameState {
+    pub id: u64,
     pub p1: (String, UnboundedSender<Message>),
     pub p2: (String, UnboundedSender<Message>),
     pub turn: String,
@@ -77,6 +166,12 @@ pub struct GameState {
     pub p1_cards: Vec<Card>,
     pub p2_cards: Vec<Card>,
     pub turned_card: Option<Card>,
+    pub rules: RuleSet,
+    pub p1_sips: u32,
+    pub p2_sips: u32,
+    pub series: Series,
+    /// Casual games and games involving a guest don't change ratings.
+    pub rated: bool,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -85,6 +180,21 @@ pub struct GameStartNotification {
     cards: Vec<Card>,
     opponent_elo: i32,
     opponent_name: String,
+    rated: bool,
+    rules: RuleSet,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct RoomCreatedNotification {
+    id: String,
+    code: String,
+    rules: RuleSet,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct GameErrorNotification {
+    id: String,
+    err: String,
 }
 
 #[derive(Serialize, Deserialize)]
@@ -100,16 +210,121 @@ pub struct TurnedCardNotif {
     symbol: String,
 }
 
+#[derive(Serialize, Deserialize)]
+pub struct PenaltyNotification {
+    id: String,
+    username: String,
+    sips: u32,
+    total_sips: u32,
+}
+
 #[derive(Serialize, Deserialize)]
 pub struct GameEndNotification {
     id: String,
     winner: String,
 }
 
-pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSocket) {
+#[derive(Serialize, Deserialize)]
+pub struct GameAbortedNotification {
+    id: String,
+    reason: String,
+}
+
+#[derive(Serialize, Deserialize)]
+pub struct SeriesScoreNotification {
+    id: String,
+    best_of: u32,
+    player1: String,
+    player1_wins: u32,
+    player2: String,
+    player2_wins: u32,
+    finished: bool,
+}
+
+/// First message on `/game`, a session token or the account's credentials.
+#[derive(Deserialize)]
+#[serde(untagged)]
+pub enum GameAuthentication {
+    Session { token: String },
+    Password(AuthenticationData),
+}
+
+async fn authenticate(
+    request: &RequestInfo,
+    db: &Db,
+    limiter: &LoginLimiter,
+    authentication: GameAuthentication,
+) -> anyhow::Result<User> {
+    let authentication = match authentication {
+        GameAuthentication::Session { token } => return db.get_user_by_session(&token).await,
+        GameAuthentication::Password(authentication) => authentication,
+    };
+
+    if let Err(retry_after) = limiter.check_user(&authentication.username).await {
+        return Err(anyhow::Error::msg(format!(
+            "Too many login attempts, try again in {} seconds",
+            rate_limit::retry_after_secs(retry_after)
+        )));
+    }
+
+    match db
+        .get_user_by_name_password(&authentication.username, &authentication.password)
+        .await
+    {
+        Ok(user) => {
+            if user.totp_enabled {
+                // there is no challenge step here, the code must come along
+                let code = authentication.code.as_deref().ok_or_else(|| {
+                    anyhow::Error::msg("Two-factor code required, or sign in with a session")
+                })?;
+                two_factor::verify_login(db, request, limiter, &user, code).await?;
+            }
+
+            limiter.record_success(&user.username).await;
+            audit::record(
+                db,
+                request,
+                AuditAction::Login,
+                Some(user.id),
+                Some(user.id),
+                Some("game"),
+            )
+            .await;
+            Ok(user)
+        }
+        Err(error) => {
+            limiter
+                .record_failure(request.ip, &authentication.username)
+                .await;
+            audit::record_failed_login(db, request, &authentication.username, &error).await;
+            Err(error)
+        }
+    }
+}
+
+pub async fn handle(
+    request: RequestInfo,
+    db: Arc<Db>,
+    state: Arc<Mutex<State>>,
+    limiter: Arc<LoginLimiter>,
+    chat_limiter: Arc<ChatLimiter>,
+    ws: warp::ws::WebSocket,
+) {
     let (tx, rx) = mpsc::unbounded_channel();
     let (mut user_ws_tx, mut user_ws_rx) = ws.split();
 
+    let mut rx = UnboundedReceiverStream::new(rx);
+    tokio::task::spawn(async move {
+        while let Some(message) = rx.next().await {
+            user_ws_tx
+                .send(message)
+                .unwrap_or_else(|e| {
+                    eprintln!("websocket send error: {}", e);
+                })
+                .await;
+        }
+    });
+
     let authentication = match user_ws_rx.next().await {
         Some(Ok(authentication)) => authentication,
         _ => {
@@ -118,7 +333,7 @@ pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSock
         }
     };
     let authentication =
-        match serde_json::from_slice::<AuthenticationData>(authentication.as_bytes()) {
+        match serde_json::from_slice::<GameAuthentication>(authentication.as_bytes()) {
             Ok(authentication) => authentication,
             Err(_) => {
                 log::error!("Invalid authentication json");
@@ -126,28 +341,21 @@ pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSock
             }
         };
 
-    let me = match db
-        .get_user_by_name_password(&authentication.username, &authentication.password)
-        .await
-    {
+    let me = match authenticate(&request, &db, &limiter, authentication).await {
         Ok(user) => user,
         Err(error) => {
-            eprintln!("Invalid authentication for /game: {}", error);
+            log::warn!("Invalid authentication for /game: {}", error);
+            send_error(&tx, error);
             return;
         }
     };
 
-    let mut rx = UnboundedReceiverStream::new(rx);
-    tokio::task::spawn(async move {
-        while let Some(message) = rx.next().await {
-            user_ws_tx
-                .send(message)
-                .unwrap_or_else(|e| {
-                    eprintln!("websocket send error: {}", e);
-                })
-                .await;
-        }
-    });
+    {
+        let mut state = state.lock().await;
+        state.connections.insert(me.username.clone(), tx.clone());
+        friends::broadcast_presence(&db, &state, &me.username).await;
+        friends::send_presences(&db, &state, &me.username, &tx).await;
+    }
 
     while let Some(result) = user_ws_rx.next().await {
         let msg = match result {
@@ -173,117 +381,150 @@ pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSock
             }
         };
 
-        let id = msg["id"].as_str().unwrap();
+        let id = msg["id"].as_str().unwrap_or_default();
         match id {
             "findmatch" => {
+                let rules = match parse_rules(&msg) {
+                    Ok(rules) => rules,
+                    Err(error) => {
+                        send_error(&tx, error);
+                        continue;
+                    }
+                };
+
                 let mut state = state.lock().await;
-                if let Some(pending_match) = state.pending_match.take() {
-                    let (p1_cards, p2_cards, turn) = {
-                        let mut rand = rand::thread_rng();
-                        let mut cards = Vec::with_capacity(52);
-
-                        for symbol in [
-                            Symbol::Clubs,
-                            Symbol::Diamonds,
-                            Symbol::Hearts,
-                            Symbol::Spades,
-                        ]
-                        .into_iter()
-                        {
-                            for i in 1..=13 {
-                                cards.push(Card { symbol, value: i });
-                            }
-                        }
+                if let Some(pending_match) = state.pending_matches.remove(&rules) {
+                    let me = (me.username.clone(), tx.clone());
+                    if let Err(error) = start_game(&db, &mut state, pending_match, me, rules).await
+                    {
+                        log::error!("Failed to start game: {}", error);
+                    }
+                } else {
+                    state
+                        .pending_matches
+                        .insert(rules, (me.username.clone(), tx.clone()));
+                    friends::broadcast_presence(&db, &state, &me.username).await;
+                }
+            }
 
-                        cards.shuffle(&mut rand);
+            "createroom" => {
+                let rules = match parse_rules(&msg) {
+                    Ok(rules) => rules,
+                    Err(error) => {
+                        send_error(&tx, error);
+                        continue;
+                    }
+                };
 
-                        let mut p1_cards = Vec::with_capacity(26);
-                        let mut p2_cards = Vec::with_capacity(26);
+                let mut state = state.lock().await;
+                let code = state.generate_room_code();
+                let room = Room {
+                    host: (me.username.clone(), tx.clone()),
+                    rules,
+                };
+                state.rooms.insert(code.clone(), room);
+                friends::broadcast_presence(&db, &state, &me.username).await;
 
-                        for (i, card) in cards.into_iter().enumerate() {
-                            if i % 2 == 0 {
-                                p1_cards.push(card);
-                            } else {
-                                p2_cards.push(card);
-                            }
-                        }
+                let room_notif = RoomCreatedNotification {
+                    id: String::from("roomcreated"),
+                    code,
+                    rules,
+                };
 
-                        (p1_cards, p2_cards, rand.gen_bool(0.5))
-                    };
+                tx.send(Message::text(serde_json::to_string(&room_notif).unwrap()))
+                    .unwrap();
+            }
 
-                    let opponent = db.get_user_by_name(&pending_match.0).await.unwrap();
-                    let notif1 = GameStartNotification {
-                        id: String::from("gamestart"),
-                        cards: p2_cards.clone(),
-                        opponent_elo: opponent.elo_points,
-                        opponent_name: opponent.username,
-                    };
+            "joinroom" => {
+                let code = msg["code"]
+                    .as_str()
+                    .unwrap_or_default()
+                    .to_ascii_uppercase();
 
-                    let notif2 = GameStartNotification {
-                        id: String::from("gamestart"),
-                        cards: p1_cards.clone(),
-                        opponent_elo: me.elo_points,
-                        opponent_name: me.username.clone(),
-                    };
+                let mut state = state.lock().await;
+                let room = match state.rooms.remove(&code) {
+                    Some(room) => room,
+                    None => {
+                        send_error(&tx, anyhow::Error::msg("Room does not exist"));
+                        continue;
+                    }
+                };
 
-                    tx.send(Message::text(serde_json::to_string(&notif1).unwrap()))
-                        .unwrap();
+                let me = (me.username.clone(), tx.clone());
+                if let Err(error) = start_game(&db, &mut state, room.host, me, room.rules).await {
+                    log::error!("Failed to start game: {}", error);
+                }
+            }
 
-                    pending_match
-                        .1
-                        .send(Message::text(serde_json::to_string(&notif2).unwrap()))
-                        .unwrap();
+            "challenge" => {
+                if let Err(error) = challenge::challenge(&db, &state, &me, &tx, &msg).await {
+                    send_error(&tx, error);
+                }
+            }
 
-                    let turn_notif = if turn {
-                        GameTurnNotification {
-                            id: String::from("turnnotif"),
-                            turn: pending_match.0.clone(),
-                        }
-                    } else {
-                        GameTurnNotification {
-                            id: String::from("turnnotif"),
-                            turn: me.username.clone(),
-                        }
-                    };
+            "acceptchallenge" => {
+                if let Err(error) = challenge::accept(&db, &state, &me, &tx, &msg).await {
+                    send_error(&tx, error);
+                }
+            }
 
-                    let turn_notif = Message::text(serde_json::to_string(&turn_notif).unwrap());
+            "declinechallenge" => {
+                if let Err(error) = challenge::decline(&state, &me, &msg).await {
+                    send_error(&tx, error);
+                }
+            }
 
-                    tx.send(turn_notif.clone()).unwrap();
-                    pending_match.1.send(turn_notif).unwrap();
+            "cancelchallenge" => {
+                if let Err(error) = challenge::cancel(&state, &me, &msg).await {
+                    send_error(&tx, error);
+                }
+            }
 
-                    let game = GameState {
-                        turn: if turn {
-                            pending_match.0.clone()
-                        } else {
-                            me.username.clone()
-                        },
-                        p1: pending_match,
-                        p2: (me.username.clone(), tx.clone()),
-                        timer: Instant::now(),
-                        p1_cards,
-                        p2_cards,
-                        turned_card: None,
-                    };
+            "chat" => {
+                if let Err(error) = chat::chat(&db, &state, &chat_limiter, &me, &msg).await {
+                    send_error(&tx, error);
+                }
+            }
 
-                    state.games.push(game);
-                } else {
-                    state.pending_match = Some((me.username.clone(), tx.clone()));
+            "emote" => {
+                if let Err(error) = chat::emote(&db, &state, &chat_limiter, &me, &msg).await {
+                    send_error(&tx, error);
+                }
+            }
+
+            "mutechat" => {
+                if let Err(error) = chat::mute(&state, &me, &tx, &msg).await {
+                    send_error(&tx, error);
                 }
             }
 
             "turncard" => {
                 let mut state = state.lock().await;
-                let game = state.get_game(&me.username);
+                // the game may have been ended by a moderator in the meantime
+                let game = match state.get_game(&me.username) {
+                    Ok(game) => game,
+                    Err(error) => {
+                        send_error(&tx, error);
+                        continue;
+                    }
+                };
                 if game.turn != me.username {
                     continue;
                 }
 
-                let symbol = msg["cardsymbol"].as_str().unwrap();
-                let value = msg["cardvalue"].as_i64().unwrap() as u32;
+                let (symbol, value) = match (msg["cardsymbol"].as_str(), msg["cardvalue"].as_u64())
+                {
+                    (Some(symbol), Some(value)) => (symbol, value as u32),
+                    _ => {
+                        send_error(&tx, anyhow::Error::msg("Invalid card"));
+                        continue;
+                    }
+                };
                 let symbol = match symbol {
                     "spades" => Symbol::Spades,
                     "clubs" => Symbol::Clubs,
                     "diamonds" => Symbol::Diamonds,
+                    "joker" => Symbol::Joker,
                     _ => Symbol::Hearts,
                 };
 
@@ -294,34 +535,74 @@ pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSock
                     &mut game.p2_cards
                 };
 
-                let index = cards
+                let index = match cards
                     .iter()
                     .position(|x| x.symbol == symbol && x.value == value)
-                    .unwrap();
+                {
+                    Some(index) => index,
+                    None => {
+                        send_error(&tx, anyhow::Error::msg("You don't hold that card"));
+                        continue;
+                    }
+                };
 
                 let mut turned_card = cards.remove(index);
 
                 if let Some(mut prev_turned_card) = game.turned_card.take() {
-                    turned_card.normalize();
-                    prev_turned_card.normalize();
+                    turned_card.normalize(&game.rules);
+                    prev_turned_card.normalize(&game.rules);
 
                     if turned_card.value < prev_turned_card.value {
-                        if is_p1 {
+                        let sips = game.rules.sips_per_loss;
+                        let total_sips = if is_p1 {
                             game.turn = game.p2.0.clone();
+                            game.p1_sips += sips;
+                            game.p1_sips
                         } else {
                             game.turn = game.p1.0.clone();
+                            game.p2_sips += sips;
+                            game.p2_sips
+                        };
+
+                        if sips > 0 {
+                            if let Err(error) = db.add_penalty(game.id, &me.username, sips).await {
+                                log::error!("Failed to record penalty: {}", error);
+                            }
+
+                            let penalty_notif = PenaltyNotification {
+                                id: String::from("penalty"),
+                                username: me.username.clone(),
+                                sips,
+                                total_sips,
+                            };
+
+                            let penalty_notif =
+                                Message::text(serde_json::to_string(&penalty_notif).unwrap());
+                            let _ = game.p1.1.send(penalty_notif.clone());
+                            let _ = game.p2.1.send(penalty_notif);
                         }
                     }
                 } else {
+                    let opponent_cards = if is_p1 {
+                        &game.p2_cards
+                    } else {
+                        &game.p1_cards
+                    };
+
+                    let must_answer = !game.rules.follow_suit
+                        || opponent_cards
+                            .iter()
+                            .any(|x| x.symbol == turned_card.symbol);
+
                     if is_p1 {
-                        if game.p2_cards.iter().all(|x| x.symbol != turned_card.symbol) {
+                        if !must_answer {
                             game.turn = game.p1.0.clone();
                         } else {
                             game.turn = game.p2.0.clone();
                             game.turned_card = Some(turned_card);
                         }
                     } else {
-                        if game.p1_cards.iter().all(|x| x.symbol != turned_card.symbol) {
+                        if !must_answer {
                             game.turn = game.p2.0.clone();
                         } else {
                             game.turn = game.p1.0.clone();
@@ -344,47 +625,20 @@ pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSock
                     }
                 };
 
+                // the opponent may have disconnected, the game goes on without them
+                let turned_card_notif =
+                    Message::text(serde_json::to_string(&turned_card_notif).unwrap());
                 if is_p1 {
-                    game.p2
-                        .1
-                        .send(Message::text(
-                            serde_json::to_string(&turned_card_notif).unwrap(),
-                        ))
-                        .unwrap();
+                    let _ = game.p2.1.send(turned_card_notif);
                 } else {
-                    game.p1
-                        .1
-                        .send(Message::text(
-                            serde_json::to_string(&turned_card_notif).unwrap(),
-                        ))
-                        .unwrap();
+                    let _ = game.p1.1.send(turned_card_notif);
                 }
 
-                if game.p1_cards.is_empty() || game.p1_cards.is_empty() {
-                    let end_notif = GameEndNotification {
-                        id: String::from("gameend"),
-                        winner: game.turn.clone(),
-                    };
-
-                    let end_notif = Message::text(serde_json::to_string(&end_notif).unwrap());
-
-                    db.add_elo(&me.username, 15).await;
-
-                    if is_p1 {
-                        db.remove_elo(&game.p2.0, 15).await;
-                    } else {
-                        db.remove_elo(&game.p1.0, 15).await;
+                if game.p1_cards.is_empty() || game.p2_cards.is_empty() {
+                    let game_id = game.id;
+                    if let Err(error) = finish_game(&db, &mut state, game_id, &me.username).await {
+                        log::error!("Failed to finish game: {}", error);
                     }
-
-                    game.p1.1.send(end_notif.clone()).unwrap();
-                    game.p2.1.send(end_notif).unwrap();
-
-                    let index = state
-                        .games
-                        .iter()
-                        .position(|x| x.p1.0 == me.username || x.p2.0 == me.username)
-                        .unwrap();
-                    state.games.remove(index);
                 } else {
                     let turn_notif = GameTurnNotification {
                         id: String::from("turnnotif"),
@@ -392,16 +646,425 @@ pub async fn handle(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::WebSock
                     };
 
                     let turn_notif = Message::text(serde_json::to_string(&turn_notif).unwrap());
-                    game.p1.1.send(turn_notif.clone()).unwrap();
-                    game.p2.1.send(turn_notif).unwrap();
+                    let _ = game.p1.1.send(turn_notif.clone());
+                    let _ = game.p2.1.send(turn_notif);
                 }
             }
 
             _ => {}
         }
     }
+
+    let mut state = state.lock().await;
+    if state
+        .connections
+        .get(&me.username)
+        .is_some_and(|x| x.same_channel(&tx))
+    {
+        state.connections.remove(&me.username);
+        challenge::cancel_all(&mut state, &me.username);
+        friends::broadcast_presence(&db, &state, &me.username).await;
+    }
 }
 
-pub fn game(db: Arc<Db>, state: Arc<Mutex<State>>, ws: warp::ws::Ws) -> impl Reply {
-    ws.on_upgrade(|websocket| handle(db, state, websocket))
+pub fn parse_rules(msg: &Value) -> anyhow::Result<RuleSet> {
+    let rules = match &msg["rules"] {
+        Value::Null => RuleSet::default(),
+        rules => serde_json::from_value::<RuleSet>(rules.clone())?,
+    };
+
+    rules.validate()?;
+    Ok(rules)
+}
+
+fn send_error(tx: &UnboundedSender<Message>, error: anyhow::Error) {
+    let error_notif = GameErrorNotification {
+        id: String::from("error"),
+        err: error.to_string(),
+    };
+
+    tx.send(Message::text(serde_json::to_string(&error_notif).unwrap()))
+        .unwrap();
+}
+
+pub async fn start_game(
+    db: &Db,
+    state: &mut State,
+    p1: (String, UnboundedSender<Message>),
+    p2: (String, UnboundedSender<Message>),
+    rules: RuleSet,
+) -> anyhow::Result<()> {
+    start_game_for_match(db, state, p1, p2, rules, None).await
+}
+
+/// Starts the first game of a series deciding a tournament match.
+pub async fn start_game_for_match(
+    db: &Db,
+    state: &mut State,
+    p1: (String, UnboundedSender<Message>),
+    p2: (String, UnboundedSender<Message>),
+    rules: RuleSet,
+    tournament_match: Option<i32>,
+) -> anyhow::Result<()> {
+    start_new_series(db, state, p1, p2, rules, tournament_match, false).await
+}
+
+/// Starts the series of an accepted challenge, rated only when `ranked`.
+pub async fn start_challenge_game(
+    db: &Db,
+    state: &mut State,
+    p1: (String, UnboundedSender<Message>),
+    p2: (String, UnboundedSender<Message>),
+    rules: RuleSet,
+    ranked: bool,
+) -> anyhow::Result<()> {
+    start_new_series(db, state, p1, p2, rules, None, !ranked).await
+}
+
+async fn start_new_series(
+    db: &Db,
+    state: &mut State,
+    p1: (String, UnboundedSender<Message>),
+    p2: (String, UnboundedSender<Message>),
+    rules: RuleSet,
+    tournament_match: Option<i32>,
+    casual: bool,
+) -> anyhow::Result<()> {
+    let first_turn = if rand::thread_rng().gen_bool(0.5) {
+        p1.0.clone()
+    } else {
+        p2.0.clone()
+    };
+
+    let series = Series {
+        best_of: rules.best_of,
+        p1_wins: 0,
+        p2_wins: 0,
+        first_turn,
+        tournament_match,
+        casual,
+    };
+
+    start_series_game(db, state, p1, p2, rules, series).await
+}
+
+async fn start_series_game(
+    db: &Db,
+    state: &mut State,
+    p1: (String, UnboundedSender<Message>),
+    p2: (String, UnboundedSender<Message>),
+    rules: RuleSet,
+    series: Series,
+) -> anyhow::Result<()> {
+    let (p1_cards, p2_cards) = rules.deal();
+
+    let p1_user = db.get_user_by_name(&p1.0).await?;
+    let p2_user = db.get_user_by_name(&p2.0).await?;
+    let id = db.insert_game(p1_user.id, p2_user.id, &rules).await?;
+    let rated = !series.casual && !p1_user.is_guest && !p2_user.is_guest;
+
+    if let Some(tournament_match) = series.tournament_match {
+        db.set_tournament_match_game(tournament_match, id).await?;
+    }
+
+    let notif1 = GameStartNotification {
+        id: String::from("gamestart"),
+        cards: p2_cards.clone(),
+        opponent_elo: p1_user.elo_points,
+        opponent_name: p1_user.username,
+        rated,
+        rules,
+    };
+
+    let notif2 = GameStartNotification {
+        id: String::from("gamestart"),
+        cards: p1_cards.clone(),
+        opponent_elo: p2_user.elo_points,
+        opponent_name: p2_user.username,
+        rated,
+        rules,
+    };
+
+    p2.1.send(Message::text(serde_json::to_string(&notif1).unwrap()))?;
+    p1.1.send(Message::text(serde_json::to_string(&notif2).unwrap()))?;
+
+    let turn = series.first_turn.clone();
+    let turn_notif = GameTurnNotification {
+        id: String::from("turnnotif"),
+        turn: turn.clone(),
+    };
+
+    let turn_notif = Message::text(serde_json::to_string(&turn_notif).unwrap());
+
+    p2.1.send(turn_notif.clone())?;
+    p1.1.send(turn_notif)?;
+
+    let game = GameState {
+        id,
+        turn,
+        p1,
+        p2,
+        timer: Instant::now(),
+        p1_cards,
+        p2_cards,
+        turned_card: None,
+        rules,
+        p1_sips: 0,
+        p2_sips: 0,
+        series,
+        rated,
+    };
+
+    // later games of a series don't change what the players are doing
+    let first_game = game.series.p1_wins + game.series.p2_wins == 0;
+    let (player1, player2) = (game.p1.0.clone(), game.p2.0.clone());
+    state.games.push(game);
+
+    if first_game {
+        friends::broadcast_presence(db, state, &player1).await;
+        friends::broadcast_presence(db, state, &player2).await;
+    }
+
+    Ok(())
+}
+
+/// Ends a game won by `winner`, applies the rating change and deals the
+/// next game when the series is not decided yet.
+pub async fn finish_game(
+    db: &Db,
+    state: &mut State,
+    game_id: u64,
+    winner: &str,
+) -> anyhow::Result<()> {
+    let index = state
+        .games
+        .iter()
+        .position(|x| x.id == game_id)
+        .ok_or_else(|| anyhow::Error::msg("Game does not exist"))?;
+
+    // the game stays in place when the result can't be stored, so it can
+    // still be ended later instead of silently dropping the series
+    if let Err(error) = db.end_game(game_id, winner).await {
+        let error_notif = GameErrorNotification {
+            id: String::from("error"),
+            err: format!("Failed to end game: {}", error),
+        };
+
+        let error_notif = Message::text(serde_json::to_string(&error_notif).unwrap());
+        let game = &state.games[index];
+        let _ = game.p1.1.send(error_notif.clone());
+        let _ = game.p2.1.send(error_notif);
+        return Err(error);
+    }
+
+    let mut game = state.games.remove(index);
+
+    let p1_won = winner == game.p1.0;
+    if p1_won {
+        game.series.p1_wins += 1;
+    } else {
+        game.series.p2_wins += 1;
+    }
+
+    let finished = game.series.is_decided();
+    match game.rules.series_rating {
+        _ if !game.rated => {}
+        SeriesRating::PerGame => {
+            let loser = if p1_won { &game.p2.0 } else { &game.p1.0 };
+            db.add_elo(winner, 15, Some(game.id)).await?;
+            db.remove_elo(loser, 15, Some(game.id)).await?;
+        }
+        SeriesRating::PerSeries if finished => {
+            let (series_winner, series_loser) = if game.series.p1_wins > game.series.p2_wins {
+                (&game.p1.0, &game.p2.0)
+            } else {
+                (&game.p2.0, &game.p1.0)
+            };
+            db.add_elo(series_winner, 15, Some(game.id)).await?;
+            db.remove_elo(series_loser, 15, Some(game.id)).await?;
+        }
+        SeriesRating::PerSeries => {}
+    }
+
+    let loser = if p1_won { &game.p2.0 } else { &game.p1.0 };
+    let game_seconds = game.timer.elapsed().as_secs();
+    if let Err(error) = db.record_game_result(winner, loser, game_seconds).await {
+        log::error!("Failed to record game stats: {}", error);
+    }
+
+    if let Err(error) = db.leaderboard_stream.publish(db).await {
+        log::error!("Failed to publish leaderboard changes: {}", error);
+    }
+
+    let end_notif = GameEndNotification {
+        id: String::from("gameend"),
+        winner: winner.to_owned(),
+    };
+
+    // either player may have left already, the series still has to move on
+    let end_notif = Message::text(serde_json::to_string(&end_notif).unwrap());
+    let _ = game.p1.1.send(end_notif.clone());
+    let _ = game.p2.1.send(end_notif);
+
+    if game.series.best_of > 1 {
+        let series_notif = SeriesScoreNotification {
+            id: String::from("seriesscore"),
+            best_of: game.series.best_of,
+            player1: game.p1.0.clone(),
+            player1_wins: game.series.p1_wins,
+            player2: game.p2.0.clone(),
+            player2_wins: game.series.p2_wins,
+            finished,
+        };
+
+        let series_notif = Message::text(serde_json::to_string(&series_notif).unwrap());
+        let _ = game.p1.1.send(series_notif.clone());
+        let _ = game.p2.1.send(series_notif);
+    }
+
+    if finished {
+        chat::clear_mutes(state, &game.p1.0, &game.p2.0);
+
+        if let Some(tournament_match) = game.series.tournament_match {
+            let series_winner = if game.series.p1_wins > game.series.p2_wins {
+                &game.p1.0
+            } else {
+                &game.p2.0
+            };
+
+            tournament::record_result(db, state, tournament_match, series_winner).await?;
+        }
+
+        friends::broadcast_presence(db, state, &game.p1.0).await;
+        friends::broadcast_presence(db, state, &game.p2.0).await;
+    } else {
+        // the first turn alternates between the games of a series
+        game.series.first_turn = if game.series.first_turn == game.p1.0 {
+            game.p2.0.clone()
+        } else {
+            game.p1.0.clone()
+        };
+
+        start_series_game(db, state, game.p1, game.p2, game.rules, game.series).await?;
+    }
+
+    Ok(())
+}
+
+/// Ends a game without a winner or rating change, the rest of its series
+/// is not played.
+pub async fn abort_game(
+    db: &Db,
+    state: &mut State,
+    game_id: u64,
+    reason: &str,
+) -> anyhow::Result<()> {
+    let index = state
+        .games
+        .iter()
+        .position(|x| x.id == game_id)
+        .ok_or_else(|| anyhow::Error::msg("Game does not exist"))?;
+
+    if state.games[index].series.tournament_match.is_some() {
+        return Err(anyhow::Error::msg(
+            "Tournament games must be ended with a winner",
+        ));
+    }
+
+    let game = state.games.remove(index);
+    chat::clear_mutes(state, &game.p1.0, &game.p2.0);
+    db.abort_game(game.id).await?;
+
+    let notif = Message::text(
+        serde_json::to_string(&GameAbortedNotification {
+            id: String::from("gameaborted"),
+            reason: String::from(reason),
+        })
+        .unwrap(),
+    );
+
+    // either player may have left already
+    let _ = game.p1.1.send(notif.clone());
+    let _ = game.p2.1.send(notif);
+
+    friends::broadcast_presence(db, state, &game.p1.0).await;
+    friends::broadcast_presence(db, state, &game.p2.0).await;
+
+    Ok(())
+}
+
+pub fn game(
+    request: RequestInfo,
+    db: Arc<Db>,
+    state: Arc<Mutex<State>>,
+    limiter: Arc<LoginLimiter>,
+    chat_limiter: Arc<ChatLimiter>,
+    ws: warp::ws::Ws,
+) -> impl Reply {
+    ws.on_upgrade(move |websocket| handle(request, db, state, limiter, chat_limiter, websocket))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn series(best_of: u32, p1_wins: u32, p2_wins: u32) -> Series {
+        Series {
+            best_of,
+            p1_wins,
+            p2_wins,
+            first_turn: String::from("p1"),
+            tournament_match: None,
+            casual: false,
+        }
+    }
+
+    #[test]
+    fn disconnected_players_are_present_while_in_a_queue() {
+        let (tx, _rx) = mpsc::unbounded_channel();
+        let mut state = State::new();
+        assert!(!state.is_present("alice"));
+
+        state
+            .pending_matches
+            .insert(RuleSet::default(), (String::from("alice"), tx.clone()));
+        assert!(state.is_present("alice"));
+        assert!(!state.is_present("bob"));
+
+        state.pending_matches.clear();
+        state.rooms.insert(
+            String::from("ABCDEF"),
+            Room {
+                host: (String::from("alice"), tx.clone()),
+                rules: RuleSet::default(),
+            },
+        );
+        assert!(state.is_present("alice"));
+
+        state.rooms.clear();
+        state.challenges.push(Challenge {
+            id: 1,
+            challenger: (String::from("bob"), tx),
+            target: String::from("alice"),
+            rules: RuleSet::default(),
+            ranked: false,
+        });
+        assert!(state.is_present("alice"));
+    }
+
+    #[test]
+    fn single_game_is_decided_by_one_win() {
+        assert!(!series(1, 0, 0).is_decided());
+        assert!(series(1, 1, 0).is_decided());
+        assert!(series(1, 0, 1).is_decided());
+    }
+
+    #[test]
+    fn series_is_decided_by_majority() {
+        assert!(!series(3, 1, 0).is_decided());
+        assert!(!series(3, 1, 1).is_decided());
+        assert!(series(3, 2, 1).is_decided());
+        assert!(!series(7, 3, 3).is_decided());
+        assert!(series(7, 0, 4).is_decided());
+    }
 }
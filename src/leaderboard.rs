@@ -1,7 +1,661 @@
-use crate::db::Db;
-use std::sync::Arc;
+use crate::db::{Db, LeaderboardEntry, LeaderboardFilter, User};
+use futures_util::{stream, StreamExt};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{hash_map::DefaultHasher, HashMap},
+    hash::{Hash, Hasher},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use tokio::sync::broadcast::{self, error::RecvError};
+use warp::{
+    http::StatusCode,
+    reply::{Reply, Response},
+    sse::Event,
+};
 
-pub async fn leaderboard(db: Arc<Db>) -> String {
-    let leaderboards = db.get_leaderboard().await;
-    serde_json::to_string(&leaderboards).unwrap()
+pub const DEFAULT_PAGE_SIZE: u32 = 50;
+pub const MAX_PAGE_SIZE: u32 = 100;
+pub const DEFAULT_AROUND: u32 = 5;
+pub const MAX_AROUND: u32 = 25;
+pub const LEADERBOARD_CACHE_TTL: Duration = Duration::from_secs(30);
+pub const DEFAULT_STREAM_TOP: u32 = 10;
+pub const MAX_STREAM_TOP: u32 = 100;
+pub const STREAM_CAPACITY: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum CachedBoard {
+    /// All-time page holding the given rating range, `None` when empty.
+    AllTime(Option<(i32, i32)>),
+    Windowed,
+    Season,
+}
+
+#[derive(Debug)]
+struct CachedPage {
+    body: String,
+    etag: String,
+    board: CachedBoard,
+    cached_at: Instant,
+}
+
+/// Serialized leaderboard pages, dropped after a TTL or as soon as a rating
+/// change could alter them.
+#[derive(Debug)]
+pub struct LeaderboardCache {
+    ttl: Duration,
+    pages: Mutex<HashMap<String, CachedPage>>,
+}
+
+impl LeaderboardCache {
+    pub fn new(ttl: Duration) -> Self {
+        Self {
+            ttl,
+            pages: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Returns the body and ETag of a cached page.
+    pub fn get(&self, key: &str) -> Option<(String, String)> {
+        let mut pages = self.pages.lock().unwrap();
+        match pages.get(key) {
+            Some(page) if page.cached_at.elapsed() < self.ttl => {
+                Some((page.body.clone(), page.etag.clone()))
+            }
+            Some(_) => {
+                pages.remove(key);
+                None
+            }
+            None => None,
+        }
+    }
+
+    /// Caches a page and returns its ETag.
+    pub fn insert(&self, key: String, body: String, board: CachedBoard) -> String {
+        let mut hasher = DefaultHasher::new();
+        body.hash(&mut hasher);
+        let etag = format!("\"{:016x}\"", hasher.finish());
+
+        let mut pages = self.pages.lock().unwrap();
+        pages.retain(|_, x| x.cached_at.elapsed() < self.ttl);
+        pages.insert(
+            key,
+            CachedPage {
+                body,
+                etag: etag.clone(),
+                board,
+                cached_at: Instant::now(),
+            },
+        );
+
+        etag
+    }
+
+    /// Drops the pages a rating moving from `before` to `after` can change:
+    /// every rolling window, and the all-time pages whose rating range the
+    /// player entered, left or moved across.
+    pub fn invalidate_rating_change(&self, before: i32, after: i32) {
+        let (low, high) = (before.min(after), before.max(after));
+        self.pages.lock().unwrap().retain(|_, x| match x.board {
+            CachedBoard::AllTime(Some((min_elo, max_elo))) => low > max_elo || high < min_elo,
+            CachedBoard::AllTime(None) | CachedBoard::Windowed => false,
+            CachedBoard::Season => true,
+        });
+    }
+
+    pub fn clear(&self) {
+        self.pages.lock().unwrap().clear();
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaderboardError {
+    pub err: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum Window {
+    Daily,
+    Weekly,
+    Monthly,
+}
+
+impl Window {
+    pub fn days(&self) -> u32 {
+        match self {
+            Window::Daily => 1,
+            Window::Weekly => 7,
+            Window::Monthly => 30,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum WindowSort {
+    #[default]
+    Rating,
+    Wins,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StreamQuery {
+    /// Only changes within this many top ranks are pushed.
+    pub top: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct RankChange {
+    pub username: String,
+    pub elo_points: i32,
+    /// `None` when the player dropped out of the tracked ranks.
+    pub rank: Option<i64>,
+    pub previous_rank: Option<i64>,
+}
+
+impl RankChange {
+    fn within(&self, top: u32) -> bool {
+        let top = top as i64;
+        self.rank.is_some_and(|x| x <= top) || self.previous_rank.is_some_and(|x| x <= top)
+    }
+}
+
+/// Broadcasts how the top of the all-time leaderboard moved after ratings
+/// changed, for `/leaderboard/stream` subscribers.
+#[derive(Debug)]
+pub struct LeaderboardStream {
+    sender: broadcast::Sender<Arc<Vec<RankChange>>>,
+    snapshot: Mutex<Option<Vec<LeaderboardEntry>>>,
+}
+
+impl Default for LeaderboardStream {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl LeaderboardStream {
+    pub fn new() -> Self {
+        Self {
+            sender: broadcast::channel(STREAM_CAPACITY).0,
+            snapshot: Mutex::new(None),
+        }
+    }
+
+    pub fn subscribe(&self) -> broadcast::Receiver<Arc<Vec<RankChange>>> {
+        self.sender.subscribe()
+    }
+
+    /// Diffs the current top ranks against the last published ones and
+    /// pushes the differences. The first call only takes the snapshot.
+    pub async fn publish(&self, db: &Db) -> anyhow::Result<()> {
+        let filter = LeaderboardFilter {
+            country: None,
+            min_games: 0,
+            offset: 0,
+            limit: MAX_STREAM_TOP,
+        };
+
+        let (entries, _) = db.get_leaderboard(&filter).await?;
+
+        let changes = {
+            let mut snapshot = self.snapshot.lock().unwrap();
+            let changes = snapshot.as_ref().map(|x| rank_changes(x, &entries));
+            *snapshot = Some(entries);
+            changes.unwrap_or_default()
+        };
+
+        if !changes.is_empty() {
+            // sending only fails without subscribers
+            self.sender.send(Arc::new(changes)).ok();
+        }
+
+        Ok(())
+    }
+}
+
+fn rank_changes(previous: &[LeaderboardEntry], current: &[LeaderboardEntry]) -> Vec<RankChange> {
+    let mut changes: Vec<RankChange> = current
+        .iter()
+        .filter_map(|entry| {
+            let before = previous.iter().find(|x| x.username == entry.username);
+            if before.is_some_and(|x| x.rank == entry.rank && x.elo_points == entry.elo_points) {
+                return None;
+            }
+
+            Some(RankChange {
+                username: entry.username.clone(),
+                elo_points: entry.elo_points,
+                rank: Some(entry.rank),
+                previous_rank: before.map(|x| x.rank),
+            })
+        })
+        .collect();
+
+    changes.extend(
+        previous
+            .iter()
+            .filter(|x| current.iter().all(|entry| entry.username != x.username))
+            .map(|x| RankChange {
+                username: x.username.clone(),
+                elo_points: x.elo_points,
+                rank: None,
+                previous_rank: Some(x.rank),
+            }),
+    );
+
+    changes
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaderboardQuery {
+    /// Archived final standings of a past season.
+    pub season: Option<i32>,
+    /// Rating gained or games won over a rolling window instead of all time.
+    pub window: Option<Window>,
+    #[serde(default)]
+    pub sort: WindowSort,
+    pub country: Option<String>,
+    pub min_games: Option<u32>,
+    pub offset: Option<u32>,
+    pub limit: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct LeaderboardPage<T> {
+    pub total: i64,
+    pub offset: u32,
+    pub limit: u32,
+    pub entries: Vec<T>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AroundQuery {
+    /// Players shown above and below.
+    pub k: Option<u32>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct AroundPlayer {
+    pub username: String,
+    pub rank: i64,
+    pub country_id: String,
+    pub country_rank: i64,
+    pub entries: Vec<LeaderboardEntry>,
+}
+
+impl LeaderboardQuery {
+    pub fn filter(&self) -> anyhow::Result<LeaderboardFilter> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        if limit == 0 || limit > MAX_PAGE_SIZE {
+            return Err(anyhow::Error::msg(format!(
+                "Page size must be between 1 to {} entries",
+                MAX_PAGE_SIZE
+            )));
+        }
+
+        Ok(LeaderboardFilter {
+            country: self.country.as_ref().map(|x| x.to_ascii_uppercase()),
+            min_games: self.min_games.unwrap_or(0),
+            offset: self.offset.unwrap_or(0),
+            limit,
+        })
+    }
+}
+
+fn to_json<T: Serialize>(result: anyhow::Result<T>) -> String {
+    match result {
+        Ok(value) => serde_json::to_string(&value).unwrap(),
+        Err(error) => serde_json::to_string(&LeaderboardError {
+            err: error.to_string(),
+        })
+        .unwrap(),
+    }
+}
+
+fn etag_matches(if_none_match: Option<&str>, etag: &str) -> bool {
+    if_none_match.is_some_and(|x| {
+        x.split(',')
+            .map(|x| x.trim().trim_start_matches("W/"))
+            .any(|x| x == etag || x == "*")
+    })
+}
+
+pub async fn leaderboard(
+    query: LeaderboardQuery,
+    if_none_match: Option<String>,
+    db: Arc<Db>,
+) -> Response {
+    let filter = match query.filter() {
+        Ok(filter) => filter,
+        Err(error) => return to_json::<()>(Err(error)).into_response(),
+    };
+
+    let key = format!(
+        "{:?}|{:?}|{:?}|{:?}",
+        query.season, query.window, query.sort, filter
+    );
+
+    let (body, etag) = match db.leaderboard_cache.get(&key) {
+        Some(page) => page,
+        None => match load_page(&query, &filter, &db).await {
+            Ok((body, board)) => {
+                let etag = db.leaderboard_cache.insert(key, body.clone(), board);
+                (body, etag)
+            }
+            Err(error) => return to_json::<()>(Err(error)).into_response(),
+        },
+    };
+
+    if etag_matches(if_none_match.as_deref(), &etag) {
+        let reply = warp::reply::with_status(warp::reply(), StatusCode::NOT_MODIFIED);
+        return warp::reply::with_header(reply, "ETag", etag).into_response();
+    }
+
+    warp::reply::with_header(body, "ETag", etag).into_response()
+}
+
+async fn load_page(
+    query: &LeaderboardQuery,
+    filter: &LeaderboardFilter,
+    db: &Db,
+) -> anyhow::Result<(String, CachedBoard)> {
+    if let Some(window) = query.window {
+        let by_wins = query.sort == WindowSort::Wins;
+        let (entries, total) = db
+            .get_windowed_leaderboard(window.days(), by_wins, filter)
+            .await?;
+        let page = LeaderboardPage {
+            total,
+            offset: filter.offset,
+            limit: filter.limit,
+            entries,
+        };
+
+        Ok((serde_json::to_string(&page)?, CachedBoard::Windowed))
+    } else if let Some(season) = query.season {
+        let (entries, total) = db.get_season_leaderboard(season, filter).await?;
+        let page = LeaderboardPage {
+            total,
+            offset: filter.offset,
+            limit: filter.limit,
+            entries,
+        };
+
+        Ok((serde_json::to_string(&page)?, CachedBoard::Season))
+    } else {
+        let (entries, total) = db.get_leaderboard(filter).await?;
+        let range =
+            entries
+                .iter()
+                .map(|x| x.elo_points)
+                .fold(None, |range: Option<(i32, i32)>, x| match range {
+                    Some((min, max)) => Some((min.min(x), max.max(x))),
+                    None => Some((x, x)),
+                });
+        let page = LeaderboardPage {
+            total,
+            offset: filter.offset,
+            limit: filter.limit,
+            entries,
+        };
+
+        Ok((serde_json::to_string(&page)?, CachedBoard::AllTime(range)))
+    }
+}
+
+pub async fn me(user: User, query: AroundQuery, db: Arc<Db>) -> String {
+    to_json(around_user(user, query, &db).await)
+}
+
+pub async fn around(username: String, query: AroundQuery, db: Arc<Db>) -> String {
+    let result = async {
+        let user = db.get_user_by_name(&username).await?;
+        around_user(user, query, &db).await
+    }
+    .await;
+
+    to_json(result)
+}
+
+async fn around_user(user: User, query: AroundQuery, db: &Db) -> anyhow::Result<AroundPlayer> {
+    let k = query.k.unwrap_or(DEFAULT_AROUND);
+    if k > MAX_AROUND {
+        return Err(anyhow::Error::msg(format!(
+            "Can show at most {} players around",
+            MAX_AROUND
+        )));
+    }
+
+    let rank = db.get_rank(&user).await?;
+    let offset = (rank.position as u32).saturating_sub(k);
+    let filter = LeaderboardFilter {
+        country: None,
+        min_games: 0,
+        offset,
+        limit: rank.position as u32 - offset + k + 1,
+    };
+
+    let (entries, _) = db.get_leaderboard(&filter).await?;
+
+    Ok(AroundPlayer {
+        username: user.username,
+        rank: rank.rank,
+        country_id: user.country_id,
+        country_rank: rank.country_rank,
+        entries,
+    })
+}
+
+pub async fn stream(query: StreamQuery, db: Arc<Db>) -> Response {
+    let top = query
+        .top
+        .unwrap_or(DEFAULT_STREAM_TOP)
+        .clamp(1, MAX_STREAM_TOP);
+    let receiver = db.leaderboard_stream.subscribe();
+
+    let filter = LeaderboardFilter {
+        country: None,
+        min_games: 0,
+        offset: 0,
+        limit: top,
+    };
+
+    let entries = match db.get_leaderboard(&filter).await {
+        Ok((entries, _)) => entries,
+        Err(error) => return to_json::<()>(Err(error)).into_response(),
+    };
+
+    let snapshot =
+        stream::once(async move { Event::default().event("snapshot").json_data(entries) });
+    let changes = stream::unfold(receiver, move |mut receiver| async move {
+        loop {
+            let changes: Vec<RankChange> = match receiver.recv().await {
+                Ok(changes) => changes.iter().filter(|x| x.within(top)).cloned().collect(),
+                Err(RecvError::Lagged(skipped)) => {
+                    log::warn!("Leaderboard stream subscriber skipped {} updates", skipped);
+                    continue;
+                }
+                Err(RecvError::Closed) => return None,
+            };
+
+            if !changes.is_empty() {
+                let event = Event::default().event("rankchange").json_data(changes);
+                return Some((event, receiver));
+            }
+        }
+    });
+
+    warp::sse::reply(warp::sse::keep_alive().stream(snapshot.chain(changes))).into_response()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn query(limit: Option<u32>) -> LeaderboardQuery {
+        LeaderboardQuery {
+            season: None,
+            window: None,
+            sort: WindowSort::Rating,
+            country: Some(String::from("de")),
+            min_games: None,
+            offset: Some(100),
+            limit,
+        }
+    }
+
+    #[test]
+    fn filter_uses_defaults() {
+        let filter = query(None).filter().unwrap();
+        assert_eq!(
+            filter,
+            LeaderboardFilter {
+                country: Some(String::from("DE")),
+                min_games: 0,
+                offset: 100,
+                limit: DEFAULT_PAGE_SIZE,
+            }
+        );
+    }
+
+    #[test]
+    fn filter_rejects_page_size() {
+        assert!(query(Some(0)).filter().is_err());
+        assert!(query(Some(MAX_PAGE_SIZE + 1)).filter().is_err());
+        assert!(query(Some(MAX_PAGE_SIZE)).filter().is_ok());
+    }
+
+    #[test]
+    fn etag_matches_if_none_match() {
+        let etag = "\"0123456789abcdef\"";
+        assert!(etag_matches(Some(etag), etag));
+        assert!(etag_matches(Some("W/\"0123456789abcdef\""), etag));
+        assert!(etag_matches(Some("\"other\", \"0123456789abcdef\""), etag));
+        assert!(etag_matches(Some("*"), etag));
+        assert!(!etag_matches(Some("\"other\""), etag));
+        assert!(!etag_matches(None, etag));
+    }
+
+    #[test]
+    fn cache_returns_inserted_page() {
+        let cache = LeaderboardCache::new(LEADERBOARD_CACHE_TTL);
+        let etag = cache.insert(
+            String::from("page"),
+            String::from("body"),
+            CachedBoard::Season,
+        );
+
+        assert_eq!(cache.get("page"), Some((String::from("body"), etag)));
+        assert_eq!(cache.get("other"), None);
+    }
+
+    #[test]
+    fn cache_expires_pages() {
+        let cache = LeaderboardCache::new(Duration::ZERO);
+        cache.insert(
+            String::from("page"),
+            String::from("body"),
+            CachedBoard::Season,
+        );
+
+        assert_eq!(cache.get("page"), None);
+    }
+
+    #[test]
+    fn rating_change_invalidates_affected_pages() {
+        let cache = LeaderboardCache::new(LEADERBOARD_CACHE_TTL);
+        let pages = [
+            ("top", CachedBoard::AllTime(Some((1500, 2000)))),
+            ("middle", CachedBoard::AllTime(Some((1200, 1499)))),
+            ("bottom", CachedBoard::AllTime(Some((800, 1199)))),
+            ("empty", CachedBoard::AllTime(None)),
+            ("weekly", CachedBoard::Windowed),
+            ("season", CachedBoard::Season),
+        ];
+        for (key, board) in pages {
+            cache.insert(String::from(key), String::new(), board);
+        }
+
+        cache.invalidate_rating_change(1490, 1505);
+
+        assert!(cache.get("top").is_none());
+        assert!(cache.get("middle").is_none());
+        assert!(cache.get("bottom").is_some());
+        assert!(cache.get("empty").is_none());
+        assert!(cache.get("weekly").is_none());
+        assert!(cache.get("season").is_some());
+    }
+
+    fn entry(rank: i64, username: &str, elo_points: i32) -> LeaderboardEntry {
+        LeaderboardEntry {
+            rank,
+            username: String::from(username),
+            elo_points,
+            country_id: String::from("DE"),
+            profile_picture_url: String::new(),
+            games_played: 10,
+        }
+    }
+
+    #[test]
+    fn rank_changes_skip_unchanged_players() {
+        let previous = [entry(1, "alice", 1600), entry(2, "bob", 1500)];
+        assert!(rank_changes(&previous, &previous).is_empty());
+    }
+
+    #[test]
+    fn rank_changes_report_moves() {
+        let previous = [
+            entry(1, "alice", 1600),
+            entry(2, "bob", 1500),
+            entry(3, "carol", 1400),
+        ];
+        let current = [
+            entry(1, "bob", 1615),
+            entry(2, "alice", 1600),
+            entry(3, "dave", 1420),
+        ];
+
+        let changes = rank_changes(&previous, &current);
+        let changes: Vec<_> = changes
+            .iter()
+            .map(|x| (x.username.as_str(), x.rank, x.previous_rank))
+            .collect();
+
+        assert_eq!(
+            changes,
+            vec![
+                ("bob", Some(1), Some(2)),
+                ("alice", Some(2), Some(1)),
+                ("dave", Some(3), None),
+                ("carol", None, Some(3)),
+            ]
+        );
+    }
+
+    #[test]
+    fn rank_changes_report_rating_at_same_rank() {
+        let previous = [entry(1, "alice", 1600)];
+        let current = [entry(1, "alice", 1615)];
+
+        let changes = rank_changes(&previous, &current);
+        assert_eq!(changes.len(), 1);
+        assert_eq!(changes[0].elo_points, 1615);
+        assert!(changes[0].within(1));
+    }
+
+    #[test]
+    fn rank_change_within_top() {
+        let change = RankChange {
+            username: String::from("alice"),
+            elo_points: 1600,
+            rank: None,
+            previous_rank: Some(10),
+        };
+
+        assert!(change.within(10));
+        assert!(!change.within(9));
+    }
 }
@@ -1,14 +1,36 @@
 use std::sync::Arc;
 
+use authentication::Role;
+use avatar::{BlobStore, LocalBlobStore};
 use db::Db;
 use game::State;
+use mail::Mailer;
+use rate_limit::{ChatLimiter, LoginLimiter, MemoryStore};
 use tokio::sync::Mutex;
 use warp::Filter;
 
+pub mod account;
+pub mod admin;
+pub mod audit;
 pub mod authentication;
+pub mod avatar;
+pub mod challenge;
+pub mod chat;
 pub mod db;
+pub mod friends;
 pub mod game;
 pub mod leaderboard;
+pub mod mail;
+pub mod password_reset;
+pub mod profanity;
+pub mod profile;
+pub mod rate_limit;
+pub mod rules;
+pub mod season;
+pub mod stats;
+pub mod tournament;
+pub mod two_factor;
+pub mod username;
 
 #[tokio::main]
 async fn main() {
@@ -23,9 +45,12 @@ async fn main() {
             "User-Agent",
             "Content-Type",
             "Content-Length",
+            "Authorization",
+            "If-None-Match",
             "Access-Control-Request-Method",
             "Access-Control-Request-Headers",
         ])
+        .expose_headers(["ETag"])
         .build();
 
     let db_cloned: Arc<Db> = db.clone();
@@ -36,26 +61,428 @@ async fn main() {
         .and(warp::any().map(move || db_cloned.clone()))
         .then(authentication::register);
 
+    let limiter = Arc::new(LoginLimiter::new(Box::<MemoryStore>::default()));
+
     let db_cloned: Arc<Db> = db.clone();
+    let limiter_cloned = limiter.clone();
     let login_route = warp::path("login")
         .and(warp::post())
+        .and(rate_limit::limit_ip(limiter.clone()))
+        .and(audit::request_info())
         .and(warp::body::content_length_limit(1024 * 16))
         .and(warp::body::json())
         .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || limiter_cloned.clone()))
         .then(authentication::login);
 
+    let db_cloned: Arc<Db> = db.clone();
+    let limiter_cloned = limiter.clone();
+    let login_two_factor_route = warp::path!("login" / "2fa")
+        .and(warp::post())
+        .and(rate_limit::limit_ip(limiter.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || limiter_cloned.clone()))
+        .then(two_factor::login);
+
     let db_cloned = db.clone();
-    let leaderboard_route = warp::path("leaderboard")
+    let leaderboard_route = warp::path!("leaderboard")
         .and(warp::get())
+        .and(warp::query::<leaderboard::LeaderboardQuery>())
+        .and(warp::header::optional::<String>("if-none-match"))
         .and(warp::any().map(move || db_cloned.clone()))
         .then(leaderboard::leaderboard);
 
+    let db_cloned = db.clone();
+    let leaderboard_me_route = warp::path!("leaderboard" / "me")
+        .and(warp::get())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::query::<leaderboard::AroundQuery>())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(leaderboard::me);
+
+    let db_cloned = db.clone();
+    let leaderboard_stream_route = warp::path!("leaderboard" / "stream")
+        .and(warp::get())
+        .and(warp::query::<leaderboard::StreamQuery>())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(leaderboard::stream);
+
+    let db_cloned = db.clone();
+    let leaderboard_around_route = warp::path!("leaderboard" / "around" / String)
+        .and(warp::get())
+        .and(warp::query::<leaderboard::AroundQuery>())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(leaderboard::around);
+
+    let db_cloned = db.clone();
+    let seasons_route = warp::path!("seasons")
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(season::seasons);
+
+    let db_cloned = db.clone();
+    let rollover_route = warp::path!("seasons")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Admin))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(season::rollover);
+
+    let db_cloned = db.clone();
+    let rewards_route = warp::path!("users" / String / "rewards")
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(season::rewards);
+
+    let db_cloned = db.clone();
+    let update_profile_route = warp::path!("me")
+        .and(warp::patch())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(profile::update);
+
+    let blob_store = Arc::new(LocalBlobStore::from_env());
+    let avatars_route = warp::path("avatars").and(warp::fs::dir(blob_store.root.clone()));
+
+    let db_cloned = db.clone();
+    let blob_store: Arc<dyn BlobStore> = blob_store;
+    let blob_store_cloned = blob_store.clone();
+    let upload_avatar_route = warp::path!("me" / "avatar")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::multipart::form().max_length(avatar::MAX_UPLOAD_BYTES))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || blob_store_cloned.clone()))
+        .then(avatar::upload);
+
+    let mailer: Arc<dyn Mailer> = Arc::from(mail::from_env().unwrap());
+
+    let db_cloned = db.clone();
+    let request_reset_route = warp::path!("password-reset" / "request")
+        .and(warp::post())
+        .and(rate_limit::limit_ip(limiter.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || mailer.clone()))
+        .then(password_reset::request);
+
+    let db_cloned = db.clone();
+    let confirm_reset_route = warp::path!("password-reset" / "confirm")
+        .and(warp::post())
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(password_reset::confirm);
+
+    let db_cloned = db.clone();
+    let change_password_route = warp::path!("me" / "password")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(account::change_password);
+
+    let db_cloned = db.clone();
+    let two_factor_setup_route = warp::path!("me" / "2fa" / "setup")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(two_factor::setup);
+
+    let db_cloned = db.clone();
+    let two_factor_enable_route = warp::path!("me" / "2fa" / "enable")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(two_factor::enable);
+
+    let db_cloned = db.clone();
+    let two_factor_disable_route = warp::path!("me" / "2fa" / "disable")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(two_factor::disable);
+
+    let db_cloned = db.clone();
+    let recovery_codes_route = warp::path!("me" / "2fa" / "recovery-codes")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(two_factor::regenerate_recovery_codes);
+
+    let db_cloned = db.clone();
+    let blob_store_cloned = blob_store.clone();
+    let delete_account_route = warp::path!("me")
+        .and(warp::delete())
+        .and(authentication::authenticated(db.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || blob_store_cloned.clone()))
+        .then(account::delete);
+
+    let db_cloned = db.clone();
+    let guest_route = warp::path!("guest")
+        .and(warp::post())
+        .and(rate_limit::limit_ip(limiter.clone()))
+        .and(audit::request_info())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(account::guest);
+
+    let db_cloned = db.clone();
+    let claim_route = warp::path!("me" / "claim")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(account::claim);
+
+    let db_cloned = db.clone();
+    let export_route = warp::path!("me" / "export")
+        .and(warp::get())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(account::export);
+
+    let db_cloned = db.clone();
+    let profile_route = warp::path!("users" / String)
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(profile::profile);
+
+    let db_cloned = db.clone();
+    let stats_route = warp::path!("users" / String / "stats")
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(stats::stats);
+
+    let db_cloned = db.clone();
+    let rating_history_route = warp::path!("users" / String / "rating-history")
+        .and(warp::get())
+        .and(warp::query::<stats::RatingHistoryQuery>())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(stats::rating_history);
+
+    let db_cloned = db.clone();
+    let head_to_head_route = warp::path!("users" / String / "versus" / String)
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(stats::head_to_head);
+
     let state = Arc::new(Mutex::new(State::new()));
+
+    let db_cloned = db.clone();
+    let create_tournament_route = warp::path!("tournaments")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Admin))
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(tournament::create);
+
+    let db_cloned = db.clone();
+    let tournaments_route = warp::path!("tournaments")
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(tournament::list);
+
+    let db_cloned = db.clone();
+    let register_tournament_route = warp::path!("tournaments" / i32 / "register")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(tournament::register);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let start_tournament_route = warp::path!("tournaments" / i32 / "start")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Admin))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(tournament::start_now);
+
+    let db_cloned = db.clone();
+    let bracket_route = warp::path!("tournaments" / i32 / "bracket")
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(tournament::bracket);
+
+    let db_cloned = db.clone();
+    let standings_route = warp::path!("tournaments" / i32 / "standings")
+        .and(warp::get())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(tournament::standings);
+
+    let db_cloned = db.clone();
+    let admin_role_route = warp::path!("admin" / "users" / String / "role")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Admin))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(admin::set_role);
+
+    let db_cloned = db.clone();
+    let admin_ban_route = warp::path!("admin" / "users" / String / "ban")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(admin::ban);
+
+    let db_cloned = db.clone();
+    let admin_unban_route = warp::path!("admin" / "users" / String / "ban")
+        .and(warp::delete())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(audit::request_info())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(admin::unban);
+
+    let db_cloned = db.clone();
+    let admin_rating_route = warp::path!("admin" / "users" / String / "rating")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Admin))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(admin::adjust_rating);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let admin_rename_route = warp::path!("admin" / "users" / String / "rename")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(admin::rename);
+
+    let db_cloned = db.clone();
+    let admin_history_route = warp::path!("admin" / "users" / String)
+        .and(warp::get())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(admin::history);
+
+    let state_cloned = state.clone();
+    let admin_games_route = warp::path!("admin" / "games")
+        .and(warp::get())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(admin::games);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let admin_end_game_route = warp::path!("admin" / "games" / u64 / "end")
+        .and(warp::post())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(audit::request_info())
+        .and(warp::body::content_length_limit(1024 * 16))
+        .and(warp::body::json())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(admin::end_game);
+
+    let db_cloned = db.clone();
+    let admin_chat_route = warp::path!("admin" / "games" / u64 / "chat")
+        .and(warp::get())
+        .and(authentication::with_role(db.clone(), Role::Moderator))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(admin::chat);
+
+    let db_cloned = db.clone();
+    let audit_route = warp::path!("admin" / "audit")
+        .and(warp::get())
+        .and(authentication::with_role(db.clone(), Role::Admin))
+        .and(warp::query::<audit::AuditQuery>())
+        .and(warp::any().map(move || db_cloned.clone()))
+        .then(audit::events);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let friends_route = warp::path!("me" / "friends")
+        .and(warp::get())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(friends::list);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let friend_request_route = warp::path!("me" / "friends" / String)
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(friends::request);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let friend_accept_route = warp::path!("me" / "friends" / String / "accept")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(friends::accept);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let friend_decline_route = warp::path!("me" / "friends" / String / "decline")
+        .and(warp::post())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(friends::decline);
+
+    let db_cloned = db.clone();
+    let state_cloned = state.clone();
+    let friend_remove_route = warp::path!("me" / "friends" / String)
+        .and(warp::delete())
+        .and(authentication::authenticated(db.clone()))
+        .and(warp::any().map(move || db_cloned.clone()))
+        .and(warp::any().map(move || state_cloned.clone()))
+        .then(friends::remove);
+
+    let chat_limiter = Arc::new(ChatLimiter::new(Box::<MemoryStore>::default()));
+
     let db_cloned = db.clone();
     let state_cloned = state.clone();
     let game_route = warp::path("game")
+        .and(rate_limit::limit_ip(limiter.clone()))
+        .and(audit::request_info())
         .and(warp::any().map(move || db_cloned.clone()))
         .and(warp::any().map(move || state_cloned.clone()))
+        .and(warp::any().map(move || limiter.clone()))
+        .and(warp::any().map(move || chat_limiter.clone()))
         .and(warp::ws())
         .map(game::game);
 
@@ -64,12 +491,80 @@ async fn main() {
     pretty_env_logger::init_custom_env("RUST_APP_LOG");
     log::info!("Drinkard Backend");
 
+    // boxed in groups, a single chain this long overflows the trait solver
+    let account_routes = change_password_route
+        .or(two_factor_setup_route)
+        .or(two_factor_enable_route)
+        .or(two_factor_disable_route)
+        .or(recovery_codes_route)
+        .or(request_reset_route)
+        .or(confirm_reset_route)
+        .or(delete_account_route)
+        .or(export_route)
+        .or(guest_route)
+        .or(claim_route)
+        .boxed();
+
+    let admin_routes = admin_role_route
+        .or(admin_ban_route)
+        .or(admin_unban_route)
+        .or(admin_rating_route)
+        .or(admin_rename_route)
+        .or(admin_history_route)
+        .or(admin_games_route)
+        .or(admin_end_game_route)
+        .or(admin_chat_route)
+        .or(audit_route)
+        .boxed();
+
+    let friend_routes = friends_route
+        .or(friend_request_route)
+        .or(friend_accept_route)
+        .or(friend_decline_route)
+        .or(friend_remove_route)
+        .boxed();
+
     let routes = register_route
+        .or(login_two_factor_route)
         .or(login_route)
         .or(leaderboard_route)
+        .or(leaderboard_me_route)
+        .or(leaderboard_stream_route)
+        .or(leaderboard_around_route)
+        .or(seasons_route)
+        .or(rollover_route)
+        .or(rewards_route)
+        .or(update_profile_route)
+        .or(upload_avatar_route)
+        .or(account_routes)
+        .or(avatars_route)
+        .or(profile_route)
+        .or(stats_route)
+        .or(rating_history_route)
+        .or(head_to_head_route)
+        .or(create_tournament_route)
+        .or(tournaments_route)
+        .or(register_tournament_route)
+        .or(start_tournament_route)
+        .or(bracket_route)
+        .or(standings_route)
+        .or(admin_routes)
+        .or(friend_routes)
         .or(game_route)
+        .recover(authentication::handle_rejection)
         .with(cors)
         .with(warp::log("backend"));
 
+    username::backfill(&db).await;
+    admin::bootstrap_admins(&db).await;
+
+    if let Err(error) = db.leaderboard_stream.publish(&db).await {
+        log::error!("Failed to snapshot the leaderboard: {}", error);
+    }
+
+    tokio::task::spawn(tournament::run_scheduler(db.clone(), state.clone()));
+    tokio::task::spawn(audit::run_retention(db.clone()));
+    tokio::task::spawn(account::run_guest_retention(db.clone()));
+
     warp::serve(routes).run(([0, 0, 0, 0], 8000)).await;
 }
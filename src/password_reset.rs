@@ -0,0 +1,127 @@
+use crate::{
+    audit::{self, AuditAction, RequestInfo},
+    authentication::{self, AuthError},
+    db::Db,
+    mail::Mailer,
+};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::reply::Json;
+
+pub const RESET_TOKEN_VALID_MINUTES: i64 = 60;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResetRequestData {
+    pub email: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ResetConfirmData {
+    pub token: String,
+    pub new_password: String,
+}
+
+fn reset_link(token: &str) -> String {
+    format!(
+        "{}?token={}",
+        dotenv::var("PASSWORD_RESET_URL")
+            .unwrap_or(String::from("http://localhost:3000/reset-password")),
+        token
+    )
+}
+
+async fn send_reset(
+    request: &RequestInfo,
+    email: &str,
+    db: &Db,
+    mailer: &dyn Mailer,
+) -> anyhow::Result<()> {
+    let Some(user) = db.get_user_by_email(email).await? else {
+        return Ok(());
+    };
+    let Some(email) = user.email else {
+        return Ok(());
+    };
+
+    let token = authentication::generate_token();
+    db.insert_password_reset(
+        &authentication::hash_token(&token),
+        user.id,
+        RESET_TOKEN_VALID_MINUTES,
+    )
+    .await?;
+    audit::record(
+        db,
+        request,
+        AuditAction::PasswordResetRequested,
+        None,
+        Some(user.id),
+        None,
+    )
+    .await;
+
+    let body = format!(
+        "Hi {},\n\nsomeone asked to reset the password of your account. \
+         Open the link below within {} minutes to choose a new one:\n\n{}\n\n\
+         If that wasn't you, you can ignore this mail.",
+        user.username,
+        RESET_TOKEN_VALID_MINUTES,
+        reset_link(&token)
+    );
+
+    mailer.send(&email, "Reset your password", &body).await
+}
+
+/// Mails a reset token to the owner of the address. The reply is the same,
+/// and sent before looking the address up, whether or not an account uses
+/// it, so addresses can't be probed.
+pub async fn request(
+    request: RequestInfo,
+    data: ResetRequestData,
+    db: Arc<Db>,
+    mailer: Arc<dyn Mailer>,
+) -> Json {
+    tokio::task::spawn(async move {
+        let email = data.email.trim();
+        if let Err(error) = send_reset(&request, email, &db, mailer.as_ref()).await {
+            log::error!("Failed to send password reset: {}", error);
+        }
+    });
+
+    warp::reply::json(&AuthError {
+        err: String::new(),
+        code: None,
+    })
+}
+
+pub async fn confirm(request: RequestInfo, data: ResetConfirmData, db: Arc<Db>) -> Json {
+    let result = async {
+        authentication::validate_password(&data.new_password)?;
+        let user_id = db
+            .redeem_password_reset(&authentication::hash_token(&data.token), &data.new_password)
+            .await?;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::PasswordReset,
+            Some(user_id),
+            Some(user_id),
+            None,
+        )
+        .await;
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => warp::reply::json(&AuthError {
+            err: String::new(),
+            code: None,
+        }),
+        Err(err) => warp::reply::json(&AuthError {
+            err: err.to_string(),
+            code: None,
+        }),
+    }
+}
@@ -0,0 +1,181 @@
+use unicode_normalization::{char::is_combining_mark, UnicodeNormalization};
+
+/// Words rejected in names and masked in chat. Matched against whole words,
+/// lowercased, without accents and with common letter substitutions undone,
+/// so that names merely containing the letters, like "therapist", pass.
+pub const BLOCKED_WORDS: &[&str] = &[
+    "fuck", "shit", "cunt", "bitch", "whore", "slut", "nigger", "nigga", "faggot", "retard",
+    "rapist", "nazi", "hitler", "pussy", "penis", "vagina", "porn",
+];
+
+/// Endings a blocked word may carry and still count as that word.
+const SUFFIXES: &[&str] = &["", "s", "es", "er", "ers", "ed", "ing", "y", "ty", "o"];
+
+/// Undoes digits standing in for letters.
+fn fold(character: char) -> char {
+    match character {
+        '0' => 'o',
+        '1' => 'i',
+        '3' => 'e',
+        '4' => 'a',
+        '5' => 's',
+        '7' => 't',
+        x => x,
+    }
+}
+
+/// Splits the text into lowercased words. Symbols standing in for letters
+/// count as letters when a letter follows, and runs of single letters such
+/// as "f.u.c.k" are joined into one word. With `camel_case` a capital after
+/// a small letter also starts a new word.
+fn words(text: &str, camel_case: bool) -> Vec<String> {
+    let characters: Vec<char> = text.nfkd().filter(|x| !is_combining_mark(*x)).collect();
+
+    let mut words = Vec::new();
+    let mut word = String::new();
+    for (i, &character) in characters.iter().enumerate() {
+        let before_letter = characters.get(i + 1).is_some_and(|x| x.is_alphanumeric());
+        let character = match character {
+            '@' if before_letter => 'a',
+            '$' if before_letter => 's',
+            '!' if before_letter => 'i',
+            x if x.is_alphanumeric() => x,
+            _ => {
+                words.push(std::mem::take(&mut word));
+                continue;
+            }
+        };
+
+        let after_small = i > 0 && characters[i - 1].is_lowercase();
+        if camel_case && character.is_uppercase() && after_small {
+            words.push(std::mem::take(&mut word));
+        }
+
+        word.extend(character.to_lowercase().map(fold));
+    }
+    words.push(word);
+
+    let mut joined: Vec<String> = Vec::new();
+    let mut after_letter = false;
+    for word in words.into_iter().filter(|x| !x.is_empty()) {
+        let letter = word.chars().count() == 1;
+        match joined.last_mut() {
+            Some(last) if letter && after_letter => last.push_str(&word),
+            _ => joined.push(word),
+        }
+        after_letter = letter;
+    }
+
+    joined
+}
+
+fn is_blocked(word: &str) -> bool {
+    BLOCKED_WORDS.iter().any(|blocked| {
+        word.strip_prefix(blocked)
+            .is_some_and(|rest| SUFFIXES.contains(&rest))
+    })
+}
+
+pub fn contains_profanity(text: &str) -> bool {
+    words(text, false).iter().any(|x| is_blocked(x))
+        || words(text, true).iter().any(|x| is_blocked(x))
+}
+
+/// Replaces every word containing profanity with asterisks, including
+/// blocked words spelled out as single letters between spaces.
+pub fn mask(text: &str) -> String {
+    let words: Vec<&str> = text.split(' ').collect();
+    let mut masked: Vec<bool> = words.iter().map(|x| contains_profanity(x)).collect();
+
+    let single_letter = |word: &str| word.chars().filter(|x| x.is_alphanumeric()).count() == 1;
+    let mut start = 0;
+    while start < words.len() {
+        let end = start
+            + words[start..]
+                .iter()
+                .take_while(|x| single_letter(x))
+                .count();
+        if end - start > 1 && contains_profanity(&words[start..end].concat()) {
+            masked[start..end].fill(true);
+        }
+        start = end + 1;
+    }
+
+    words
+        .iter()
+        .zip(masked)
+        .map(|(word, masked)| {
+            if masked {
+                "*".repeat(word.chars().count())
+            } else {
+                String::from(*word)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn finds_profanity() {
+        let texts = [
+            "fuck",
+            "Fuck you",
+            "what the fuck!",
+            "fucking",
+            "shitty",
+            "nazis",
+            "rapist",
+            "porn",
+            "h1tler",
+            "sh!t",
+            "$hit",
+            "f.u.c.k",
+            "b_i_t_c_h",
+            "fück",
+            "xX_nazi_Xx",
+            "HitlerFan",
+        ];
+
+        for text in texts {
+            assert!(contains_profanity(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn allows_words_containing_blocked_letters() {
+        let texts = [
+            "pomelo",
+            "Pompey",
+            "tompom",
+            "therapist",
+            "Ignazio",
+            "Nazli",
+            "Scunthorpe",
+            "assassin",
+            "shiitake",
+            "cocktail",
+            "well played!",
+            "I am in",
+        ];
+
+        for text in texts {
+            assert!(!contains_profanity(text), "{}", text);
+        }
+    }
+
+    #[test]
+    fn masks_profane_words() {
+        assert_eq!(mask("well fuck that"), "well **** that");
+        assert_eq!(mask("sh!t happens"), "**** happens");
+        assert_eq!(mask("go f u c k off"), "go * * * * off");
+        assert_eq!(
+            mask("the therapist from Pompey"),
+            "the therapist from Pompey"
+        );
+        assert_eq!(mask("a b c"), "a b c");
+    }
+}
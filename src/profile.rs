@@ -0,0 +1,225 @@
+use crate::db::{Db, PlayerStats, SeasonStanding, User};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::reply::Json;
+
+pub const MAX_PROFILE_PICTURE_URL_LENGTH: usize = 1024;
+pub const MAX_EMAIL_LENGTH: usize = 254;
+
+/// ISO 3166-1 alpha-2 country codes.
+pub const COUNTRY_CODES: [&str; 249] = [
+    "AD", "AE", "AF", "AG", "AI", "AL", "AM", "AO", "AQ", "AR", "AS", "AT", "AU", "AW", "AX", "AZ",
+    "BA", "BB", "BD", "BE", "BF", "BG", "BH", "BI", "BJ", "BL", "BM", "BN", "BO", "BQ", "BR", "BS",
+    "BT", "BV", "BW", "BY", "BZ", "CA", "CC", "CD", "CF", "CG", "CH", "CI", "CK", "CL", "CM", "CN",
+    "CO", "CR", "CU", "CV", "CW", "CX", "CY", "CZ", "DE", "DJ", "DK", "DM", "DO", "DZ", "EC", "EE",
+    "EG", "EH", "ER", "ES", "ET", "FI", "FJ", "FK", "FM", "FO", "FR", "GA", "GB", "GD", "GE", "GF",
+    "GG", "GH", "GI", "GL", "GM", "GN", "GP", "GQ", "GR", "GS", "GT", "GU", "GW", "GY", "HK", "HM",
+    "HN", "HR", "HT", "HU", "ID", "IE", "IL", "IM", "IN", "IO", "IQ", "IR", "IS", "IT", "JE", "JM",
+    "JO", "JP", "KE", "KG", "KH", "KI", "KM", "KN", "KP", "KR", "KW", "KY", "KZ", "LA", "LB", "LC",
+    "LI", "LK", "LR", "LS", "LT", "LU", "LV", "LY", "MA", "MC", "MD", "ME", "MF", "MG", "MH", "MK",
+    "ML", "MM", "MN", "MO", "MP", "MQ", "MR", "MS", "MT", "MU", "MV", "MW", "MX", "MY", "MZ", "NA",
+    "NC", "NE", "NF", "NG", "NI", "NL", "NO", "NP", "NR", "NU", "NZ", "OM", "PA", "PE", "PF", "PG",
+    "PH", "PK", "PL", "PM", "PN", "PR", "PS", "PT", "PW", "PY", "QA", "RE", "RO", "RS", "RU", "RW",
+    "SA", "SB", "SC", "SD", "SE", "SG", "SH", "SI", "SJ", "SK", "SL", "SM", "SN", "SO", "SR", "SS",
+    "ST", "SV", "SX", "SY", "SZ", "TC", "TD", "TF", "TG", "TH", "TJ", "TK", "TL", "TM", "TN", "TO",
+    "TR", "TT", "TV", "TW", "TZ", "UA", "UG", "UM", "US", "UY", "UZ", "VA", "VC", "VE", "VG", "VI",
+    "VN", "VU", "WF", "WS", "YE", "YT", "ZA", "ZM", "ZW",
+];
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileError {
+    pub err: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ProfileUpdate {
+    pub country_id: Option<String>,
+    pub profile_picture_url: Option<String>,
+    /// An empty string removes the address.
+    pub email: Option<String>,
+}
+
+#[derive(Serialize)]
+pub struct Profile {
+    pub username: String,
+    pub elo_points: i32,
+    pub country_id: String,
+    pub profile_picture_url: String,
+    pub total_sips: i32,
+    pub stats: PlayerStats,
+    pub rewards: Vec<SeasonStanding>,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&ProfileError {
+        err: error.to_string(),
+    })
+}
+
+pub fn validate_country(country_id: &str) -> anyhow::Result<String> {
+    let country_id = country_id.trim().to_ascii_uppercase();
+    if !COUNTRY_CODES.contains(&country_id.as_str()) {
+        return Err(anyhow::Error::msg("Unknown ISO 3166 country code"));
+    }
+
+    Ok(country_id)
+}
+
+/// Accepts absolute http(s) URLs, or an empty string to clear the picture.
+pub fn validate_profile_picture_url(url: &str) -> anyhow::Result<String> {
+    let url = url.trim();
+    if url.is_empty() {
+        return Ok(String::new());
+    }
+
+    if url.len() > MAX_PROFILE_PICTURE_URL_LENGTH {
+        return Err(anyhow::Error::msg(format!(
+            "Profile picture URL must be at most {} characters",
+            MAX_PROFILE_PICTURE_URL_LENGTH
+        )));
+    }
+
+    match url::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {
+            Ok(parsed.to_string())
+        }
+        _ => Err(anyhow::Error::msg(
+            "Profile picture URL must be a http(s) URL",
+        )),
+    }
+}
+
+/// Loose sanity check, the address is only proven by receiving a mail.
+/// Returns `None` for an empty string.
+pub fn validate_email(email: &str) -> anyhow::Result<Option<String>> {
+    let email = email.trim();
+    if email.is_empty() {
+        return Ok(None);
+    }
+
+    let valid = email.len() <= MAX_EMAIL_LENGTH
+        && !email.contains(char::is_whitespace)
+        && match email.split_once('@') {
+            Some((local, domain)) => {
+                !local.is_empty() && !domain.contains('@') && domain.contains('.')
+            }
+            None => false,
+        };
+
+    if !valid {
+        return Err(anyhow::Error::msg("Invalid email address"));
+    }
+
+    Ok(Some(String::from(email)))
+}
+
+pub async fn update(user: User, update: ProfileUpdate, db: Arc<Db>) -> Json {
+    let result = async {
+        let country_id = update
+            .country_id
+            .as_deref()
+            .map(validate_country)
+            .transpose()?;
+        let profile_picture_url = update
+            .profile_picture_url
+            .as_deref()
+            .map(validate_profile_picture_url)
+            .transpose()?;
+        let email = update.email.as_deref().map(validate_email).transpose()?;
+
+        db.update_profile(
+            user.id,
+            country_id.as_deref(),
+            profile_picture_url.as_deref(),
+        )
+        .await?;
+
+        if let Some(email) = email {
+            db.update_email(user.id, email.as_deref()).await?;
+        }
+
+        get_profile(&db, &user.username).await
+    }
+    .await;
+
+    match result {
+        Ok(profile) => warp::reply::json(&profile),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn profile(username: String, db: Arc<Db>) -> Json {
+    match get_profile(&db, &username).await {
+        Ok(profile) => warp::reply::json(&profile),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn get_profile(db: &Db, username: &str) -> anyhow::Result<Profile> {
+    let user = db.get_user_by_name(username).await?;
+    let stats = db.get_player_stats(&user.username).await?;
+    let rewards = db.get_season_rewards(&user.username).await?;
+
+    Ok(Profile {
+        username: user.username,
+        elo_points: user.elo_points,
+        country_id: user.country_id,
+        profile_picture_url: user.profile_picture_url,
+        total_sips: user.total_sips,
+        stats,
+        rewards,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn country_codes_are_normalized() {
+        assert_eq!(validate_country(" de ").unwrap(), "DE");
+        assert!(validate_country("XX").is_err());
+        assert!(validate_country("DEU").is_err());
+    }
+
+    #[test]
+    fn profile_pictures_must_be_http_urls() {
+        assert_eq!(validate_profile_picture_url("  ").unwrap(), "");
+        assert_eq!(
+            validate_profile_picture_url("https://example.com/a.png").unwrap(),
+            "https://example.com/a.png"
+        );
+        for url in [
+            "javascript:alert(1)",
+            "ftp://example.com/a.png",
+            "example.com/a.png",
+            "data:image/png;base64,AAAA",
+        ] {
+            assert!(validate_profile_picture_url(url).is_err(), "{}", url);
+        }
+
+        let long = format!(
+            "https://example.com/{}",
+            "a".repeat(MAX_PROFILE_PICTURE_URL_LENGTH)
+        );
+        assert!(validate_profile_picture_url(&long).is_err());
+    }
+
+    #[test]
+    fn emails_get_a_sanity_check() {
+        assert_eq!(validate_email("").unwrap(), None);
+        assert_eq!(
+            validate_email(" alice@example.com ").unwrap().as_deref(),
+            Some("alice@example.com")
+        );
+        for email in [
+            "alice",
+            "@example.com",
+            "alice@localhost",
+            "a@b@c.com",
+            "al ice@example.com",
+        ] {
+            assert!(validate_email(email).is_err(), "{}", email);
+        }
+    }
+}
@@ -0,0 +1,461 @@
+use crate::authentication::AuthError;
+use std::{
+    collections::HashMap,
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    pin::Pin,
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+use warp::{
+    http::{header::RETRY_AFTER, StatusCode},
+    reject::Reject,
+    reply::{Reply, Response},
+    Filter, Rejection,
+};
+
+/// Attempts a single address may burst, refilled one per interval.
+pub const IP_BUCKET: Bucket = Bucket {
+    capacity: 20,
+    refill: Duration::from_secs(3),
+};
+/// Attempts against a single account, across every address.
+pub const USERNAME_BUCKET: Bucket = Bucket {
+    capacity: 10,
+    refill: Duration::from_secs(30),
+};
+/// Chat messages and emotes a player may burst during games.
+pub const CHAT_BUCKET: Bucket = Bucket {
+    capacity: 5,
+    refill: Duration::from_secs(2),
+};
+/// Failed logins within [`FAILURE_WINDOW`] that lock the account.
+pub const MAX_FAILURES: u32 = 5;
+pub const FAILURE_WINDOW: Duration = Duration::from_secs(15 * 60);
+pub const LOCKOUT_DURATION: Duration = Duration::from_secs(15 * 60);
+/// Every this many failures from one address are logged as suspicious.
+pub const SUSPICIOUS_IP_FAILURES: u32 = 20;
+
+const PRUNE_THRESHOLD: usize = 10_000;
+
+#[derive(Debug, Clone, Copy)]
+pub struct Bucket {
+    pub capacity: u32,
+    pub refill: Duration,
+}
+
+pub type LimitFuture<'a, T> = Pin<Box<dyn Future<Output = anyhow::Result<T>> + Send + 'a>>;
+
+/// Counters behind the limiter, kept in memory or in a backend shared
+/// between instances.
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from the bucket of `key`, returning how long to wait
+    /// for the next one when it is empty.
+    fn take<'a>(&'a self, key: &'a str, bucket: Bucket) -> LimitFuture<'a, Option<Duration>>;
+    /// Counts a failure and returns the failures of `key` within `window`.
+    fn add_failure<'a>(&'a self, key: &'a str, window: Duration) -> LimitFuture<'a, u32>;
+    fn clear_failures<'a>(&'a self, key: &'a str) -> LimitFuture<'a, ()>;
+    fn lock<'a>(&'a self, key: &'a str, duration: Duration) -> LimitFuture<'a, ()>;
+    /// Time left on the lock of `key`.
+    fn locked_for<'a>(&'a self, key: &'a str) -> LimitFuture<'a, Option<Duration>>;
+}
+
+#[derive(Debug)]
+struct BucketState {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl BucketState {
+    /// Refills the tokens gained since the last update and takes one.
+    fn take(&mut self, bucket: Bucket, now: Instant) -> Option<Duration> {
+        let refilled = (now - self.updated_at).as_secs_f64() / bucket.refill.as_secs_f64();
+        self.tokens = (self.tokens + refilled).min(bucket.capacity as f64);
+        self.updated_at = now;
+
+        if self.tokens >= 1.0 {
+            self.tokens -= 1.0;
+            None
+        } else {
+            Some(bucket.refill.mul_f64(1.0 - self.tokens))
+        }
+    }
+}
+
+#[derive(Debug)]
+struct Failures {
+    count: u32,
+    since: Instant,
+}
+
+#[derive(Debug, Default)]
+struct Counters {
+    buckets: HashMap<String, BucketState>,
+    failures: HashMap<String, Failures>,
+    locks: HashMap<String, Instant>,
+}
+
+/// Keeps every counter in this process.
+#[derive(Debug, Default)]
+pub struct MemoryStore {
+    counters: Mutex<Counters>,
+}
+
+impl MemoryStore {
+    /// Drops entries that would not limit anybody anymore.
+    fn prune(counters: &mut Counters, now: Instant) {
+        if counters.buckets.len() > PRUNE_THRESHOLD {
+            let idle = USERNAME_BUCKET.refill * USERNAME_BUCKET.capacity;
+            counters
+                .buckets
+                .retain(|_, bucket| now - bucket.updated_at < idle);
+        }
+        if counters.failures.len() > PRUNE_THRESHOLD {
+            counters
+                .failures
+                .retain(|_, failures| now - failures.since < FAILURE_WINDOW);
+        }
+        counters.locks.retain(|_, until| *until > now);
+    }
+}
+
+impl RateLimitStore for MemoryStore {
+    fn take<'a>(&'a self, key: &'a str, bucket: Bucket) -> LimitFuture<'a, Option<Duration>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut counters = self.counters.lock().unwrap();
+            Self::prune(&mut counters, now);
+
+            let state = counters
+                .buckets
+                .entry(String::from(key))
+                .or_insert(BucketState {
+                    tokens: bucket.capacity as f64,
+                    updated_at: now,
+                });
+
+            Ok(state.take(bucket, now))
+        })
+    }
+
+    fn add_failure<'a>(&'a self, key: &'a str, window: Duration) -> LimitFuture<'a, u32> {
+        Box::pin(async move {
+            let now = Instant::now();
+            let mut counters = self.counters.lock().unwrap();
+            let failures = counters
+                .failures
+                .entry(String::from(key))
+                .or_insert(Failures {
+                    count: 0,
+                    since: now,
+                });
+
+            if now - failures.since >= window {
+                failures.count = 0;
+                failures.since = now;
+            }
+            failures.count += 1;
+
+            Ok(failures.count)
+        })
+    }
+
+    fn clear_failures<'a>(&'a self, key: &'a str) -> LimitFuture<'a, ()> {
+        Box::pin(async move {
+            self.counters.lock().unwrap().failures.remove(key);
+            Ok(())
+        })
+    }
+
+    fn lock<'a>(&'a self, key: &'a str, duration: Duration) -> LimitFuture<'a, ()> {
+        Box::pin(async move {
+            self.counters
+                .lock()
+                .unwrap()
+                .locks
+                .insert(String::from(key), Instant::now() + duration);
+            Ok(())
+        })
+    }
+
+    fn locked_for<'a>(&'a self, key: &'a str) -> LimitFuture<'a, Option<Duration>> {
+        Box::pin(async move {
+            let now = Instant::now();
+            Ok(self
+                .counters
+                .lock()
+                .unwrap()
+                .locks
+                .get(key)
+                .filter(|until| **until > now)
+                .map(|until| *until - now))
+        })
+    }
+}
+
+#[derive(Debug)]
+pub struct TooManyRequests {
+    pub retry_after: Duration,
+}
+
+impl Reject for TooManyRequests {}
+
+/// Guards password checks against guessing, per address and per account.
+pub struct LoginLimiter {
+    store: Box<dyn RateLimitStore>,
+}
+
+fn ip_key(ip: IpAddr) -> String {
+    format!("ip:{}", ip)
+}
+
+fn user_key(username: &str) -> String {
+    format!("user:{}", username.to_lowercase())
+}
+
+impl LoginLimiter {
+    pub fn new(store: Box<dyn RateLimitStore>) -> Self {
+        Self { store }
+    }
+
+    /// Takes an attempt from the address' bucket.
+    pub async fn check_ip(&self, ip: IpAddr) -> Result<(), Duration> {
+        match self.store.take(&ip_key(ip), IP_BUCKET).await {
+            Ok(None) => Ok(()),
+            Ok(Some(retry_after)) => {
+                log::warn!("Rate limited login attempts from {}", ip);
+                Err(retry_after)
+            }
+            Err(error) => {
+                // an unavailable backend should not lock everybody out
+                log::error!("Rate limit store failed: {}", error);
+                Ok(())
+            }
+        }
+    }
+
+    /// Fails while the account is locked or its bucket is empty.
+    pub async fn check_user(&self, username: &str) -> Result<(), Duration> {
+        let key = user_key(username);
+        let result = async {
+            if let Some(locked_for) = self.store.locked_for(&key).await? {
+                return Ok(Some(locked_for));
+            }
+
+            self.store.take(&key, USERNAME_BUCKET).await
+        }
+        .await;
+
+        match result {
+            Ok(None) => Ok(()),
+            Ok(Some(retry_after)) => Err(retry_after),
+            Err(error) => {
+                log::error!("Rate limit store failed: {}", error);
+                Ok(())
+            }
+        }
+    }
+
+    /// Counts a wrong password, locking the account after [`MAX_FAILURES`].
+    pub async fn record_failure(&self, ip: Option<IpAddr>, username: &str) {
+        let key = user_key(username);
+        let result = async {
+            let failures = self.store.add_failure(&key, FAILURE_WINDOW).await?;
+            if failures >= MAX_FAILURES {
+                self.store.lock(&key, LOCKOUT_DURATION).await?;
+                self.store.clear_failures(&key).await?;
+                log::warn!(
+                    "Locked {} for {} minutes after {} failed logins, last from {:?}",
+                    username,
+                    LOCKOUT_DURATION.as_secs() / 60,
+                    failures,
+                    ip
+                );
+            }
+
+            if let Some(ip) = ip {
+                let failures = self.store.add_failure(&ip_key(ip), FAILURE_WINDOW).await?;
+                if failures.is_multiple_of(SUSPICIOUS_IP_FAILURES) {
+                    log::warn!(
+                        "{} failed logins from {} within {} minutes, last for {}",
+                        failures,
+                        ip,
+                        FAILURE_WINDOW.as_secs() / 60,
+                        username
+                    );
+                }
+            }
+
+            anyhow::Ok(())
+        }
+        .await;
+
+        if let Err(error) = result {
+            log::error!("Rate limit store failed: {}", error);
+        }
+    }
+
+    pub async fn record_success(&self, username: &str) {
+        if let Err(error) = self.store.clear_failures(&user_key(username)).await {
+            log::error!("Rate limit store failed: {}", error);
+        }
+    }
+}
+
+/// Keeps players from flooding their opponent's chat.
+pub struct ChatLimiter {
+    store: Box<dyn RateLimitStore>,
+}
+
+impl ChatLimiter {
+    pub fn new(store: Box<dyn RateLimitStore>) -> Self {
+        Self { store }
+    }
+
+    pub async fn check(&self, username: &str) -> Result<(), Duration> {
+        match self.store.take(&user_key(username), CHAT_BUCKET).await {
+            Ok(None) => Ok(()),
+            Ok(Some(retry_after)) => Err(retry_after),
+            Err(error) => {
+                log::error!("Rate limit store failed: {}", error);
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Rejects with [`TooManyRequests`] once the caller's address ran out of attempts.
+pub fn limit_ip(
+    limiter: Arc<LoginLimiter>,
+) -> impl Filter<Extract = (), Error = Rejection> + Clone {
+    warp::addr::remote()
+        .and_then(move |addr: Option<SocketAddr>| {
+            let limiter = limiter.clone();
+            async move {
+                if let Some(addr) = addr {
+                    if let Err(retry_after) = limiter.check_ip(addr.ip()).await {
+                        return Err(warp::reject::custom(TooManyRequests { retry_after }));
+                    }
+                }
+
+                Ok(())
+            }
+        })
+        .untuple_one()
+}
+
+/// Whole seconds to wait, rounded up as retrying early would only hit the
+/// limit again.
+pub fn retry_after_secs(retry_after: Duration) -> u64 {
+    retry_after.as_secs() + u64::from(retry_after.subsec_nanos() > 0)
+}
+
+/// `429` reply telling the client when to retry.
+pub fn too_many_requests(retry_after: Duration) -> Response {
+    let seconds = retry_after_secs(retry_after);
+
+    let mut response = warp::reply::with_status(
+        warp::reply::json(&AuthError {
+            err: format!("Too many login attempts, try again in {} seconds", seconds),
+            code: None,
+        }),
+        StatusCode::TOO_MANY_REQUESTS,
+    )
+    .into_response();
+
+    response
+        .headers_mut()
+        .insert(RETRY_AFTER, seconds.to_string().parse().unwrap());
+
+    response
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const BUCKET: Bucket = Bucket {
+        capacity: 3,
+        refill: Duration::from_secs(10),
+    };
+
+    #[test]
+    fn bucket_allows_burst_up_to_capacity() {
+        let now = Instant::now();
+        let mut state = BucketState {
+            tokens: BUCKET.capacity as f64,
+            updated_at: now,
+        };
+
+        for _ in 0..BUCKET.capacity {
+            assert_eq!(state.take(BUCKET, now), None);
+        }
+        assert_eq!(state.take(BUCKET, now), Some(BUCKET.refill));
+    }
+
+    #[test]
+    fn bucket_refills_over_time() {
+        let now = Instant::now();
+        let mut state = BucketState {
+            tokens: 0.0,
+            updated_at: now,
+        };
+
+        assert_eq!(
+            state.take(BUCKET, now + Duration::from_secs(4)),
+            Some(Duration::from_secs(6))
+        );
+        assert_eq!(state.take(BUCKET, now + Duration::from_secs(10)), None);
+        assert_eq!(
+            state.take(BUCKET, now + Duration::from_secs(10)),
+            Some(BUCKET.refill)
+        );
+    }
+
+    #[test]
+    fn bucket_does_not_refill_past_capacity() {
+        let now = Instant::now();
+        let mut state = BucketState {
+            tokens: 0.0,
+            updated_at: now,
+        };
+
+        let later = now + BUCKET.refill * 100;
+        for _ in 0..BUCKET.capacity {
+            assert_eq!(state.take(BUCKET, later), None);
+        }
+        assert!(state.take(BUCKET, later).is_some());
+    }
+
+    #[tokio::test]
+    async fn memory_store_keeps_buckets_per_key() {
+        let store = MemoryStore::default();
+        for _ in 0..BUCKET.capacity {
+            assert_eq!(store.take("a", BUCKET).await.unwrap(), None);
+        }
+
+        assert!(store.take("a", BUCKET).await.unwrap().is_some());
+        assert_eq!(store.take("b", BUCKET).await.unwrap(), None);
+    }
+
+    #[tokio::test]
+    async fn failures_lock_the_account() {
+        let limiter = LoginLimiter::new(Box::<MemoryStore>::default());
+        for _ in 0..MAX_FAILURES {
+            assert!(limiter.check_user("Alice").await.is_ok());
+            limiter.record_failure(None, "Alice").await;
+        }
+
+        let locked_for = limiter.check_user("alice").await.unwrap_err();
+        assert!(locked_for > LOCKOUT_DURATION - Duration::from_secs(60));
+        assert!(limiter.check_user("bob").await.is_ok());
+    }
+
+    #[test]
+    fn retry_after_rounds_up() {
+        assert_eq!(retry_after_secs(Duration::ZERO), 0);
+        assert_eq!(retry_after_secs(Duration::from_secs(3)), 3);
+        assert_eq!(retry_after_secs(Duration::from_millis(3001)), 4);
+        assert_eq!(retry_after_secs(Duration::from_millis(200)), 1);
+    }
+}
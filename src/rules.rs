@@ -0,0 +1,240 @@
+use crate::game::{Card, Symbol};
+use rand::seq::SliceRandom;
+use serde::{Deserialize, Serialize};
+
+pub const MAX_DECK_COUNT: u32 = 4;
+pub const JOKERS_PER_DECK: u32 = 2;
+pub const MAX_SIPS_PER_LOSS: u32 = 10;
+pub const MAX_BEST_OF: u32 = 7;
+
+/// When rating points are exchanged during a series.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum SeriesRating {
+    #[default]
+    PerGame,
+    PerSeries,
+}
+
+/// House rules a table is played with. Players are only matched with
+/// players that queued for the exact same rule set.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(default)]
+pub struct RuleSet {
+    /// Aces rank above kings when set, below twos otherwise.
+    pub ace_high: bool,
+    /// Adds two jokers per deck, outranking every other card.
+    pub jokers: bool,
+    /// Number of 52 card decks shuffled together.
+    pub deck_count: u32,
+    /// Cards dealt to each player, `None` splits the whole deck.
+    pub hand_size: Option<u32>,
+    /// The opponent only has to answer a card when holding its suit.
+    pub follow_suit: bool,
+    /// Sips a player has to drink for every losing play.
+    pub sips_per_loss: u32,
+    /// Games in a series, the pairing plays until one side wins the majority.
+    pub best_of: u32,
+    pub series_rating: SeriesRating,
+}
+
+impl Default for RuleSet {
+    fn default() -> Self {
+        Self {
+            ace_high: true,
+            jokers: false,
+            deck_count: 1,
+            hand_size: None,
+            follow_suit: true,
+            sips_per_loss: 1,
+            best_of: 1,
+            series_rating: SeriesRating::PerGame,
+        }
+    }
+}
+
+impl RuleSet {
+    pub fn deck_size(&self) -> u32 {
+        let per_deck = if self.jokers {
+            52 + JOKERS_PER_DECK
+        } else {
+            52
+        };
+
+        per_deck * self.deck_count
+    }
+
+    pub fn validate(&self) -> anyhow::Result<()> {
+        if self.deck_count == 0 || self.deck_count > MAX_DECK_COUNT {
+            return Err(anyhow::Error::msg(format!(
+                "Deck count must be between 1 to {} decks",
+                MAX_DECK_COUNT
+            )));
+        }
+
+        if let Some(hand_size) = self.hand_size {
+            if hand_size == 0 || hand_size > self.deck_size() / 2 {
+                return Err(anyhow::Error::msg(format!(
+                    "Hand size must be between 1 to {} cards",
+                    self.deck_size() / 2
+                )));
+            }
+        }
+
+        if self.sips_per_loss > MAX_SIPS_PER_LOSS {
+            return Err(anyhow::Error::msg(format!(
+                "Sips per loss must be between 0 to {} sips",
+                MAX_SIPS_PER_LOSS
+            )));
+        }
+
+        if self.best_of == 0 || self.best_of > MAX_BEST_OF || self.best_of.is_multiple_of(2) {
+            return Err(anyhow::Error::msg(format!(
+                "Series must be an odd number of games up to {} games",
+                MAX_BEST_OF
+            )));
+        }
+
+        Ok(())
+    }
+
+    /// Shuffles the decks and deals both hands.
+    pub fn deal(&self) -> (Vec<Card>, Vec<Card>) {
+        let mut rand = rand::thread_rng();
+        let mut cards = Vec::with_capacity(self.deck_size() as usize);
+
+        for _ in 0..self.deck_count {
+            for symbol in [
+                Symbol::Clubs,
+                Symbol::Diamonds,
+                Symbol::Hearts,
+                Symbol::Spades,
+            ]
+            .into_iter()
+            {
+                for i in 1..=13 {
+                    cards.push(Card { symbol, value: i });
+                }
+            }
+
+            if self.jokers {
+                for _ in 0..JOKERS_PER_DECK {
+                    cards.push(Card {
+                        symbol: Symbol::Joker,
+                        value: 0,
+                    });
+                }
+            }
+        }
+
+        cards.shuffle(&mut rand);
+
+        let hand_size = self
+            .hand_size
+            .map(|x| x as usize)
+            .unwrap_or(cards.len() / 2);
+
+        let mut p1_cards = Vec::with_capacity(hand_size);
+        let mut p2_cards = Vec::with_capacity(hand_size);
+
+        for (i, card) in cards.into_iter().take(hand_size * 2).enumerate() {
+            if i % 2 == 0 {
+                p1_cards.push(card);
+            } else {
+                p2_cards.push(card);
+            }
+        }
+
+        (p1_cards, p2_cards)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn default_rules_are_valid() {
+        assert!(RuleSet::default().validate().is_ok());
+    }
+
+    #[test]
+    fn rejects_invalid_rules() {
+        let invalid = [
+            RuleSet {
+                deck_count: 0,
+                ..Default::default()
+            },
+            RuleSet {
+                deck_count: MAX_DECK_COUNT + 1,
+                ..Default::default()
+            },
+            RuleSet {
+                hand_size: Some(0),
+                ..Default::default()
+            },
+            RuleSet {
+                hand_size: Some(27),
+                ..Default::default()
+            },
+            RuleSet {
+                sips_per_loss: MAX_SIPS_PER_LOSS + 1,
+                ..Default::default()
+            },
+            RuleSet {
+                best_of: 2,
+                ..Default::default()
+            },
+            RuleSet {
+                best_of: MAX_BEST_OF + 2,
+                ..Default::default()
+            },
+        ];
+
+        for rules in invalid {
+            assert!(rules.validate().is_err(), "{:?}", rules);
+        }
+    }
+
+    #[test]
+    fn rejects_huge_hand_size() {
+        for hand_size in [1 << 31, u32::MAX] {
+            let rules = RuleSet {
+                hand_size: Some(hand_size),
+                ..Default::default()
+            };
+            assert!(rules.validate().is_err());
+        }
+    }
+
+    #[test]
+    fn deals_whole_deck_by_default() {
+        let rules = RuleSet {
+            jokers: true,
+            deck_count: 2,
+            ..Default::default()
+        };
+        let (p1_cards, p2_cards) = rules.deal();
+
+        assert_eq!(p1_cards.len(), 54);
+        assert_eq!(p2_cards.len(), 54);
+        let jokers = p1_cards
+            .iter()
+            .chain(&p2_cards)
+            .filter(|x| x.symbol == Symbol::Joker)
+            .count();
+        assert_eq!(jokers, 4);
+    }
+
+    #[test]
+    fn deals_hand_size() {
+        let rules = RuleSet {
+            hand_size: Some(26),
+            ..Default::default()
+        };
+        let (p1_cards, p2_cards) = rules.deal();
+
+        assert_eq!(p1_cards.len(), 26);
+        assert_eq!(p2_cards.len(), 26);
+    }
+}
@@ -0,0 +1,64 @@
+use crate::db::{Db, User};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::reply::Json;
+
+/// Rating every account starts with, see the `User` table migration.
+pub const BASE_ELO: i32 = 500;
+
+/// Share of the distance to [`BASE_ELO`] a rating keeps over a rollover.
+pub const SOFT_RESET_FACTOR: f64 = 0.5;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct SeasonError {
+    pub err: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RolloverData {
+    pub name: String,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&SeasonError {
+        err: error.to_string(),
+    })
+}
+
+pub async fn seasons(db: Arc<Db>) -> Json {
+    match db.get_seasons().await {
+        Ok(seasons) => warp::reply::json(&seasons),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn rollover(user: User, data: RolloverData, db: Arc<Db>) -> Json {
+    if data.name.trim().len() < 3 || data.name.len() > 64 {
+        return error(anyhow::Error::msg(
+            "Season name must be between 3 to 64 characters",
+        ));
+    }
+
+    match db
+        .rollover_season(data.name.trim(), BASE_ELO, SOFT_RESET_FACTOR)
+        .await
+    {
+        Ok(id) => {
+            log::info!("{} started season {}", user.username, id);
+
+            if let Err(err) = db.leaderboard_stream.publish(&db).await {
+                log::error!("Failed to publish leaderboard changes: {}", err);
+            }
+
+            seasons(db).await
+        }
+        Err(err) => error(err),
+    }
+}
+
+pub async fn rewards(username: String, db: Arc<Db>) -> Json {
+    match db.get_season_rewards(&username).await {
+        Ok(rewards) => warp::reply::json(&rewards),
+        Err(err) => error(err),
+    }
+}
@@ -0,0 +1,164 @@
+use crate::db::{Db, RatingChange};
+use chrono::{Duration, NaiveDate};
+use serde::{Deserialize, Serialize};
+use std::sync::Arc;
+use warp::reply::Json;
+
+pub const DEFAULT_HISTORY_POINTS: usize = 200;
+pub const MAX_HISTORY_POINTS: usize = 1000;
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatingHistoryQuery {
+    /// First day included, defaults to the beginning of the history.
+    pub from: Option<NaiveDate>,
+    /// Last day included, defaults to today.
+    pub to: Option<NaiveDate>,
+    /// Maximum number of points returned.
+    pub points: Option<usize>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct RatingHistory {
+    pub username: String,
+    /// Number of rating changes in the range before downsampling.
+    pub total: usize,
+    pub changes: Vec<RatingChange>,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct StatsError {
+    pub err: String,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&StatsError {
+        err: error.to_string(),
+    })
+}
+
+pub async fn stats(username: String, db: Arc<Db>) -> Json {
+    match db.get_player_stats(&username).await {
+        Ok(stats) => warp::reply::json(&stats),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn head_to_head(username: String, opponent: String, db: Arc<Db>) -> Json {
+    let result = async {
+        let player1 = db.get_user_by_name(&username).await?;
+        let player2 = db.get_user_by_name(&opponent).await?;
+        db.get_head_to_head(&player1, &player2).await
+    }
+    .await;
+
+    match result {
+        Ok(head_to_head) => warp::reply::json(&head_to_head),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn rating_history(username: String, query: RatingHistoryQuery, db: Arc<Db>) -> Json {
+    let result = async {
+        let points = query.points.unwrap_or(DEFAULT_HISTORY_POINTS);
+        if !(2..=MAX_HISTORY_POINTS).contains(&points) {
+            return Err(anyhow::Error::msg(format!(
+                "Points must be between 2 to {} points",
+                MAX_HISTORY_POINTS
+            )));
+        }
+
+        let from = query.from.map(|x| x.and_hms_opt(0, 0, 0).unwrap());
+        let to = query
+            .to
+            .map(|x| x.and_hms_opt(0, 0, 0).unwrap() + Duration::days(1));
+
+        if from.zip(to).is_some_and(|(from, to)| from >= to) {
+            return Err(anyhow::Error::msg("Range must end after it starts"));
+        }
+
+        let user = db.get_user_by_name(&username).await?;
+        let changes = db.get_rating_history(user.id, from, to).await?;
+
+        Ok(RatingHistory {
+            username: user.username,
+            total: changes.len(),
+            changes: downsample(changes, points),
+        })
+    }
+    .await;
+
+    match result {
+        Ok(history) => warp::reply::json(&history),
+        Err(err) => error(err),
+    }
+}
+
+/// Picks `points` evenly spaced changes, always keeping the first and the
+/// last one so the chart starts and ends at the right rating.
+fn downsample(changes: Vec<RatingChange>, points: usize) -> Vec<RatingChange> {
+    if changes.len() <= points {
+        return changes;
+    }
+
+    let last = changes.len() - 1;
+    let step = last as f64 / (points - 1) as f64;
+    let mut keep = (0..points)
+        .map(|i| (i as f64 * step).round() as usize)
+        .peekable();
+
+    changes
+        .into_iter()
+        .enumerate()
+        .filter_map(|(i, change)| {
+            if keep.peek() == Some(&i) {
+                while keep.peek() == Some(&i) {
+                    keep.next();
+                }
+                Some(change)
+            } else {
+                None
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::NaiveDateTime;
+
+    fn changes(count: i32) -> Vec<RatingChange> {
+        (0..count)
+            .map(|i| RatingChange {
+                game_id: Some(i),
+                elo_before: 500 + i,
+                elo_after: 501 + i,
+                delta: 1,
+                reason: None,
+                created_at: NaiveDateTime::default(),
+            })
+            .collect()
+    }
+
+    fn game_ids(changes: &[RatingChange]) -> Vec<i32> {
+        changes.iter().map(|x| x.game_id.unwrap()).collect()
+    }
+
+    #[test]
+    fn downsample_keeps_short_histories() {
+        assert_eq!(game_ids(&downsample(changes(3), 5)), vec![0, 1, 2]);
+    }
+
+    #[test]
+    fn downsample_keeps_first_and_last() {
+        assert_eq!(game_ids(&downsample(changes(10), 4)), vec![0, 3, 6, 9]);
+        assert_eq!(game_ids(&downsample(changes(100), 2)), vec![0, 99]);
+    }
+
+    #[test]
+    fn downsample_returns_requested_points() {
+        for points in 2..20 {
+            assert_eq!(downsample(changes(20), points).len(), points);
+        }
+    }
+}
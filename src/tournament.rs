@@ -0,0 +1,582 @@
+use crate::{
+    db::{Db, Tournament, TournamentMatch, TournamentPlayer, User},
+    game::{self, State},
+    rules::RuleSet,
+};
+use serde::{Deserialize, Serialize};
+use std::{collections::HashSet, sync::Arc, time::Duration};
+use tokio::sync::Mutex;
+use warp::reply::Json;
+
+pub const MAX_PLAYERS: u32 = 256;
+pub const MAX_REGISTRATION_MINUTES: u32 = 60 * 24 * 14;
+pub const SCHEDULER_INTERVAL: Duration = Duration::from_secs(10);
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentFormat {
+    SingleElimination,
+    Swiss,
+}
+
+impl TournamentFormat {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TournamentFormat::SingleElimination => "single_elimination",
+            TournamentFormat::Swiss => "swiss",
+        }
+    }
+}
+
+impl TryFrom<String> for TournamentFormat {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "single_elimination" => Ok(TournamentFormat::SingleElimination),
+            "swiss" => Ok(TournamentFormat::Swiss),
+            _ => Err(anyhow::Error::msg("Invalid tournament format")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum TournamentStatus {
+    Registration,
+    Running,
+    Finished,
+    Cancelled,
+}
+
+impl TournamentStatus {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            TournamentStatus::Registration => "registration",
+            TournamentStatus::Running => "running",
+            TournamentStatus::Finished => "finished",
+            TournamentStatus::Cancelled => "cancelled",
+        }
+    }
+}
+
+impl TryFrom<String> for TournamentStatus {
+    type Error = anyhow::Error;
+
+    fn try_from(value: String) -> Result<Self, Self::Error> {
+        match value.as_str() {
+            "registration" => Ok(TournamentStatus::Registration),
+            "running" => Ok(TournamentStatus::Running),
+            "finished" => Ok(TournamentStatus::Finished),
+            "cancelled" => Ok(TournamentStatus::Cancelled),
+            _ => Err(anyhow::Error::msg("Invalid tournament status")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct TournamentError {
+    pub err: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CreateTournamentData {
+    pub name: String,
+    pub format: TournamentFormat,
+    #[serde(default)]
+    pub rules: RuleSet,
+    /// Swiss rounds, defaults to enough rounds to find a single winner.
+    pub rounds: Option<u32>,
+    pub max_players: u32,
+    pub registration_minutes: u32,
+}
+
+#[derive(Serialize)]
+pub struct TournamentBracket {
+    pub tournament: Tournament,
+    pub rounds: Vec<Vec<TournamentMatch>>,
+}
+
+#[derive(Serialize)]
+pub struct TournamentStandings {
+    pub tournament: Tournament,
+    pub players: Vec<TournamentPlayer>,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&TournamentError {
+        err: error.to_string(),
+    })
+}
+
+pub async fn create(user: User, data: CreateTournamentData, db: Arc<Db>) -> Json {
+    if data.name.trim().len() < 3 || data.name.len() > 64 {
+        return error(anyhow::Error::msg(
+            "Tournament name must be between 3 to 64 characters",
+        ));
+    }
+
+    if data.max_players < 2 || data.max_players > MAX_PLAYERS {
+        return error(anyhow::Error::msg(format!(
+            "Tournament must allow between 2 to {} players",
+            MAX_PLAYERS
+        )));
+    }
+
+    if data.registration_minutes > MAX_REGISTRATION_MINUTES {
+        return error(anyhow::Error::msg(format!(
+            "Registration can be open for at most {} minutes",
+            MAX_REGISTRATION_MINUTES
+        )));
+    }
+
+    if let Err(err) = data.rules.validate() {
+        return error(err);
+    }
+
+    let rounds = match data.format {
+        TournamentFormat::SingleElimination => 0,
+        TournamentFormat::Swiss => data.rounds.unwrap_or(0),
+    };
+
+    let result = db
+        .insert_tournament(
+            data.name.trim(),
+            data.format,
+            &data.rules,
+            rounds,
+            data.max_players,
+            data.registration_minutes,
+            user.id,
+        )
+        .await;
+
+    match result {
+        Ok(id) => match db.get_tournament(id).await {
+            Ok(tournament) => warp::reply::json(&tournament),
+            Err(err) => error(err),
+        },
+        Err(err) => error(err),
+    }
+}
+
+pub async fn list(db: Arc<Db>) -> Json {
+    match db.get_tournaments().await {
+        Ok(tournaments) => warp::reply::json(&tournaments),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn register(id: i32, user: User, db: Arc<Db>) -> Json {
+    if user.is_guest {
+        return error(anyhow::Error::msg("Guests can't join tournaments"));
+    }
+
+    match db.insert_tournament_player(id, user.id).await {
+        Ok(()) => warp::reply::json(&TournamentError { err: String::new() }),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn start_now(id: i32, _user: User, db: Arc<Db>, state: Arc<Mutex<State>>) -> Json {
+    let result = async {
+        let tournament = db.get_tournament(id).await?;
+        if tournament.status != TournamentStatus::Registration {
+            return Err(anyhow::Error::msg("Tournament already started"));
+        }
+
+        start(&db, &tournament).await?;
+        launch_matches(&db, &mut *state.lock().await).await?;
+        db.get_tournament(id).await
+    }
+    .await;
+
+    match result {
+        Ok(tournament) => warp::reply::json(&tournament),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn bracket(id: i32, db: Arc<Db>) -> Json {
+    let result = async {
+        let tournament = db.get_tournament(id).await?;
+        let mut rounds: Vec<Vec<TournamentMatch>> = Vec::new();
+
+        for tournament_match in db.get_tournament_matches(id).await? {
+            match rounds.last_mut() {
+                Some(round) if round[0].round == tournament_match.round => {
+                    round.push(tournament_match)
+                }
+                _ => rounds.push(vec![tournament_match]),
+            }
+        }
+
+        Ok::<_, anyhow::Error>(TournamentBracket { tournament, rounds })
+    }
+    .await;
+
+    match result {
+        Ok(bracket) => warp::reply::json(&bracket),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn standings(id: i32, db: Arc<Db>) -> Json {
+    let result = async {
+        let tournament = db.get_tournament(id).await?;
+        let players = db.get_tournament_players(id).await?;
+        Ok::<_, anyhow::Error>(TournamentStandings {
+            tournament,
+            players,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(standings) => warp::reply::json(&standings),
+        Err(err) => error(err),
+    }
+}
+
+/// Closes registration, seeds the players by rating and pairs the first round.
+pub async fn start(db: &Db, tournament: &Tournament) -> anyhow::Result<()> {
+    // players are ordered by rating before the seeds are assigned
+    let seeding: Vec<i32> = db
+        .get_tournament_players(tournament.id)
+        .await?
+        .into_iter()
+        .map(|x| x.user_id)
+        .collect();
+
+    if seeding.len() < 2 {
+        log::info!(
+            "Cancelling tournament {}, not enough players",
+            tournament.id
+        );
+        return db
+            .finish_tournament(tournament.id, TournamentStatus::Cancelled, None)
+            .await;
+    }
+
+    let elimination_rounds = seeding.len().next_power_of_two().trailing_zeros();
+    let rounds = match tournament.format {
+        TournamentFormat::SingleElimination => elimination_rounds,
+        TournamentFormat::Swiss if tournament.rounds > 0 => {
+            (tournament.rounds as u32).min(seeding.len() as u32 - 1)
+        }
+        TournamentFormat::Swiss => elimination_rounds,
+    };
+
+    db.start_tournament(tournament.id, rounds, &seeding).await?;
+
+    let pairings = match tournament.format {
+        TournamentFormat::SingleElimination => first_elimination_round(&seeding),
+        TournamentFormat::Swiss => swiss_round(&seeding, &[]),
+    };
+
+    db.insert_tournament_round(tournament.id, 1, &pairings)
+        .await
+}
+
+/// Pairs the next round once every match of the current one has a winner,
+/// or finishes the tournament after its last round.
+pub async fn advance(db: &Db, tournament_id: i32) -> anyhow::Result<()> {
+    let tournament = db.get_tournament(tournament_id).await?;
+    if tournament.status != TournamentStatus::Running {
+        return Ok(());
+    }
+
+    let matches = db.get_tournament_matches(tournament_id).await?;
+    let current_round: Vec<&TournamentMatch> = matches
+        .iter()
+        .filter(|x| x.round == tournament.current_round)
+        .collect();
+
+    if current_round.iter().any(|x| x.winner_id.is_none()) {
+        return Ok(());
+    }
+
+    let players = db.get_tournament_players(tournament_id).await?;
+
+    if tournament.current_round >= tournament.rounds {
+        let winner = match tournament.format {
+            TournamentFormat::SingleElimination => current_round.first().and_then(|x| x.winner_id),
+            TournamentFormat::Swiss => players.first().map(|x| x.user_id),
+        };
+
+        return db
+            .finish_tournament(tournament_id, TournamentStatus::Finished, winner)
+            .await;
+    }
+
+    let pairings = match tournament.format {
+        TournamentFormat::SingleElimination => next_elimination_round(&current_round),
+        TournamentFormat::Swiss => {
+            let standings: Vec<i32> = players.iter().map(|x| x.user_id).collect();
+            swiss_round(&standings, &matches)
+        }
+    };
+
+    db.insert_tournament_round(
+        tournament_id,
+        tournament.current_round as u32 + 1,
+        &pairings,
+    )
+    .await
+}
+
+/// Stores the winner of a decided series and moves the tournament along.
+pub async fn record_result(
+    db: &Db,
+    state: &mut State,
+    match_id: i32,
+    winner: &str,
+) -> anyhow::Result<()> {
+    let tournament_match = db.get_tournament_match(match_id).await?;
+    let tournament = db.get_tournament(tournament_match.tournament_id).await?;
+
+    let winner_id = if tournament_match.player1.as_deref() == Some(winner) {
+        tournament_match.player1_id
+    } else {
+        tournament_match.player2_id
+    }
+    .ok_or_else(|| anyhow::Error::msg("Winner is not part of the match"))?;
+
+    let eliminate = tournament.format == TournamentFormat::SingleElimination;
+    db.set_tournament_match_winner(&tournament_match, winner_id, eliminate)
+        .await?;
+
+    advance(db, tournament.id).await?;
+    launch_matches(db, state).await
+}
+
+/// Starts the games of every unplayed match whose players are both connected
+/// and not busy with another game.
+pub async fn launch_matches(db: &Db, state: &mut State) -> anyhow::Result<()> {
+    for tournament in db
+        .get_tournaments_by_status(TournamentStatus::Running)
+        .await?
+    {
+        for tournament_match in db.get_tournament_matches(tournament.id).await? {
+            if tournament_match.round != tournament.current_round
+                || tournament_match.winner_id.is_some()
+            {
+                continue;
+            }
+
+            let (Some(player1), Some(player2)) =
+                (tournament_match.player1, tournament_match.player2)
+            else {
+                continue;
+            };
+
+            if state.is_playing(&player1) || state.is_playing(&player2) {
+                continue;
+            }
+
+            let (Some(tx1), Some(tx2)) = (
+                state.connections.get(&player1).cloned(),
+                state.connections.get(&player2).cloned(),
+            ) else {
+                continue;
+            };
+
+            state
+                .pending_matches
+                .retain(|_, x| x.0 != player1 && x.0 != player2);
+
+            // one broken match must not hold up the others
+            if let Err(error) = game::start_game_for_match(
+                db,
+                state,
+                (player1, tx1),
+                (player2, tx2),
+                tournament.rules,
+                Some(tournament_match.id),
+            )
+            .await
+            {
+                log::error!(
+                    "Failed to launch tournament match {}: {}",
+                    tournament_match.id,
+                    error
+                );
+            }
+        }
+    }
+
+    Ok(())
+}
+
+pub async fn run_scheduler(db: Arc<Db>, state: Arc<Mutex<State>>) {
+    let mut interval = tokio::time::interval(SCHEDULER_INTERVAL);
+    loop {
+        interval.tick().await;
+
+        match db.get_tournaments_to_start().await {
+            Ok(tournaments) => {
+                // one tournament failing to start must not hold up the others
+                for tournament in tournaments {
+                    if let Err(error) = start(&db, &tournament).await {
+                        log::error!("Failed to start tournament {}: {}", tournament.id, error);
+                    }
+                }
+            }
+            Err(error) => log::error!("Failed to get tournaments to start: {}", error),
+        }
+
+        if let Err(error) = launch_matches(&db, &mut *state.lock().await).await {
+            log::error!("Tournament scheduler failed: {}", error);
+        }
+    }
+}
+
+/// Seeds in bracket order, so that the two best seeds can only meet in the
+/// final: `[1, 8, 4, 5, 2, 7, 3, 6]` for eight players.
+fn bracket_order(size: usize) -> Vec<usize> {
+    let mut order = vec![1];
+    while order.len() < size {
+        let len = order.len() * 2;
+        order = order
+            .into_iter()
+            .flat_map(|seed| [seed, len + 1 - seed])
+            .collect();
+    }
+
+    order
+}
+
+/// Pairs the seeded players, the best seeds get the byes when the player
+/// count is not a power of two.
+fn first_elimination_round(seeding: &[i32]) -> Vec<(Option<i32>, Option<i32>)> {
+    bracket_order(seeding.len().next_power_of_two())
+        .chunks(2)
+        .map(|x| {
+            (
+                seeding.get(x[0] - 1).copied(),
+                seeding.get(x[1] - 1).copied(),
+            )
+        })
+        .collect()
+}
+
+fn next_elimination_round(previous: &[&TournamentMatch]) -> Vec<(Option<i32>, Option<i32>)> {
+    previous
+        .chunks(2)
+        .map(|x| (x[0].winner_id, x.get(1).and_then(|x| x.winner_id)))
+        .collect()
+}
+
+/// Pairs players with equal scores, avoiding rematches where possible. With
+/// an odd player count the lowest ranked player without a bye sits out.
+fn swiss_round(standings: &[i32], matches: &[TournamentMatch]) -> Vec<(Option<i32>, Option<i32>)> {
+    let mut played = HashSet::new();
+    let mut byes = HashSet::new();
+    for tournament_match in matches {
+        match (tournament_match.player1_id, tournament_match.player2_id) {
+            (Some(player1), Some(player2)) => {
+                played.insert((player1.min(player2), player1.max(player2)));
+            }
+            (Some(player), None) | (None, Some(player)) => {
+                byes.insert(player);
+            }
+            (None, None) => {}
+        }
+    }
+
+    let mut remaining = standings.to_vec();
+    let mut bye = None;
+    if remaining.len() % 2 == 1 {
+        let index = remaining
+            .iter()
+            .rposition(|x| !byes.contains(x))
+            .unwrap_or(remaining.len() - 1);
+        bye = Some(remaining.remove(index));
+    }
+
+    let mut pairings = Vec::with_capacity(standings.len() / 2 + 1);
+    while !remaining.is_empty() {
+        let player = remaining.remove(0);
+        let index = remaining
+            .iter()
+            .position(|x| !played.contains(&(player.min(*x), player.max(*x))))
+            .unwrap_or(0);
+        let opponent = remaining.remove(index);
+        pairings.push((Some(player), Some(opponent)));
+    }
+
+    if bye.is_some() {
+        pairings.push((bye, None));
+    }
+
+    pairings
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn played(round: i32, player1: Option<i32>, player2: Option<i32>) -> TournamentMatch {
+        TournamentMatch {
+            id: 0,
+            tournament_id: 0,
+            round,
+            slot: 0,
+            player1_id: player1,
+            player1: None,
+            player2_id: player2,
+            player2: None,
+            winner_id: player1,
+            winner: None,
+            game_id: None,
+        }
+    }
+
+    #[test]
+    fn bracket_order_keeps_top_seeds_apart() {
+        assert_eq!(bracket_order(1), vec![1]);
+        assert_eq!(bracket_order(2), vec![1, 2]);
+        assert_eq!(bracket_order(4), vec![1, 4, 2, 3]);
+        assert_eq!(bracket_order(8), vec![1, 8, 4, 5, 2, 7, 3, 6]);
+    }
+
+    #[test]
+    fn best_seeds_get_the_byes() {
+        let round = first_elimination_round(&[10, 20, 30, 40, 50, 60]);
+        assert_eq!(
+            round,
+            vec![
+                (Some(10), None),
+                (Some(40), Some(50)),
+                (Some(20), None),
+                (Some(30), Some(60)),
+            ]
+        );
+    }
+
+    #[test]
+    fn swiss_pairs_by_standing() {
+        let round = swiss_round(&[1, 2, 3, 4], &[]);
+        assert_eq!(round, vec![(Some(1), Some(2)), (Some(3), Some(4))]);
+    }
+
+    #[test]
+    fn swiss_avoids_rematches() {
+        let matches = [played(1, Some(1), Some(2)), played(1, Some(3), Some(4))];
+        let round = swiss_round(&[1, 3, 2, 4], &matches);
+        assert_eq!(round, vec![(Some(1), Some(3)), (Some(2), Some(4))]);
+
+        let round = swiss_round(&[1, 2, 3, 4], &matches);
+        assert_eq!(round, vec![(Some(1), Some(3)), (Some(2), Some(4))]);
+    }
+
+    #[test]
+    fn swiss_bye_goes_to_lowest_player_without_one() {
+        let round = swiss_round(&[1, 2, 3], &[]);
+        assert_eq!(round, vec![(Some(1), Some(2)), (Some(3), None)]);
+
+        let matches = [played(1, Some(1), Some(2)), played(1, Some(3), None)];
+        let round = swiss_round(&[1, 3, 2], &matches);
+        assert_eq!(round, vec![(Some(1), Some(3)), (Some(2), None)]);
+    }
+}
@@ -0,0 +1,459 @@
+use crate::{
+    audit::{self, AuditAction, RequestInfo},
+    authentication::{self, AuthError, LoginResponse},
+    db::{Db, User},
+    rate_limit::LoginLimiter,
+};
+use data_encoding::BASE32_NOPAD;
+use hmac::{Hmac, Mac};
+use rand::{distributions::Alphanumeric, Rng, RngCore};
+use serde::{Deserialize, Serialize};
+use sha1::Sha1;
+use std::sync::Arc;
+use warp::reply::Json;
+
+/// RFC 6238 parameters understood by every authenticator app.
+pub const TOTP_STEP_SECONDS: i64 = 30;
+pub const TOTP_DIGITS: u32 = 6;
+/// Steps before and after the current one that are accepted, for clock drift.
+pub const TOTP_SKEW: i64 = 1;
+pub const SECRET_BYTES: usize = 20;
+pub const TOTP_ISSUER: &str = "Drinkard";
+
+pub const RECOVERY_CODE_COUNT: usize = 10;
+pub const RECOVERY_CODE_LENGTH: usize = 10;
+
+pub const CHALLENGE_VALID_MINUTES: i64 = 5;
+pub const MAX_CHALLENGE_ATTEMPTS: i32 = 5;
+
+#[derive(Serialize)]
+pub struct TotpSetup {
+    pub secret: String,
+    pub otpauth_uri: String,
+}
+
+#[derive(Serialize)]
+pub struct RecoveryCodes {
+    pub recovery_codes: Vec<String>,
+}
+
+/// Returned by `/login` instead of a session while the second step is due.
+#[derive(Serialize)]
+pub struct LoginChallenge {
+    pub two_factor_required: bool,
+    pub challenge: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct CodeData {
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct DisableData {
+    pub password: String,
+    pub code: String,
+}
+
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ChallengeData {
+    pub challenge: String,
+    pub code: String,
+}
+
+/// How the second factor was proven.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SecondFactor {
+    Totp,
+    RecoveryCode,
+}
+
+fn error(error: anyhow::Error) -> Json {
+    warp::reply::json(&AuthError {
+        err: error.to_string(),
+        code: None,
+    })
+}
+
+/// HOTP value of `secret` for the counter `step` (RFC 4226).
+fn hotp(secret: &[u8], step: i64) -> u32 {
+    let mut mac = Hmac::<Sha1>::new_from_slice(secret).unwrap();
+    mac.update(&step.to_be_bytes());
+    let hash = mac.finalize().into_bytes();
+
+    let offset = (hash[hash.len() - 1] & 0x0f) as usize;
+    let value = u32::from_be_bytes([
+        hash[offset] & 0x7f,
+        hash[offset + 1],
+        hash[offset + 2],
+        hash[offset + 3],
+    ]);
+
+    value % 10u32.pow(TOTP_DIGITS)
+}
+
+/// Returns the time step `code` is valid for around `now`, if any.
+pub fn verify_totp(secret: &str, code: &str, now: i64) -> Option<i64> {
+    let secret = BASE32_NOPAD.decode(secret.as_bytes()).ok()?;
+    let code: u32 = code.parse().ok()?;
+    let current = now / TOTP_STEP_SECONDS;
+
+    (current - TOTP_SKEW..=current + TOTP_SKEW).find(|&step| hotp(&secret, step) == code)
+}
+
+pub fn generate_secret() -> String {
+    let mut secret = [0u8; SECRET_BYTES];
+    rand::thread_rng().fill_bytes(&mut secret);
+    BASE32_NOPAD.encode(&secret)
+}
+
+/// Key URI scanned by authenticator apps.
+pub fn otpauth_uri(username: &str, secret: &str) -> String {
+    let label: String =
+        url::form_urlencoded::byte_serialize(format!("{}:{}", TOTP_ISSUER, username).as_bytes())
+            .collect();
+
+    format!(
+        "otpauth://totp/{}?secret={}&issuer={}&algorithm=SHA1&digits={}&period={}",
+        label, secret, TOTP_ISSUER, TOTP_DIGITS, TOTP_STEP_SECONDS
+    )
+}
+
+/// Codes are shown as `xxxxx-xxxxx`, dashes and case are ignored on input.
+fn normalize_recovery_code(code: &str) -> String {
+    code.chars()
+        .filter(|x| x.is_ascii_alphanumeric())
+        .map(|x| x.to_ascii_lowercase())
+        .collect()
+}
+
+fn generate_recovery_codes() -> (Vec<String>, Vec<String>) {
+    let codes: Vec<String> = (0..RECOVERY_CODE_COUNT)
+        .map(|_| {
+            let code: String = rand::thread_rng()
+                .sample_iter(&Alphanumeric)
+                .take(RECOVERY_CODE_LENGTH)
+                .map(|x| (x as char).to_ascii_lowercase())
+                .collect();
+            let (first, second) = code.split_at(RECOVERY_CODE_LENGTH / 2);
+            format!("{}-{}", first, second)
+        })
+        .collect();
+
+    let hashes = codes
+        .iter()
+        .map(|code| authentication::hash_token(&normalize_recovery_code(code)))
+        .collect();
+
+    (codes, hashes)
+}
+
+/// Checks a TOTP code or, failing that, uses up a recovery code.
+pub async fn verify_code(db: &Db, user: &User, code: &str) -> anyhow::Result<SecondFactor> {
+    let code = code.trim();
+    let (secret, _) = db.get_totp(user.id).await?;
+    let secret =
+        secret.ok_or_else(|| anyhow::Error::msg("Two-factor authentication is not set up"))?;
+
+    if code.len() == TOTP_DIGITS as usize && code.chars().all(|x| x.is_ascii_digit()) {
+        let now = chrono::Utc::now().timestamp();
+        if let Some(step) = verify_totp(&secret, code, now) {
+            if db.use_totp_step(user.id, step).await? {
+                return Ok(SecondFactor::Totp);
+            }
+        }
+    } else if user.totp_enabled {
+        let code_hash = authentication::hash_token(&normalize_recovery_code(code));
+        if db.use_recovery_code(user.id, &code_hash).await? {
+            return Ok(SecondFactor::RecoveryCode);
+        }
+    }
+
+    Err(anyhow::Error::msg("Invalid two-factor code"))
+}
+
+/// Verifies the second factor of a login whose password was already checked.
+pub async fn verify_login(
+    db: &Db,
+    request: &RequestInfo,
+    limiter: &LoginLimiter,
+    user: &User,
+    code: &str,
+) -> anyhow::Result<()> {
+    match verify_code(db, user, code).await {
+        Ok(SecondFactor::Totp) => Ok(()),
+        Ok(SecondFactor::RecoveryCode) => {
+            audit::record(
+                db,
+                request,
+                AuditAction::RecoveryCodeUsed,
+                Some(user.id),
+                Some(user.id),
+                None,
+            )
+            .await;
+            Ok(())
+        }
+        Err(error) => {
+            limiter.record_failure(request.ip, &user.username).await;
+            audit::record_failed_login(db, request, &user.username, &error).await;
+            Err(error)
+        }
+    }
+}
+
+/// Starts the second login step, the challenge stands in for the password.
+pub async fn challenge(db: &Db, user: &User) -> anyhow::Result<LoginChallenge> {
+    let challenge = authentication::generate_token();
+    db.insert_login_challenge(
+        &authentication::hash_token(&challenge),
+        user.id,
+        CHALLENGE_VALID_MINUTES,
+    )
+    .await?;
+
+    Ok(LoginChallenge {
+        two_factor_required: true,
+        challenge,
+    })
+}
+
+/// Second login step, trading the challenge and a code for a session.
+pub async fn login(
+    request: RequestInfo,
+    data: ChallengeData,
+    db: Arc<Db>,
+    limiter: Arc<LoginLimiter>,
+) -> Json {
+    let result = async {
+        let challenge_hash = authentication::hash_token(&data.challenge);
+        let user = db
+            .attempt_login_challenge(&challenge_hash, MAX_CHALLENGE_ATTEMPTS)
+            .await?;
+
+        if let Err(retry_after) = limiter.check_user(&user.username).await {
+            return Err(anyhow::Error::msg(format!(
+                "Too many login attempts, try again in {} seconds",
+                crate::rate_limit::retry_after_secs(retry_after)
+            )));
+        }
+
+        verify_login(&db, &request, &limiter, &user, &data.code).await?;
+        db.delete_login_challenge(&challenge_hash).await?;
+        limiter.record_success(&user.username).await;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::Login,
+            Some(user.id),
+            Some(user.id),
+            Some("two-factor"),
+        )
+        .await;
+
+        let token = authentication::generate_token();
+        db.insert_session(&token, user.id).await?;
+
+        Ok(LoginResponse { user, token })
+    }
+    .await;
+
+    match result {
+        Ok(login) => warp::reply::json(&login),
+        Err(err) => error(err),
+    }
+}
+
+/// Generates a new secret, enabled once a first code is confirmed.
+pub async fn setup(user: User, db: Arc<Db>) -> Json {
+    let result = async {
+        if user.is_guest {
+            return Err(anyhow::Error::msg(
+                "Guests can't set up two-factor authentication",
+            ));
+        }
+
+        let secret = generate_secret();
+        db.set_pending_totp(user.id, &secret).await?;
+
+        Ok(TotpSetup {
+            otpauth_uri: otpauth_uri(&user.username, &secret),
+            secret,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(setup) => warp::reply::json(&setup),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn enable(user: User, request: RequestInfo, data: CodeData, db: Arc<Db>) -> Json {
+    let result = async {
+        if user.totp_enabled {
+            return Err(anyhow::Error::msg(
+                "Two-factor authentication is already enabled",
+            ));
+        }
+
+        verify_code(&db, &user, &data.code).await?;
+
+        let (codes, hashes) = generate_recovery_codes();
+        db.enable_totp(user.id, &hashes).await?;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::TwoFactorEnabled,
+            Some(user.id),
+            Some(user.id),
+            None,
+        )
+        .await;
+
+        Ok(RecoveryCodes {
+            recovery_codes: codes,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(codes) => warp::reply::json(&codes),
+        Err(err) => error(err),
+    }
+}
+
+pub async fn disable(user: User, request: RequestInfo, data: DisableData, db: Arc<Db>) -> Json {
+    let result = async {
+        if !user.totp_enabled {
+            return Err(anyhow::Error::msg(
+                "Two-factor authentication is not enabled",
+            ));
+        }
+
+        db.get_user_by_name_password(&user.username, &data.password)
+            .await
+            .map_err(|_| anyhow::Error::msg("Invalid password"))?;
+        verify_code(&db, &user, &data.code).await?;
+
+        db.disable_totp(user.id).await?;
+        audit::record(
+            &db,
+            &request,
+            AuditAction::TwoFactorDisabled,
+            Some(user.id),
+            Some(user.id),
+            None,
+        )
+        .await;
+
+        anyhow::Ok(())
+    }
+    .await;
+
+    match result {
+        Ok(()) => warp::reply::json(&AuthError {
+            err: String::new(),
+            code: None,
+        }),
+        Err(err) => error(err),
+    }
+}
+
+/// Replaces every recovery code, the old ones stop working.
+pub async fn regenerate_recovery_codes(user: User, data: CodeData, db: Arc<Db>) -> Json {
+    let result = async {
+        if !user.totp_enabled {
+            return Err(anyhow::Error::msg(
+                "Two-factor authentication is not enabled",
+            ));
+        }
+
+        verify_code(&db, &user, &data.code).await?;
+
+        let (codes, hashes) = generate_recovery_codes();
+        db.replace_recovery_codes(user.id, &hashes).await?;
+
+        Ok(RecoveryCodes {
+            recovery_codes: codes,
+        })
+    }
+    .await;
+
+    match result {
+        Ok(codes) => warp::reply::json(&codes),
+        Err(err) => error(err),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The RFC 6238 SHA-1 seed "12345678901234567890".
+    const SECRET: &str = "GEZDGNBVGY3TQOJQGEZDGNBVGY3TQOJQ";
+
+    #[test]
+    fn matches_rfc_6238_vectors() {
+        // the RFC lists eight digits, apps show the last six
+        let vectors = [
+            (59, "287082"),
+            (1111111109, "081804"),
+            (1111111111, "050471"),
+            (1234567890, "005924"),
+            (2000000000, "279037"),
+            (20000000000, "353130"),
+        ];
+
+        for (time, code) in vectors {
+            assert_eq!(
+                verify_totp(SECRET, code, time),
+                Some(time / TOTP_STEP_SECONDS),
+                "{}",
+                time
+            );
+        }
+    }
+
+    #[test]
+    fn accepts_codes_within_skew() {
+        assert_eq!(verify_totp(SECRET, "287082", 59 + 30), Some(1));
+        assert_eq!(verify_totp(SECRET, "287082", 59 - 30), Some(1));
+        assert_eq!(verify_totp(SECRET, "287082", 59 + 90), None);
+    }
+
+    #[test]
+    fn rejects_invalid_input() {
+        assert_eq!(verify_totp(SECRET, "287083", 59), None);
+        assert_eq!(verify_totp(SECRET, "abcdef", 59), None);
+        assert_eq!(verify_totp("not base32!", "287082", 59), None);
+    }
+
+    #[test]
+    fn generated_secrets_verify() {
+        let secret = generate_secret();
+        let key = BASE32_NOPAD.decode(secret.as_bytes()).unwrap();
+        assert_eq!(key.len(), SECRET_BYTES);
+
+        let code = format!("{:06}", hotp(&key, 1000));
+        assert_eq!(
+            verify_totp(&secret, &code, 1000 * TOTP_STEP_SECONDS),
+            Some(1000)
+        );
+    }
+
+    #[test]
+    fn recovery_codes_ignore_dashes_and_case() {
+        assert_eq!(normalize_recovery_code(" AbCdE-12345 "), "abcde12345");
+
+        let (codes, hashes) = generate_recovery_codes();
+        assert_eq!(codes.len(), RECOVERY_CODE_COUNT);
+        for (code, hash) in codes.iter().zip(&hashes) {
+            assert_eq!(code.len(), RECOVERY_CODE_LENGTH + 1);
+            assert_eq!(
+                &authentication::hash_token(&normalize_recovery_code(&code.to_uppercase())),
+                hash
+            );
+        }
+    }
+}
@@ -0,0 +1,226 @@
+use crate::{account::GUEST_PREFIX, db::Db, profanity};
+use std::fmt::Display;
+use unicode_normalization::{is_nfc, UnicodeNormalization};
+use unicode_security::{GeneralSecurityProfile, MixedScript};
+
+pub const MIN_USERNAME_LENGTH: usize = 3;
+pub const MAX_USERNAME_LENGTH: usize = 15;
+/// Allowed between letters and digits, never at the ends or twice in a row.
+pub const USERNAME_SEPARATORS: &[char] = &['_', '-', '.'];
+/// Prefix of the names deleted accounts are renamed to.
+pub const DELETED_PREFIX: &str = "deleted-";
+
+/// Names that could pass for staff or the game itself, compared by skeleton.
+pub const RESERVED_USERNAMES: &[&str] = &[
+    "admin",
+    "administrator",
+    "moderator",
+    "mod",
+    "staff",
+    "support",
+    "help",
+    "official",
+    "system",
+    "server",
+    "root",
+    "drinkard",
+    "guest",
+    "deleted",
+    "anonymous",
+    "null",
+    "undefined",
+];
+
+/// Reasons a username is refused, each with a stable code for clients.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum UsernameError {
+    Length,
+    Characters,
+    MixedScripts,
+    Reserved,
+    Profanity,
+    Taken,
+}
+
+impl UsernameError {
+    pub fn code(&self) -> &'static str {
+        match self {
+            UsernameError::Length => "username_length",
+            UsernameError::Characters => "username_characters",
+            UsernameError::MixedScripts => "username_mixed_scripts",
+            UsernameError::Reserved => "username_reserved",
+            UsernameError::Profanity => "username_profanity",
+            UsernameError::Taken => "username_taken",
+        }
+    }
+}
+
+impl Display for UsernameError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            UsernameError::Length => write!(
+                f,
+                "Username must be between {} to {} characters",
+                MIN_USERNAME_LENGTH, MAX_USERNAME_LENGTH
+            ),
+            UsernameError::Characters => write!(
+                f,
+                "Username may only contain letters, digits and single {} between them",
+                USERNAME_SEPARATORS.iter().collect::<String>()
+            ),
+            UsernameError::MixedScripts => {
+                write!(f, "Username can't mix letters of different alphabets")
+            }
+            UsernameError::Reserved => write!(f, "Username is reserved"),
+            UsernameError::Profanity => write!(f, "Username contains inappropriate language"),
+            UsernameError::Taken => write!(f, "Username already exists!"),
+        }
+    }
+}
+
+impl std::error::Error for UsernameError {}
+
+/// The error code of a refused username, if that is what failed.
+pub fn error_code(error: &anyhow::Error) -> Option<String> {
+    error
+        .downcast_ref::<UsernameError>()
+        .map(|x| x.code().to_string())
+}
+
+/// Folds case, look-alike characters and separators, so names that read the
+/// same map to the same value. Stored in the unique `NormalizedUsername`.
+pub fn normalize(username: &str) -> String {
+    let lowercase: String = username.nfc().flat_map(char::to_lowercase).collect();
+
+    // the skeleton keeps `i` apart from `l`, though `I` and `l` look alike
+    unicode_security::skeleton(&lowercase)
+        .flat_map(char::to_lowercase)
+        .filter(|x| x.is_alphanumeric())
+        .map(|x| if x == 'i' { 'l' } else { x })
+        .collect()
+}
+
+fn is_separator(character: char) -> bool {
+    USERNAME_SEPARATORS.contains(&character)
+}
+
+pub fn validate(username: &str) -> Result<(), UsernameError> {
+    let length = username.chars().count();
+    if !(MIN_USERNAME_LENGTH..=MAX_USERNAME_LENGTH).contains(&length) {
+        return Err(UsernameError::Length);
+    }
+
+    let allowed = username
+        .chars()
+        .all(|x| is_separator(x) || (x.is_alphanumeric() && x.identifier_allowed()));
+    let separators_between = !username.starts_with(is_separator)
+        && !username.ends_with(is_separator)
+        && !username
+            .chars()
+            .zip(username.chars().skip(1))
+            .any(|(a, b)| is_separator(a) && is_separator(b));
+    if !is_nfc(username) || !allowed || !separators_between {
+        return Err(UsernameError::Characters);
+    }
+
+    if !username.is_single_script() {
+        return Err(UsernameError::MixedScripts);
+    }
+
+    let lowercase = username.to_lowercase();
+    let normalized = normalize(username);
+    if lowercase.starts_with(GUEST_PREFIX)
+        || lowercase.starts_with(DELETED_PREFIX)
+        || RESERVED_USERNAMES
+            .iter()
+            .any(|reserved| normalize(reserved) == normalized)
+    {
+        return Err(UsernameError::Reserved);
+    }
+
+    if profanity::contains_profanity(username) {
+        return Err(UsernameError::Profanity);
+    }
+
+    Ok(())
+}
+
+/// Fills in the normalized name of users created before it was stored.
+/// Existing look-alikes keep their names, told apart by their ID.
+pub async fn backfill(db: &Db) {
+    let result = async {
+        for (id, username) in db.get_unnormalized_usernames().await? {
+            let normalized = normalize(&username);
+            if !db.set_normalized_username(id, &normalized).await? {
+                log::warn!("Username {} looks like an existing one", username);
+                db.set_normalized_username(id, &format!("{}#{}", normalized, id))
+                    .await?;
+            }
+        }
+        anyhow::Ok(())
+    }
+    .await;
+
+    if let Err(err) = result {
+        log::error!("Failed to normalize usernames: {}", err);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn normalize_folds_look_alikes() {
+        assert_eq!(normalize("Alice"), normalize("alice"));
+        assert_eq!(normalize("a.l_i-c.e"), normalize("alice"));
+        assert_eq!(normalize("AIice"), normalize("alice"));
+        assert_eq!(normalize("аlice"), normalize("alice"));
+        assert_ne!(normalize("alice"), normalize("alicia"));
+    }
+
+    #[test]
+    fn accepts_valid_names() {
+        for username in [
+            "bob",
+            "Alice_99",
+            "jean-luc",
+            "j.doe",
+            "Zoë",
+            "東京太郎",
+            "Pompey",
+        ] {
+            assert_eq!(validate(username), Ok(()), "{}", username);
+        }
+    }
+
+    #[test]
+    fn refuses_invalid_names() {
+        let refused = [
+            ("ab", UsernameError::Length),
+            ("a_very_long_username", UsernameError::Length),
+            ("_bob", UsernameError::Characters),
+            ("bob_", UsernameError::Characters),
+            ("bo__b", UsernameError::Characters),
+            ("bob smith", UsernameError::Characters),
+            ("bob!", UsernameError::Characters),
+            ("pаypal", UsernameError::MixedScripts),
+            ("Admin", UsernameError::Reserved),
+            ("adm1n", UsernameError::Reserved),
+            ("guest_abc", UsernameError::Reserved),
+            ("deleted-12", UsernameError::Reserved),
+            ("fuck_you", UsernameError::Profanity),
+        ];
+
+        for (username, error) in refused {
+            assert_eq!(validate(username), Err(error), "{}", username);
+        }
+    }
+
+    #[test]
+    fn error_codes_survive_anyhow() {
+        let error = anyhow::Error::from(UsernameError::Taken);
+        assert_eq!(error_code(&error).as_deref(), Some("username_taken"));
+        assert_eq!(error_code(&anyhow::Error::msg("other")), None);
+    }
+}